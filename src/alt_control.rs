@@ -0,0 +1,359 @@
+use std::{ffi::c_void, ptr::null_mut, sync::Arc};
+
+use quaternion_core::point_rotation;
+use rpos::{libc::c_long, msg::get_new_rx_of_message, pthread_scheduler::SchedulePthread};
+
+use crate::{
+    basic::pid::PIDController,
+    basic::rotation::get_yaw,
+    manual_ctrl::{get_sticks, ManualParams, StickAttitude},
+    mode::FlightMode,
+    msg_define::{
+        AttitudeSetPointMsg, MsgHeader, Publisher, RcChannelsMsg, SensorAccelMsg, Stamped, Vector3, Vector4,
+        VehicleAltitudeMsg,
+    },
+    param::{self, CachedParams, ParameterData},
+};
+
+const GRAVITY: f32 = 9.80665;
+// the thrust is not compensated further when tilted more than 60 degree
+const MIN_TILT_COS: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AltCtrlParams {
+    pub vel_max: f32,  // m/s, climb rate at full stick
+    pub deadband: f32, // [0,1), stick deadband around centre
+    pub pos_kp: f32,   // altitude error to climb rate, 1/s
+    pub vel_kp: f32,   // climb rate error to acceleration
+    pub vel_ki: f32,
+    pub vel_kd: f32,
+    pub thr_hover: f32, // initial hover thrust
+    pub thr_min: f32,
+    pub thr_max: f32,
+}
+
+impl Default for AltCtrlParams {
+    fn default() -> Self {
+        AltCtrlParams {
+            vel_max: 2.0,
+            deadband: 0.1,
+            pos_kp: 1.0,
+            vel_kp: 4.0,
+            vel_ki: 2.0,
+            vel_kd: 0.0,
+            thr_hover: 0.5,
+            thr_min: 0.1,
+            thr_max: 0.9,
+        }
+    }
+}
+
+impl AltCtrlParams {
+    // None if a param is being written
+    pub fn from_params() -> Option<Self> {
+        let get = |name: &str| param::get_param(name).map(|x| x.as_f32());
+        Some(AltCtrlParams {
+            vel_max: get("alt_vel_max")?,
+            deadband: get("alt_deadband")?,
+            pos_kp: get("alt_pos_kp")?,
+            vel_kp: get("alt_vel_kp")?,
+            vel_ki: get("alt_vel_ki")?,
+            vel_kd: get("alt_vel_kd")?,
+            thr_hover: get("alt_thr_hover")?,
+            thr_min: get("alt_thr_min")?,
+            thr_max: get("alt_thr_max")?,
+        })
+    }
+}
+
+// map the stick out of the deadband to [-1,1]
+pub fn apply_deadband(x: f32, deadband: f32) -> f32 {
+    if x.abs() <= deadband {
+        0.0
+    } else {
+        x.signum() * (x.abs() - deadband) / (1.0 - deadband)
+    }
+}
+
+pub struct AltState {
+    pub alt: f32,      // m
+    pub vz: f32,       // m/s, up
+    pub acc_up: f32,   // m/s^2, vertical acceleration without gravity
+    pub tilt_cos: f32, // cos of the angle between body z and world z
+}
+
+/*
+    altitude -> climb rate -> acceleration -> collective thrust,
+    the stick commands the climb rate, the altitude is held when the stick is in the deadband.
+*/
+pub struct AltController {
+    pub params: AltCtrlParams,
+    pub alt_sp: f32,
+    pub hover_thrust: f32,
+    vel_pid: PIDController,
+    last_thrust: f32,
+}
+
+impl AltController {
+    // time constant of the hover thrust estimation, s
+    const HOVER_TAU: f32 = 2.0;
+
+    pub fn new(params: AltCtrlParams) -> Self {
+        let mut vel_pid = PIDController::new(params.vel_kp, params.vel_ki, params.vel_kd);
+        vel_pid.set_i_limit(2.0);
+        AltController {
+            hover_thrust: params.thr_hover,
+            params,
+            alt_sp: 0.0,
+            vel_pid,
+            last_thrust: 0.0,
+        }
+    }
+
+    pub fn set_params(&mut self, params: AltCtrlParams) {
+        self.vel_pid.set_gains(params.vel_kp, params.vel_ki, params.vel_kd);
+        self.params = params;
+    }
+
+    // called when the mode is switched in, hold the current altitude
+    pub fn reset(&mut self, alt: f32) {
+        self.alt_sp = alt;
+        self.vel_pid.reset();
+        self.last_thrust = 0.0;
+    }
+
+    // thrust = hover * (1 + acc / g), learn the hover thrust from the last output and the measured acceleration.
+    fn update_hover_thrust(&mut self, state: &AltState, dt: f32) {
+        let p = &self.params;
+        let saturated = self.last_thrust <= p.thr_min || self.last_thrust >= p.thr_max;
+        if saturated || state.tilt_cos < 0.8 || state.acc_up.abs() > 0.5 * GRAVITY {
+            return;
+        }
+        let measured = self.last_thrust * state.tilt_cos / (1.0 + state.acc_up / GRAVITY);
+        self.hover_thrust += (measured - self.hover_thrust) * (dt / Self::HOVER_TAU).min(1.0);
+        self.hover_thrust = self.hover_thrust.clamp(p.thr_min, p.thr_max);
+    }
+
+    // stick:[-1,1], return the collective thrust [0,1]
+    pub fn update(&mut self, stick: f32, state: &AltState, dt: f32) -> f32 {
+        let p = &self.params;
+
+        let stick = apply_deadband(stick.clamp(-1.0, 1.0), p.deadband);
+        let vz_sp = if stick != 0.0 {
+            self.alt_sp = state.alt;
+            stick * p.vel_max
+        } else {
+            ((self.alt_sp - state.alt) * p.pos_kp).clamp(-p.vel_max, p.vel_max)
+        };
+        self.update_vel(vz_sp, state, dt)
+    }
+
+    // track an external altitude and(or) climb rate target, the altitude is held if both are None
+    pub fn update_target(&mut self, alt: Option<f32>, vz: Option<f32>, state: &AltState, dt: f32) -> f32 {
+        let p = &self.params;
+        let vz_sp = match (alt, vz) {
+            (Some(alt), vz) => {
+                self.alt_sp = alt;
+                (alt - state.alt) * p.pos_kp + vz.unwrap_or(0.0)
+            }
+            (None, Some(vz)) => {
+                self.alt_sp = state.alt;
+                vz
+            }
+            (None, None) => (self.alt_sp - state.alt) * p.pos_kp,
+        };
+        self.update_vel(vz_sp.clamp(-p.vel_max, p.vel_max), state, dt)
+    }
+
+    fn update_vel(&mut self, vz_sp: f32, state: &AltState, dt: f32) -> f32 {
+        self.update_hover_thrust(state, dt);
+        let p = &self.params;
+        let acc_sp = self.vel_pid.calcuate(vz_sp - state.vz, dt);
+        let thrust = self.hover_thrust * (1.0 + acc_sp / GRAVITY) / state.tilt_cos.max(MIN_TILT_COS);
+        self.last_thrust = thrust.clamp(p.thr_min, p.thr_max);
+        self.last_thrust
+    }
+}
+
+fn alt_control_main(ptr: *mut c_void) -> *mut c_void {
+    let sp = unsafe { Arc::from_raw(ptr as *const SchedulePthread) };
+    let mut rc_rx = get_new_rx_of_message::<RcChannelsMsg>("rc_channels").unwrap();
+    let mut alt_rx = get_new_rx_of_message::<VehicleAltitudeMsg>("vehicle_altitude").unwrap();
+    let mut alt_gt_rx = get_new_rx_of_message::<VehicleAltitudeMsg>("vehicle_altitude_groundtruth").unwrap();
+    let mut acc_rx = get_new_rx_of_message::<SensorAccelMsg>("acc").unwrap();
+    let mut att_rx = get_new_rx_of_message::<Stamped<Vector4>>("attitude").unwrap();
+    let att_target_tx = Publisher::<AttitudeSetPointMsg>::new("att_target");
+
+    const ALT_CTRL_PERIOD_US: c_long = 4000;
+    let dt = ALT_CTRL_PERIOD_US as f32 / 1000_000.0;
+
+    let mut params = CachedParams::new(AltCtrlParams::from_params, AltCtrlParams::default());
+    let mut ctrl = AltController::new(*params.value());
+    let mut state = AltState { alt: 0.0, vz: 0.0, acc_up: 0.0, tilt_cos: 1.0 };
+    let mut q = (1.0, [0.0; 3]);
+    let mut sticks = [0.0, 0.0, -1.0, 0.0];
+    let mut rc_timestamp = 0;
+    // roll, pitch and yaw are flown as stabilize mode
+    let mut manual = CachedParams::new(ManualParams::from_params, ManualParams::default());
+    let mut stick_att = StickAttitude::new(*manual.value());
+    let mut active = false;
+
+    loop {
+        if let Some(rc) = rc_rx.try_read() {
+            sticks = get_sticks(&rc);
+            rc_timestamp = rc.header.timestamp;
+        }
+        if let Some(att) = att_rx.try_read() {
+            q = (att.w, [att.x, att.y, att.z]);
+            state.tilt_cos = point_rotation(q, [0.0, 0.0, 1.0])[2];
+        }
+        if let Some(acc) = acc_rx.try_read() {
+            state.acc_up = point_rotation(q, [acc.acc.x, acc.acc.y, acc.acc.z])[2] - GRAVITY;
+        }
+        // fly on the groundtruth altitude of the simulator, for debug
+        let alt_msg = if param::get_param("alt_use_gt").is_some_and(|x| x.as_bool()) {
+            alt_gt_rx.try_read()
+        } else {
+            alt_rx.try_read()
+        };
+        if let Some(alt) = alt_msg {
+            state.alt = alt.alt;
+            state.vz = alt.vz;
+        }
+
+        if params.update() {
+            ctrl.set_params(*params.value());
+        }
+
+        if FlightMode::current() == FlightMode::Altitude {
+            if !active {
+                ctrl.reset(state.alt);
+                stick_att.params = *manual.get();
+                stick_att.reset(get_yaw(q));
+                active = true;
+            }
+            let thrust = ctrl.update(sticks[2], &state, dt);
+            let attitude = stick_att.update(sticks, rc_timestamp);
+            att_target_tx.send(AttitudeSetPointMsg {
+                header: MsgHeader::default(),
+                attitude: Vector4 { w: attitude.0, x: attitude.1[0], y: attitude.1[1], z: attitude.1[2] },
+                body_thrusts: Vector3 { x: 0.0, y: 0.0, z: thrust },
+            });
+        } else {
+            active = false;
+        }
+        sp.schedule_until(ALT_CTRL_PERIOD_US);
+    }
+    #[allow(unreachable_code)]
+    null_mut()
+}
+
+pub fn init_alt_control(_argc: u32, _argv: *const &str) {
+    SchedulePthread::new(1024 * 1024, 96, alt_control_main, null_mut(), false);
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    let default = AltCtrlParams::default();
+    param::add_param("alt_vel_max", ParameterData::Float(default.vel_max));
+    param::add_param("alt_deadband", ParameterData::Float(default.deadband));
+    param::add_param("alt_pos_kp", ParameterData::Float(default.pos_kp));
+    param::add_param("alt_vel_kp", ParameterData::Float(default.vel_kp));
+    param::add_param("alt_vel_ki", ParameterData::Float(default.vel_ki));
+    param::add_param("alt_vel_kd", ParameterData::Float(default.vel_kd));
+    param::add_param("alt_thr_hover", ParameterData::Float(default.thr_hover));
+    param::add_param("alt_thr_min", ParameterData::Float(default.thr_min));
+    param::add_param("alt_thr_max", ParameterData::Float(default.thr_max));
+    param::add_param("alt_use_gt", ParameterData::Bool(false));
+    rpos::module::Module::register("alt_control", init_alt_control);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.004;
+
+    // a point mass lifted by the thrust, hover_thrust is the thrust to keep it in the air
+    struct Vehicle {
+        alt: f32,
+        vz: f32,
+        acc_up: f32,
+        hover_thrust: f32,
+        tilt_cos: f32,
+    }
+
+    impl Vehicle {
+        fn step(&mut self, thrust: f32) {
+            self.acc_up = thrust * self.tilt_cos / self.hover_thrust * GRAVITY - GRAVITY;
+            self.vz += self.acc_up * DT;
+            self.alt += self.vz * DT;
+        }
+
+        fn state(&self) -> AltState {
+            AltState { alt: self.alt, vz: self.vz, acc_up: self.acc_up, tilt_cos: self.tilt_cos }
+        }
+    }
+
+    fn run(ctrl: &mut AltController, vehicle: &mut Vehicle, stick: f32, time: f32) -> f32 {
+        let mut thrust = 0.0;
+        for _ in 0..(time / DT) as usize {
+            thrust = ctrl.update(stick, &vehicle.state(), DT);
+            vehicle.step(thrust);
+        }
+        thrust
+    }
+
+    #[test]
+    fn test_deadband() {
+        assert_eq!(apply_deadband(0.05, 0.1), 0.0);
+        assert_eq!(apply_deadband(-0.1, 0.1), 0.0);
+        assert!((apply_deadband(0.55, 0.1) - 0.5).abs() < 1e-6);
+        assert_eq!(apply_deadband(-1.0, 0.1), -1.0);
+    }
+
+    #[test]
+    fn test_alt_hold_and_hover_thrust() {
+        // the hover thrust is 0.35 while the initial guess is 0.5
+        let mut vehicle = Vehicle { alt: 10.0, vz: 0.0, acc_up: 0.0, hover_thrust: 0.35, tilt_cos: 1.0 };
+        let mut ctrl = AltController::new(AltCtrlParams::default());
+        ctrl.reset(vehicle.alt);
+        run(&mut ctrl, &mut vehicle, 0.05, 20.0);
+        assert!((vehicle.alt - 10.0).abs() < 0.1, "alt:{}", vehicle.alt);
+        assert!((ctrl.hover_thrust - 0.35).abs() < 0.01, "hover:{}", ctrl.hover_thrust);
+    }
+
+    #[test]
+    fn test_climb_rate_and_release() {
+        let mut vehicle = Vehicle { alt: 0.0, vz: 0.0, acc_up: 0.0, hover_thrust: 0.5, tilt_cos: 1.0 };
+        let mut ctrl = AltController::new(AltCtrlParams::default());
+        ctrl.reset(vehicle.alt);
+
+        // full stick: climb with the max rate
+        run(&mut ctrl, &mut vehicle, 1.0, 5.0);
+        assert!((vehicle.vz - 2.0).abs() < 0.05, "vz:{}", vehicle.vz);
+        // half stick down
+        run(&mut ctrl, &mut vehicle, -0.55, 5.0);
+        assert!((vehicle.vz + 1.0).abs() < 0.05, "vz:{}", vehicle.vz);
+
+        // release the stick, the vehicle stops near where it is released
+        let released = vehicle.alt;
+        run(&mut ctrl, &mut vehicle, 0.0, 10.0);
+        assert!(vehicle.vz.abs() < 0.05);
+        assert!((vehicle.alt - released).abs() < 1.0, "{} {}", vehicle.alt, released);
+        assert!((vehicle.alt - ctrl.alt_sp).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_tilt_compensation() {
+        let tilt_cos = 30.0f32.to_radians().cos();
+        let mut vehicle = Vehicle { alt: 5.0, vz: 0.0, acc_up: 0.0, hover_thrust: 0.5, tilt_cos };
+        let mut params = AltCtrlParams::default();
+        params.vel_ki = 0.0; // the compensation alone should keep the altitude
+        let mut ctrl = AltController::new(params);
+        ctrl.reset(vehicle.alt);
+        let thrust = run(&mut ctrl, &mut vehicle, 0.0, 10.0);
+        assert!((thrust - 0.5 / tilt_cos).abs() < 0.01, "thrust:{}", thrust);
+        assert!((vehicle.alt - 5.0).abs() < 0.05);
+    }
+}
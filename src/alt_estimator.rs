@@ -0,0 +1,190 @@
+use std::{ffi::c_void, ptr::null_mut, sync::Arc};
+
+use quaternion_core::point_rotation;
+use rpos::{libc::c_long, msg::get_new_rx_of_message, pthread_scheduler::SchedulePthread};
+
+use crate::{
+    msg_define::{Publisher, SensorAccelMsg, SensorBaroMsg, Stamped, Vector4, VehicleAltitudeMsg},
+    param::{self, ParameterData},
+};
+
+const GRAVITY: f32 = 9.80665;
+
+// altitude difference(m) of two pressures(Pa), the international standard atmosphere
+pub fn pressure_to_alt(pressure: f32, ref_pressure: f32) -> f32 {
+    44330.0 * (1.0 - (pressure / ref_pressure).powf(1.0 / 5.255))
+}
+
+/*
+    third order complementary filter of altitude, vertical speed and acceleration bias.
+    the accelerometer is trusted in the short term, the baro in the long term, split by time_constant.
+*/
+pub struct AltEstimator {
+    pub alt: f32,
+    pub vz: f32,
+    pub acc_bias: f32,
+    k1: f32,
+    k2: f32,
+    k3: f32,
+}
+
+impl AltEstimator {
+    pub fn new(time_constant: f32) -> Self {
+        let mut est = AltEstimator {
+            alt: 0.0,
+            vz: 0.0,
+            acc_bias: 0.0,
+            k1: 0.0,
+            k2: 0.0,
+            k3: 0.0,
+        };
+        est.set_time_constant(time_constant);
+        est
+    }
+
+    pub fn set_time_constant(&mut self, tc: f32) {
+        let tc = tc.max(0.1);
+        self.k1 = 3.0 / tc;
+        self.k2 = 3.0 / (tc * tc);
+        self.k3 = 1.0 / (tc * tc * tc);
+    }
+
+    // acc_up: vertical acceleration in world frame without gravity, m/s^2
+    pub fn update(&mut self, acc_up: f32, baro_alt: f32, dt: f32) {
+        let err = baro_alt - self.alt;
+        self.acc_bias -= err * self.k3 * dt;
+        let acc = acc_up - self.acc_bias;
+        self.vz += (acc + err * self.k2) * dt;
+        self.alt += (self.vz + err * self.k1) * dt;
+    }
+}
+
+fn alt_est_main(ptr: *mut c_void) -> *mut c_void {
+    let sp = unsafe { Arc::from_raw(ptr as *const SchedulePthread) };
+    let mut acc_rx = get_new_rx_of_message::<SensorAccelMsg>("acc").unwrap();
+    let mut baro_rx = get_new_rx_of_message::<SensorBaroMsg>("baro").unwrap();
+    let mut att_rx = get_new_rx_of_message::<Stamped<Vector4>>("attitude").unwrap();
+    let alt_tx = Publisher::<VehicleAltitudeMsg>::new("vehicle_altitude");
+
+    const ALT_EST_PERIOD_US: c_long = 4000;
+    const ALT_MAX_DT_US: u64 = 100_000;
+    // pressures averaged as the home reference
+    const REF_SAMPLES: u32 = 20;
+
+    let mut est = AltEstimator::new(param::get_param("alt_tc").map_or(3.0, |x| x.as_f32()));
+    let mut q = (1.0, [0.0; 3]);
+    let mut ref_sum = 0.0;
+    let mut ref_count = 0;
+    let mut ref_pressure = None;
+    let mut baro_alt = 0.0;
+    let mut last_acc_timestamp: u64 = 0;
+    let mut cnt: u32 = 0;
+
+    loop {
+        if let Some(att) = att_rx.try_read() {
+            q = (att.w, [att.x, att.y, att.z]);
+        }
+        if let Some(baro) = baro_rx.try_read() {
+            match ref_pressure {
+                Some(p0) => baro_alt = pressure_to_alt(baro.pressure, p0),
+                None => {
+                    ref_sum += baro.pressure;
+                    ref_count += 1;
+                    if ref_count == REF_SAMPLES {
+                        ref_pressure = Some(ref_sum / REF_SAMPLES as f32);
+                    }
+                }
+            }
+        }
+        if let Some(acc_msg) = acc_rx.try_read() {
+            let timestamp = acc_msg.header.timestamp;
+            if ref_pressure.is_some()
+                && last_acc_timestamp != 0
+                && timestamp > last_acc_timestamp
+                && timestamp - last_acc_timestamp < ALT_MAX_DT_US
+            {
+                let dt = (timestamp - last_acc_timestamp) as f32 / 1000_000.0;
+                let acc = point_rotation(q, [acc_msg.acc.x, acc_msg.acc.y, acc_msg.acc.z]);
+                est.update(acc[2] - GRAVITY, baro_alt, dt);
+
+                cnt += 1;
+                if cnt % 250 == 0 {
+                    est.set_time_constant(param::get_param("alt_tc").map_or(3.0, |x| x.as_f32()));
+                }
+                alt_tx.send(VehicleAltitudeMsg {
+                    alt: est.alt,
+                    vz: est.vz,
+                    baro_alt,
+                    acc_bias: est.acc_bias,
+                    ..Default::default()
+                });
+            }
+            last_acc_timestamp = timestamp;
+        }
+        sp.schedule_until(ALT_EST_PERIOD_US);
+    }
+    #[allow(unreachable_code)]
+    null_mut()
+}
+
+pub fn init_alt_est(_argc: u32, _argv: *const &str) {
+    SchedulePthread::new(1024 * 1024, 96, alt_est_main, null_mut(), false);
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    // time constant of the baro/accelerometer fusion, s
+    param::add_param("alt_tc", ParameterData::Float(3.0));
+    rpos::module::Module::register("alt_est", init_alt_est);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.004;
+
+    #[test]
+    fn test_pressure_to_alt() {
+        assert_eq!(pressure_to_alt(101325.0, 101325.0), 0.0);
+        // about 8.4m per hPa near the sea level
+        let alt = pressure_to_alt(101225.0, 101325.0);
+        assert!((alt - 8.3).abs() < 0.2, "{}", alt);
+        assert!((pressure_to_alt(89875.0, 101325.0) - 1000.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn test_alt_est_acc_bias() {
+        // still on the ground, the accelerometer has a bias and the baro is noisy
+        let mut est = AltEstimator::new(3.0);
+        for i in 0..(60.0 / DT) as usize {
+            let noise = if i % 2 == 0 { 0.5 } else { -0.5 };
+            est.update(0.3, noise, DT);
+        }
+        assert!((est.acc_bias - 0.3).abs() < 0.02, "{}", est.acc_bias);
+        assert!(est.alt.abs() < 0.2);
+        assert!(est.vz.abs() < 0.1);
+    }
+
+    #[test]
+    fn test_alt_est_climb() {
+        // accelerate to 2m/s in 1s, then climb with a constant speed, the baro lags behind the truth
+        let mut est = AltEstimator::new(3.0);
+        let mut alt = 0.0;
+        let mut vz = 0.0;
+        let mut baro_alt = 0.0;
+        for i in 0..(10.0 / DT) as usize {
+            let acc = if i < (1.0 / DT) as usize { 2.0 } else { 0.0 };
+            vz += acc * DT;
+            alt += vz * DT;
+            if i % 10 == 0 {
+                baro_alt = alt;
+            }
+            est.update(acc, baro_alt, DT);
+            // the accelerometer keeps the speed right during the transient
+            assert!((est.vz - vz).abs() < 0.3, "{} {}", est.vz, vz);
+        }
+        assert!((est.alt - alt).abs() < 0.3, "{} {}", est.alt, alt);
+        assert!((est.vz - 2.0).abs() < 0.05);
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// always armed without an arm switch, rc_update disarms at init if one is mapped
+static ARMED: AtomicBool = AtomicBool::new(true);
+static KILLED: AtomicBool = AtomicBool::new(false);
+
+pub fn armed() -> bool {
+    ARMED.load(Ordering::Acquire)
+}
+
+pub fn set_armed(armed: bool) {
+    ARMED.store(armed, Ordering::Release);
+}
+
+pub fn killed() -> bool {
+    KILLED.load(Ordering::Acquire)
+}
+
+pub fn set_killed(killed: bool) {
+    KILLED.store(killed, Ordering::Release);
+}
+
+// read by the mixer, the motors are stopped otherwise
+pub fn motors_enabled() -> bool {
+    armed() && !killed()
+}
+
+// the arm switch disarms at any time, but only arms with the throttle at the bottom
+pub fn next_armed(armed: bool, switch_on: bool, throttle: Option<f32>) -> bool {
+    switch_on && (armed || throttle.is_some_and(|x| x < -0.9))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_armed() {
+        assert!(!next_armed(false, true, Some(0.0)));
+        assert!(!next_armed(false, true, None));
+        assert!(next_armed(false, true, Some(-1.0)));
+        // keeps armed when the throttle is raised
+        assert!(next_armed(true, true, Some(0.5)));
+        assert!(!next_armed(true, false, Some(-1.0)));
+        assert!(!next_armed(false, false, Some(-1.0)));
+    }
+}
@@ -1,13 +1,13 @@
 use rpos::{
-    channel::Sender,
-    msg::{get_new_rx_of_message, get_new_tx_of_message},
+    msg::get_new_rx_of_message,
     pthread_scheduler::SchedulePthread,
 };
 use std::{os::raw::c_void, ptr::null_mut, sync::Arc};
 
 use crate::{
     basic::pid::PIDController,
-    msg_define::{Vector4, TorqueThrustMsg, EulerVector3, Vector3, AttitudeSetPointMsg},
+    mode::FlightMode,
+    msg_define::{Vector4, TorqueThrustMsg, EulerVector3, Vector3, AttitudeSetPointMsg, MsgHeader, Publisher, Stamped},
     param,
 };
 
@@ -16,7 +16,20 @@ use quaternion_core::{frame_rotation, point_rotation, Quaternion as Q};
 struct AttitudeController {
     pitch_controller: PIDController,
     roll_controller: PIDController,
-    tx: Sender<TorqueThrustMsg>,
+    yaw_controller: PIDController,
+    tx: Publisher<TorqueThrustMsg>,
+}
+
+impl AttitudeController {
+    // torques [pitch, roll, yaw]
+    fn update(&mut self, target: Q<f32>, now: Q<f32>, dt: f32) -> [f32; 3] {
+        let q_err = get_attitude_distance(target, now);
+        [
+            self.pitch_controller.calcuate(q_err[0], dt),
+            self.roll_controller.calcuate(q_err[1], dt),
+            self.yaw_controller.calcuate(q_err[2], dt),
+        ]
+    }
 }
 
 fn get_attitude_distance(target: Q<f32>, now: Q<f32>) -> [f32; 3] {
@@ -25,9 +38,9 @@ fn get_attitude_distance(target: Q<f32>, now: Q<f32>) -> [f32; 3] {
 
     // 获取机体坐标系的z轴在世界坐标系的坐标（向量）
     // 获取期望的集体坐标系z轴在世界坐标系的坐标（向量）
-    let theta = quaternion_core::dot(now_z, target_z).acos();
+    let theta = quaternion_core::dot(now_z, target_z).clamp(-1.0, 1.0).acos();
     if theta.abs() < 0.00001 {
-        return [0.0; 3];
+        return [0.0, 0.0, get_heading_distance(target, now)];
     }
 
     let axis = quaternion_core::cross(now_z, target_z);
@@ -39,19 +52,31 @@ fn get_attitude_distance(target: Q<f32>, now: Q<f32>) -> [f32; 3] {
     let axis_q = quaternion_core::from_axis_angle(axis_new, theta);
     // 通过这个轴和角度，构造一个机体坐标系的旋转四元数
 
-    quaternion_core::normalize(axis_q).1
+    let mut err = quaternion_core::normalize(axis_q).1;
+    err[2] = get_heading_distance(target, quaternion_core::mul(now, axis_q));
+    err
+}
+
+// the rest rotation around body z, after the body z axis is aligned to the target(tilt first)
+fn get_heading_distance(target: Q<f32>, aligned: Q<f32>) -> f32 {
+    let yaw_q = quaternion_core::mul(quaternion_core::conj(aligned), target);
+    // the shorter way around
+    let yaw_q = if yaw_q.0 < 0.0 { quaternion_core::negate(yaw_q) } else { yaw_q };
+    yaw_q.1[2]
 }
 
 fn att_control_main(ptr: *mut c_void) -> *mut c_void {
     let sp: Arc<SchedulePthread> = unsafe { Arc::from_raw(ptr as *const SchedulePthread) };
 
     let mut att_target_rx = get_new_rx_of_message::<AttitudeSetPointMsg>("att_target").unwrap();
-    let mut att_rx = get_new_rx_of_message::<Vector4>("attitude").unwrap();
+    let mut att_rx = get_new_rx_of_message::<Stamped<Vector4>>("attitude").unwrap();
+    let mut att_gt_rx = get_new_rx_of_message::<Stamped<Vector4>>("attitude_groundtruth").unwrap();
 
     let mut att_ctrler = AttitudeController {
         pitch_controller: PIDController::new(100.0, 0.0, 0.0),
         roll_controller: PIDController::new(100.0, 0.0, 0.0),
-        tx: get_new_tx_of_message("toreque_thrust_setpoint").unwrap(),
+        yaw_controller: PIDController::new(40.0, 0.0, 0.0),
+        tx: Publisher::new("toreque_thrust_setpoint"),
     };
 
     let mut att_target_q: Q<f32> = (1.0, [0.0, 0.0, 0.0]);
@@ -72,23 +97,31 @@ fn att_control_main(ptr: *mut c_void) -> *mut c_void {
             // );
         }
 
-        if let Some(attmsg) = att_rx.try_read() {
+        // fly on the groundtruth attitude of the simulator, for debug
+        let use_groundtruth = param::get_param("att_use_gt").is_some_and(|x| x.as_bool());
+        let att_msg = if use_groundtruth {
+            att_gt_rx.try_read()
+        } else {
+            att_rx.try_read()
+        };
+        if let Some(attmsg) = att_msg {
             att_q = (attmsg.w, [attmsg.x, attmsg.y, attmsg.z]);
         }
 
-        let q_err = get_attitude_distance(att_target_q, att_q);
+        // the offboard torques go to the mixer directly, the rate controller drives it in acro mode
+        if crate::offboard::torque_direct() || FlightMode::current() == FlightMode::Acro {
+            sp.schedule_until(2500);
+            continue;
+        }
 
-        let pitch_out = att_ctrler.pitch_controller.calcuate(q_err[0], 0.0025);
-        let roll_out = att_ctrler.roll_controller.calcuate(q_err[1], 0.0025);
+        let [pitch_out, roll_out, yaw_out] = att_ctrler.update(att_target_q, att_q, 0.0025);
 
-        let mut output_all = [0.0; 8];
-        output_all[0] = pitch_out;
-        output_all[1] = roll_out;
         att_ctrler.tx.send(TorqueThrustMsg {
+            header: MsgHeader::default(),
             torques: EulerVector3 {
                 pitch: pitch_out,
                 roll: roll_out,
-                yaw: 0.0,
+                yaw: yaw_out,
             },
             thrusts: Vector3{
                 x: 0.0,
@@ -107,6 +140,7 @@ pub fn init_att_control(_argc: u32, _argv: *const &str) {
     param::add_param("att_Kp", param::ParameterData::Float(0.0));
     param::add_param("att_Ki", param::ParameterData::Float(0.0));
     param::add_param("att_Kd", param::ParameterData::Float(0.0));
+    param::add_param("att_use_gt", param::ParameterData::Bool(false));
     SchedulePthread::new(16384, 98, att_control_main, null_mut(), false); // TODO edit pthread_key
 }
 
@@ -127,4 +161,30 @@ mod tests {
         let err = get_attitude_distance(target_q, now_q);
         println!("err:{:?}", err);
     }
+
+    #[test]
+    fn test_heading_setpoint() {
+        let mut ctrl = AttitudeController {
+            pitch_controller: PIDController::new(100.0, 0.0, 0.0),
+            roll_controller: PIDController::new(100.0, 0.0, 0.0),
+            yaw_controller: PIDController::new(40.0, 0.0, 0.0),
+            tx: Publisher::new("toreque_thrust_setpoint"),
+        };
+        let level = (1.0, [0.0; 3]);
+        // heading 0.5 rad to the left(ccw), a positive yaw torque only
+        let target = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], 0.5);
+        let torques = ctrl.update(target, level, 0.0025);
+        assert!(torques[0].abs() < 1e-4 && torques[1].abs() < 1e-4);
+        assert!((torques[2] - 40.0 * 0.25f32.sin()).abs() < 1e-3, "{:?}", torques);
+        // to the right, the other way
+        assert!(ctrl.update(quaternion_core::conj(target), level, 0.0025)[2] < 0.0);
+
+        // tilted and turned, the tilt does not leak into yaw
+        let tilt = quaternion_core::from_axis_angle([1.0, 0.0, 0.0], 0.3);
+        let err = get_attitude_distance(quaternion_core::mul(target, tilt), level);
+        assert!((err[2] - 0.25f32.sin()).abs() < 1e-3, "{:?}", err);
+        assert!(err[0] > 0.1);
+        let err = get_attitude_distance(tilt, tilt);
+        assert!(err.iter().all(|x| x.abs() < 1e-4));
+    }
 }
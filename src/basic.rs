@@ -2,6 +2,12 @@ pub mod scaler;
 pub mod rotation;
 pub mod pid;
 
+// current hrt time in us, it follows the gazebo clock when lock step is enabled.
+pub fn hrt_now_us() -> u64 {
+    let t = rpos::hrt::get_time_now();
+    t.sec as u64 * 1000_000 + t.nsec as u64 / 1000
+}
+
 pub fn client_process_args<T:clap::Parser>(
     argc: u32,
     argv: *const &str
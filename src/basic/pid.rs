@@ -5,11 +5,12 @@ pub struct PIDController {
 
     last_err: f32,
     i_err: f32, //integration of err
+    i_limit: f32, // limit of i_err, anti windup
 }
 
 impl PIDController {
     pub fn calcuate(&mut self, err:f32, dt: f32) -> f32 {
-        self.i_err += err * dt;
+        self.i_err = (self.i_err + err * dt).clamp(-self.i_limit, self.i_limit);
         let out = err * self.kp + self.i_err * self.ki + (err - self.last_err) / dt * self.kd;
         self.last_err = err;
         out
@@ -22,6 +23,22 @@ impl PIDController {
             kd,
             last_err: 0.0,
             i_err: 0.0,
+            i_limit: f32::INFINITY,
         }
     }
+
+    pub fn set_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
+    pub fn set_i_limit(&mut self, limit: f32) {
+        self.i_limit = limit;
+    }
+
+    pub fn reset(&mut self) {
+        self.last_err = 0.0;
+        self.i_err = 0.0;
+    }
 }
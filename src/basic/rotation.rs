@@ -1,11 +1,11 @@
 
-
 use quaternion_core::Quaternion;
 use std::f32::consts::PI;
 use quaternion_core::RotationSequence::*;
 use quaternion_core::RotationType::*;
 
 use crate::msg_define::Vector3;
+use crate::param::{self, ParameterData};
 
 #[allow(dead_code)]
 pub fn get_euler_degree(q:quaternion_core::Quaternion<f32>)->[f32;3]{
@@ -14,22 +14,171 @@ pub fn get_euler_degree(q:quaternion_core::Quaternion<f32>)->[f32;3]{
     })
 }
 
+// yaw of the attitude(ENU world), 0 when the front(body y) points to the north, positive to the left.
+pub fn get_yaw(q:Quaternion<f32>)->f32{
+    let front = quaternion_core::point_rotation(q, [0.0,1.0,0.0]);
+    (-front[0]).atan2(front[1])
+}
+
+/*
+    heading error measured by the magnetometer, unit:rad.
+    q is the attitude(body to world), mag is in body frame, declination(rad) is positive to the east.
+    the result is the rotation around the world z axis which moves the estimate to the measured heading,
+    None if the field is almost vertical and the heading can't be measured.
+*/
+pub fn mag_yaw_error(q:Quaternion<f32>,mag:[f32;3],declination:f32)->Option<f32>{
+    let m = quaternion_core::point_rotation(q, mag);
+    let horizontal = (m[0] * m[0] + m[1] * m[1]).sqrt();
+    if horizontal < 0.1 * quaternion_core::norm(m) || horizontal < 1e-6{
+        return None;
+    }
+    // the magnetic north in world frame(x:east, y:north)
+    let north = [declination.sin(),declination.cos()];
+    Some((m[0] * north[1] - m[1] * north[0]).atan2(m[0] * north[0] + m[1] * north[1]))
+}
+
+/*
+    mavlink uses NED(north,east,down) world and FRD(front,right,down) body frames,
+    the same swap converts them to the ENU world and right-front-up body frames of docs/axis.md, and back.
+*/
+pub fn ned_to_enu(v:[f32;3])->[f32;3]{
+    [v[1],v[0],-v[2]]
+}
+
+pub fn ned_q_to_enu(q:Quaternion<f32>)->Quaternion<f32>{
+    (q.0,ned_to_enu(q.1))
+}
+
+/*
+    sensor mounting rotations, the rotation is applied as: yaw(z) * roll(y) * pitch(x),
+    follow the axis defination in docs/axis.md.
+    rotate() returns the vector in the rotated frame, eg. Yaw270: [x,y,z] -> [-y,x,z]
+*/
 #[allow(dead_code)]
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum Rotation{
+    None,
     Yaw90,
     Yaw180,
     Yaw270,
+    Roll90,
+    Roll90Yaw90,
+    Roll90Yaw180,
+    Roll90Yaw270,
+    Roll180,
+    Roll180Yaw90,
+    Roll180Yaw180,
+    Roll180Yaw270,
+    Roll270,
+    Roll270Yaw90,
+    Roll270Yaw180,
+    Roll270Yaw270,
+    Pitch90,
+    Pitch90Yaw90,
+    Pitch90Yaw180,
+    Pitch90Yaw270,
+    Pitch270,
+    Pitch270Yaw90,
+    Pitch270Yaw180,
+    Pitch270Yaw270,
+    Custom{roll:f32,pitch:f32,yaw:f32} // unit:degree
+}
+
+// index of the custom rotation in the parameter, the same as PX4
+pub const ROTATION_CUSTOM_INDEX:i32 = 100;
+
+type Mat3 = [[i8;3];3];
+
+const MAT_I:Mat3 = [[1,0,0],[0,1,0],[0,0,1]];
+const MAT_X90:Mat3 = [[1,0,0],[0,0,-1],[0,1,0]];
+const MAT_Y90:Mat3 = [[0,0,1],[0,1,0],[-1,0,0]];
+const MAT_Z90:Mat3 = [[0,-1,0],[1,0,0],[0,0,1]];
+
+fn mat_mul(a:&Mat3,b:&Mat3)->Mat3{
+    let mut ret = [[0;3];3];
+    for i in 0..3{
+        for j in 0..3{
+            ret[i][j] = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    ret
+}
+
+fn mat_pow(a:&Mat3,n:u8)->Mat3{
+    (0..n).fold(MAT_I, |acc,_| mat_mul(&acc, a))
 }
 
 impl Rotation{
+    pub const STANDARD:[Rotation;24] = [
+        Rotation::None, Rotation::Yaw90, Rotation::Yaw180, Rotation::Yaw270,
+        Rotation::Roll90, Rotation::Roll90Yaw90, Rotation::Roll90Yaw180, Rotation::Roll90Yaw270,
+        Rotation::Roll180, Rotation::Roll180Yaw90, Rotation::Roll180Yaw180, Rotation::Roll180Yaw270,
+        Rotation::Roll270, Rotation::Roll270Yaw90, Rotation::Roll270Yaw180, Rotation::Roll270Yaw270,
+        Rotation::Pitch90, Rotation::Pitch90Yaw90, Rotation::Pitch90Yaw180, Rotation::Pitch90Yaw270,
+        Rotation::Pitch270, Rotation::Pitch270Yaw90, Rotation::Pitch270Yaw180, Rotation::Pitch270Yaw270,
+    ];
+
+    pub fn from_index(index:i32,custom:[f32;3])->Option<Rotation>{
+        if index == ROTATION_CUSTOM_INDEX{
+            return Some(Rotation::Custom { roll: custom[0], pitch: custom[1], yaw: custom[2] });
+        }
+        Self::STANDARD.get(usize::try_from(index).ok()?).copied()
+    }
+
+    // register the rotation parameters of a sensor: {prefix}, {prefix}_roll, {prefix}_pitch, {prefix}_yaw
+    pub fn add_params(prefix:&str,default:Rotation){
+        let index = Self::STANDARD.iter().position(|x| *x == default).unwrap_or(0);
+        param::add_param(prefix, ParameterData::Int(index as i32));
+        for axis in ["roll","pitch","yaw"]{
+            param::add_param(&format!("{}_{}",prefix,axis), ParameterData::Float(0.0));
+        }
+    }
+
+    pub fn from_params(prefix:&str)->Rotation{
+        let index = param::get_param(prefix).map_or(0, |x| x.as_i32());
+        let custom = ["roll","pitch","yaw"].map(|axis|{
+            param::get_param(&format!("{}_{}",prefix,axis)).map_or(0.0, |x| x.as_f32())
+        });
+        Self::from_index(index, custom).unwrap_or(Rotation::None)
+    }
+
+    // quarter turns around (x, y, z)
+    fn quarter_turns(&self)->Option<(u8,u8,u8)>{
+        let index = Self::STANDARD.iter().position(|x| x == self)? as u8;
+        let yaw = index % 4;
+        let tilt = index / 4;
+        Some(match tilt{
+            0..=3 => (0, tilt, yaw), // None, Roll90, Roll180, Roll270
+            4 => (1, 0, yaw), // Pitch90
+            _ => (3, 0, yaw), // Pitch270
+        })
+    }
+
+    fn custom_q(roll:f32,pitch:f32,yaw:f32)->Quaternion<f32>{
+        let to_rad = |x:f32| x / 180.0 * PI;
+        quaternion_core::mul(
+            quaternion_core::from_axis_angle([0.0,0.0,1.0], to_rad(yaw)),
+            quaternion_core::mul(
+                quaternion_core::from_axis_angle([0.0,1.0,0.0], to_rad(roll)),
+                quaternion_core::from_axis_angle([1.0,0.0,0.0], to_rad(pitch)),
+            ),
+        )
+    }
+
     pub fn rotate(&self,q:[f32;3])->[f32;3]{
-        match self{
-            Rotation::Yaw90 =>q,
-            Rotation::Yaw180 => q,
-            Rotation::Yaw270 => {
-                [-q[1], q[0],q[2]]
-            },
+        if let Rotation::Custom { roll, pitch, yaw } = *self{
+            return quaternion_core::frame_rotation(Self::custom_q(roll, pitch, yaw), q);
+        }
+        let (x,y,z) = self.quarter_turns().unwrap();
+        let m = mat_mul(&mat_pow(&MAT_Z90, z), &mat_mul(&mat_pow(&MAT_Y90, y), &mat_pow(&MAT_X90, x)));
+        // the inverse(transpose) of m, the signs are exact so no precision is lost.
+        let mut ret = [0.0;3];
+        for i in 0..3{
+            for j in 0..3{
+                ret[i] += m[j][i] as f32 * q[j];
+            }
         }
+        ret
     }
 
     #[inline]
@@ -69,4 +218,104 @@ mod tests{
         check_q_eq(rq, quaternion_core::from_axis_angle([0.0,1.0,0.0], ANGLE));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_rotate_known_values(){
+        let v = [1.0,2.0,3.0];
+        assert_eq!(Rotation::None.rotate(v), [1.0,2.0,3.0]);
+        assert_eq!(Rotation::Yaw90.rotate(v), [2.0,-1.0,3.0]);
+        assert_eq!(Rotation::Yaw180.rotate(v), [-1.0,-2.0,3.0]);
+        assert_eq!(Rotation::Yaw270.rotate(v), [-2.0,1.0,3.0]);
+        assert_eq!(Rotation::Roll180.rotate(v), [-1.0,2.0,-3.0]);
+        assert_eq!(Rotation::Roll90.rotate(v), [-3.0,2.0,1.0]);
+        assert_eq!(Rotation::Pitch90.rotate(v), [1.0,3.0,-2.0]);
+    }
+
+    #[test]
+    fn test_all_rotations(){
+        let v = [0.3,-0.5,0.8];
+        let q = quaternion_core::from_axis_angle([0.2,0.5,-0.3], 0.7);
+        for (index,r) in Rotation::STANDARD.iter().enumerate(){
+            let (x,y,z) = r.quarter_turns().unwrap();
+            let custom = Rotation::Custom {
+                roll: y as f32 * 90.0,
+                pitch: x as f32 * 90.0,
+                yaw: z as f32 * 90.0,
+            };
+            let ret = r.rotate(v);
+            let expect = custom.rotate(v);
+            for i in 0..3{
+                assert!((ret[i] - expect[i]).abs() < 1e-5, "{:?}: {:?} != {:?}", r, ret, expect);
+            }
+            // a rotation keeps the length
+            assert!((quaternion_core::norm(ret) - quaternion_core::norm(v)).abs() < 1e-6);
+
+            // rotate the attitude quaternion, the rotated attitude should rotate the rotated vector.
+            let rq = r.rotate_q(q);
+            let a = r.rotate(quaternion_core::point_rotation(q, v));
+            let b = quaternion_core::point_rotation(rq, r.rotate(v));
+            for i in 0..3{
+                assert!((a[i] - b[i]).abs() < 1e-5);
+            }
+
+            assert_eq!(Rotation::from_index(index as i32, [0.0;3]), Some(*r));
+        }
+        // every orientation is different
+        for (i,a) in Rotation::STANDARD.iter().enumerate(){
+            for b in Rotation::STANDARD.iter().skip(i + 1){
+                assert_ne!(a.rotate(v), b.rotate(v));
+            }
+        }
+        assert_eq!(Rotation::from_index(24, [0.0;3]), None);
+        assert_eq!(
+            Rotation::from_index(ROTATION_CUSTOM_INDEX, [1.0,2.0,3.0]),
+            Some(Rotation::Custom { roll: 1.0, pitch: 2.0, yaw: 3.0 })
+        );
+    }
+
+    #[test]
+    fn test_mag_yaw_error(){
+        let field = [0.0,0.2,-0.4];
+        // the estimate yawed 30 degree to the left of the real heading
+        let q = quaternion_core::from_axis_angle([0.0,0.0,1.0], 30.0f32.to_radians());
+        let err = mag_yaw_error(q, field, 0.0).unwrap();
+        assert!((err + 30.0f32.to_radians()).abs() < 1e-5);
+
+        // declination 10 degree east
+        let decl = 10.0f32.to_radians();
+        let field = [0.2 * decl.sin(),0.2 * decl.cos(),-0.4];
+        assert!(mag_yaw_error((1.0,[0.0;3]), field, decl).unwrap().abs() < 1e-5);
+
+        assert_eq!(mag_yaw_error((1.0,[0.0;3]), [0.0,0.0,-0.4], 0.0), None);
+    }
+
+    #[test]
+    fn test_ned_to_enu(){
+        // yaw 90 degree in NED, the front points to the east
+        let q = ned_q_to_enu(quaternion_core::from_axis_angle([0.0,0.0,1.0], PI/2.0));
+        let front = quaternion_core::point_rotation(q, [0.0,1.0,0.0]);
+        assert!((front[0] - 1.0).abs() < 1e-6 && front[1].abs() < 1e-6);
+
+        // pitch up 30 degree in NED
+        let q_ned = quaternion_core::from_axis_angle([0.0,1.0,0.0], PI/6.0);
+        let front = quaternion_core::point_rotation(ned_q_to_enu(q_ned), [0.0,1.0,0.0]);
+        assert!((front[2] - 0.5).abs() < 1e-6);
+
+        // rotating in NED then converting equals converting then rotating
+        let v = [0.3,-0.2,0.9];
+        let a = ned_to_enu(quaternion_core::point_rotation(q_ned, v));
+        let b = quaternion_core::point_rotation(ned_q_to_enu(q_ned), ned_to_enu(v));
+        for i in 0..3{
+            assert!((a[i] - b[i]).abs() < 1e-6);
+        }
+        check_q_eq(ned_q_to_enu(ned_q_to_enu(q_ned)), q_ned);
+    }
+
+    #[test]
+    fn test_rotation_params(){
+        Rotation::add_params("test_rot", Rotation::Yaw180);
+        assert_eq!(Rotation::from_params("test_rot"), Rotation::Yaw180);
+        param::set_param("test_rot", ParameterData::Int(ROTATION_CUSTOM_INDEX)).unwrap();
+        param::set_param("test_rot_yaw", ParameterData::Float(45.0)).unwrap();
+        assert_eq!(Rotation::from_params("test_rot"), Rotation::Custom { roll: 0.0, pitch: 0.0, yaw: 45.0 });
+    }
+}
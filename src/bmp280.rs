@@ -0,0 +1,320 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::fd::AsRawFd,
+};
+
+use clap::Parser;
+use rpos::{libc, libc::c_long, pthread_scheduler::SchedulePthread, thread_logln};
+
+use crate::msg_define::{Publisher, SensorBaroMsg};
+
+#[derive(Parser, Clone)]
+#[command(name = "bmp280", about = "bmp280 barometer driver on linux i2c-dev, publish baro")]
+struct Cli {
+    #[arg(short, long, default_value_t = 0x76, value_parser = parse_addr, help = "i2c address, 0x76 or 0x77")]
+    addr: u16,
+
+    #[arg(short, long, default_value_t = 20_000, help = "publish period, us")]
+    period: u32,
+
+    #[arg(default_value = "/dev/i2c-1")]
+    dev_name: String,
+}
+
+fn parse_addr(s: &str) -> Result<u16, String> {
+    let ret = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse::<u16>(),
+    };
+    ret.map_err(|e| e.to_string())
+}
+
+mod regs {
+    pub const CALIB: u8 = 0x88;
+    pub const CHIP_ID: u8 = 0xD0;
+    pub const RESET: u8 = 0xE0;
+    pub const CTRL_MEAS: u8 = 0xF4;
+    pub const CONFIG: u8 = 0xF5;
+    pub const PRESS_MSB: u8 = 0xF7;
+
+    pub const CHIP_ID_VAL: u8 = 0x58;
+    pub const RESET_VAL: u8 = 0xB6;
+    // temperature x2, pressure x16 oversampling, normal mode
+    pub const CTRL_MEAS_VAL: u8 = 0b010_101_11;
+    // standby 0.5ms, iir filter x16
+    pub const CONFIG_VAL: u8 = 0b000_100_00;
+}
+
+pub trait I2cDevice {
+    fn write_read(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()>;
+}
+
+// a device on /dev/i2c-N
+pub struct LinuxI2c {
+    file: File,
+}
+
+impl LinuxI2c {
+    pub fn open(dev_name: &str, addr: u16) -> io::Result<Self> {
+        const I2C_SLAVE: u32 = 0x0703;
+        let file = OpenOptions::new().read(true).write(true).open(dev_name)?;
+        if unsafe { libc::ioctl(file.as_raw_fd(), I2C_SLAVE as _, addr as libc::c_ulong) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(LinuxI2c { file })
+    }
+}
+
+impl I2cDevice for LinuxI2c {
+    fn write_read(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        self.file.write_all(tx)?;
+        if !rx.is_empty() {
+            self.file.read_exact(rx)?;
+        }
+        Ok(())
+    }
+}
+
+// the factory trimming parameters
+#[derive(Debug, Default, Clone, Copy)]
+struct Calib {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p: [f64; 9],
+}
+
+impl Calib {
+    fn from_bytes(b: &[u8; 24]) -> Self {
+        let u = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]) as f64;
+        let s = |i: usize| i16::from_le_bytes([b[i], b[i + 1]]) as f64;
+        Calib {
+            t1: u(0),
+            t2: s(2),
+            t3: s(4),
+            p: [u(6), s(8), s(10), s(12), s(14), s(16), s(18), s(20), s(22)],
+        }
+    }
+
+    // floating point compensation from the datasheet, return (degC, Pa)
+    fn compensate(&self, adc_t: i32, adc_p: i32) -> (f64, f64) {
+        let adc_t = adc_t as f64;
+        let var1 = (adc_t / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = (adc_t / 131072.0 - self.t1 / 8192.0).powi(2) * self.t3;
+        let t_fine = var1 + var2;
+        let temperature = t_fine / 5120.0;
+
+        let p = &self.p;
+        let var1 = t_fine / 2.0 - 64000.0;
+        let var2 = var1 * var1 * p[5] / 32768.0;
+        let var2 = var2 + var1 * p[4] * 2.0;
+        let var2 = var2 / 4.0 + p[3] * 65536.0;
+        let var1 = (p[2] * var1 * var1 / 524288.0 + p[1] * var1) / 524288.0;
+        let var1 = (1.0 + var1 / 32768.0) * p[0];
+        if var1 == 0.0 {
+            return (temperature, 0.0);
+        }
+        let pressure = 1048576.0 - adc_p as f64;
+        let pressure = (pressure - var2 / 4096.0) * 6250.0 / var1;
+        let var1 = p[8] * pressure * pressure / 2147483648.0;
+        let var2 = pressure * p[7] / 32768.0;
+        (temperature, pressure + (var1 + var2 + p[6]) / 16.0)
+    }
+}
+
+struct Bmp280<I> {
+    i2c: I,
+    calib: Calib,
+    device_id: u32,
+    error_count: u32,
+}
+
+impl<I: I2cDevice> Bmp280<I> {
+    fn new(i2c: I, device_id: u32) -> Self {
+        Bmp280 {
+            i2c,
+            calib: Calib::default(),
+            device_id,
+            error_count: 0,
+        }
+    }
+
+    fn read_regs(&mut self, reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        self.i2c.write_read(&[reg], buf)
+    }
+
+    fn write_reg(&mut self, reg: u8, val: u8) -> io::Result<()> {
+        self.i2c.write_read(&[reg, val], &mut [])
+    }
+
+    fn init(&mut self) -> io::Result<()> {
+        let mut id = [0u8];
+        self.read_regs(regs::CHIP_ID, &mut id)?;
+        if id[0] != regs::CHIP_ID_VAL {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("chip id mismatch:{:#x}", id[0]),
+            ));
+        }
+        self.write_reg(regs::RESET, regs::RESET_VAL)?;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        let mut calib = [0u8; 24];
+        self.read_regs(regs::CALIB, &mut calib)?;
+        self.calib = Calib::from_bytes(&calib);
+        if self.calib.t1 == 0.0 || self.calib.p[0] == 0.0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad calibration data"));
+        }
+
+        self.write_reg(regs::CONFIG, regs::CONFIG_VAL)?;
+        self.write_reg(regs::CTRL_MEAS, regs::CTRL_MEAS_VAL)?;
+        Ok(())
+    }
+
+    fn collect(&mut self) -> Option<SensorBaroMsg> {
+        let mut data = [0u8; 6];
+        if self.read_regs(regs::PRESS_MSB, &mut data).is_err() {
+            self.error_count += 1;
+            return None;
+        }
+        let adc_p = (data[0] as i32) << 12 | (data[1] as i32) << 4 | (data[2] as i32) >> 4;
+        let adc_t = (data[3] as i32) << 12 | (data[4] as i32) << 4 | (data[5] as i32) >> 4;
+        // 0x80000 is the reset value, the measurement is not ready
+        if adc_p == 0x80000 || adc_t == 0x80000 {
+            return None;
+        }
+        let (temperature, pressure) = self.calib.compensate(adc_t, adc_p);
+        Some(SensorBaroMsg {
+            device_id: self.device_id,
+            temperature: temperature as f32,
+            error_count: self.error_count,
+            pressure: pressure as f32,
+            ..Default::default()
+        })
+    }
+}
+
+// device id: [i2c bus id:8][i2c bus:8][address:8]
+fn get_device_id(dev_name: &str, addr: u16) -> u32 {
+    const I2C_BUS_ID: u32 = 3;
+    let bus = dev_name
+        .trim_start_matches("/dev/i2c-")
+        .parse::<u32>()
+        .unwrap_or(0);
+    I2C_BUS_ID << 16 | bus << 8 | (addr as u32 & 0xff)
+}
+
+pub fn bmp280_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        let i2c = match LinuxI2c::open(&args.dev_name, args.addr) {
+            Ok(i2c) => i2c,
+            Err(e) => {
+                thread_logln!("open {} failed: {}", args.dev_name, e);
+                return;
+            }
+        };
+        let mut baro = Bmp280::new(i2c, get_device_id(&args.dev_name, args.addr));
+        if let Err(e) = baro.init() {
+            thread_logln!("bmp280 init failed: {}", e);
+            return;
+        }
+
+        let baro_tx = Publisher::<SensorBaroMsg>::new("baro");
+        let period = args.period as c_long;
+        SchedulePthread::new_fifo(
+            1024 * 1024,
+            90,
+            Box::new(move |s| loop {
+                if let Some(msg) = baro.collect() {
+                    baro_tx.send(msg);
+                }
+                s.schedule_until(period);
+            }),
+        );
+        thread_logln!("bmp280 on {} {:#x} started!", args.dev_name, args.addr);
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    rpos::module::Module::register("bmp280", bmp280_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    // the example values in the bmp280 datasheet
+    const CALIB_EXAMPLE: [u16; 12] = [
+        27504, 26435, (-1000i16) as u16, 36477, (-10685i16) as u16, 3024, 2855, 140,
+        (-7i16) as u16, 15500, (-14600i16) as u16, 6000,
+    ];
+    const ADC_T: i32 = 519888;
+    const ADC_P: i32 = 415148;
+
+    struct MockI2c {
+        regs: HashMap<u8, u8>,
+        writes: Vec<(u8, u8)>,
+    }
+
+    impl MockI2c {
+        fn new() -> Self {
+            let mut regs = HashMap::new();
+            regs.insert(regs::CHIP_ID, regs::CHIP_ID_VAL);
+            for (i, v) in CALIB_EXAMPLE.iter().enumerate() {
+                let [lo, hi] = v.to_le_bytes();
+                regs.insert(regs::CALIB + i as u8 * 2, lo);
+                regs.insert(regs::CALIB + i as u8 * 2 + 1, hi);
+            }
+            let data = [
+                (ADC_P >> 12) as u8,
+                (ADC_P >> 4) as u8,
+                (ADC_P << 4) as u8,
+                (ADC_T >> 12) as u8,
+                (ADC_T >> 4) as u8,
+                (ADC_T << 4) as u8,
+            ];
+            for (i, v) in data.iter().enumerate() {
+                regs.insert(regs::PRESS_MSB + i as u8, *v);
+            }
+            MockI2c { regs, writes: Vec::new() }
+        }
+    }
+
+    impl I2cDevice for MockI2c {
+        fn write_read(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+            if tx.len() == 2 {
+                self.writes.push((tx[0], tx[1]));
+            }
+            for (i, v) in rx.iter_mut().enumerate() {
+                *v = *self.regs.get(&(tx[0] + i as u8)).unwrap_or(&0);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bmp280_compensate() {
+        let mut baro = Bmp280::new(MockI2c::new(), 0);
+        baro.init().unwrap();
+        assert!(baro.i2c.writes.contains(&(regs::CTRL_MEAS, regs::CTRL_MEAS_VAL)));
+        assert!(baro.i2c.writes.contains(&(regs::CONFIG, regs::CONFIG_VAL)));
+
+        let msg = baro.collect().unwrap();
+        assert!((msg.temperature - 25.08).abs() < 0.01, "{}", msg.temperature);
+        assert!((msg.pressure - 100653.27).abs() < 0.1, "{}", msg.pressure);
+    }
+
+    #[test]
+    fn test_bmp280_chip_id() {
+        let mut i2c = MockI2c::new();
+        i2c.regs.insert(regs::CHIP_ID, 0x60); // bme280
+        let mut baro = Bmp280::new(i2c, 0);
+        assert!(baro.init().is_err());
+        assert_eq!(get_device_id("/dev/i2c-1", 0x76), 3 << 16 | 1 << 8 | 0x76);
+        assert_eq!(parse_addr("0x77"), Ok(0x77));
+    }
+}
@@ -0,0 +1,314 @@
+use quaternion_core::Quaternion as Q;
+
+use crate::{
+    basic::rotation::ned_q_to_enu,
+    mode::FlightMode,
+    msg_define::{BatteryStatusMsg, GpsFixType, GpsMsg},
+    rc_input::{unpack_channels, ParserStats},
+};
+
+// address of the flight controller, frames sent to the receiver start with it
+pub const CRSF_SYNC: u8 = 0xC8;
+// the other addresses seen on the bus: handset, receiver, transmitter module
+const CRSF_ADDRESSES: [u8; 4] = [CRSF_SYNC, 0xEA, 0xEC, 0xEE];
+// type + payload + crc
+const CRSF_MIN_LEN: usize = 2;
+const CRSF_MAX_LEN: usize = 62;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameType {
+    Gps = 0x02,
+    Battery = 0x08,
+    LinkStatistics = 0x14,
+    RcChannelsPacked = 0x16,
+    Attitude = 0x1E,
+    FlightMode = 0x21,
+}
+
+pub fn crc8_dvb_s2(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, x| {
+        (0..8).fold(crc ^ x, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0xD5 } else { crc << 1 })
+    })
+}
+
+// [sync, len, type, payload.., crc], len counts the type, payload and crc, crc covers the type and payload
+pub fn build_frame(frame_type: FrameType, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.push(CRSF_SYNC);
+    frame.push(payload.len() as u8 + 2);
+    frame.push(frame_type as u8);
+    frame.extend_from_slice(payload);
+    frame.push(crc8_dvb_s2(&frame[2..]));
+    frame
+}
+
+pub fn battery_frame(msg: &BatteryStatusMsg) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(8);
+    payload.extend_from_slice(&((msg.voltage * 10.0).round().clamp(0.0, 65535.0) as u16).to_be_bytes());
+    payload.extend_from_slice(&((msg.current * 10.0).round().clamp(0.0, 65535.0) as u16).to_be_bytes());
+    payload.extend_from_slice(&(msg.used_mah.round().clamp(0.0, 16777215.0) as u32).to_be_bytes()[1..]);
+    payload.push(if msg.remaining < 0.0 { 0 } else { (msg.remaining * 100.0).round().min(100.0) as u8 });
+    build_frame(FrameType::Battery, &payload)
+}
+
+// crsf uses the aviation euler angles(NED, FRD) in 1/10000 rad
+pub fn attitude_frame(q: Q<f32>) -> Vec<u8> {
+    let (w, [x, y, z]) = ned_q_to_enu(q);
+    let roll = (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y));
+    let pitch = (2.0 * (w * y - z * x)).clamp(-1.0, 1.0).asin();
+    let yaw = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z));
+    let mut payload = Vec::with_capacity(6);
+    for angle in [pitch, roll, yaw] {
+        payload.extend_from_slice(&((angle * 10000.0).round() as i16).to_be_bytes());
+    }
+    build_frame(FrameType::Attitude, &payload)
+}
+
+// shown on the radio screen
+pub fn flight_mode_name(mode: FlightMode) -> &'static str {
+    match mode {
+        FlightMode::Manual => "MANU",
+        FlightMode::Stabilize => "STAB",
+        FlightMode::Altitude => "ALT",
+        FlightMode::Position => "POS",
+        FlightMode::Offboard => "OFFB",
+        FlightMode::Acro => "ACRO",
+    }
+}
+
+pub fn flight_mode_frame(mode: FlightMode) -> Vec<u8> {
+    let mut payload = flight_mode_name(mode).as_bytes().to_vec();
+    payload.push(0);
+    build_frame(FrameType::FlightMode, &payload)
+}
+
+pub fn gps_frame(msg: &GpsMsg) -> Vec<u8> {
+    let speed = (msg.vel_n * msg.vel_n + msg.vel_e * msg.vel_e).sqrt();
+    let heading = msg.vel_e.atan2(msg.vel_n).to_degrees().rem_euclid(360.0);
+    let (lat, lon) = if msg.fix_type == GpsFixType::NoFix { (0.0, 0.0) } else { (msg.lat, msg.lon) };
+    let mut payload = Vec::with_capacity(15);
+    payload.extend_from_slice(&((lat * 1e7).round() as i32).to_be_bytes());
+    payload.extend_from_slice(&((lon * 1e7).round() as i32).to_be_bytes());
+    // km/h * 10
+    payload.extend_from_slice(&((speed * 36.0).round().min(65535.0) as u16).to_be_bytes());
+    payload.extend_from_slice(&((heading * 100.0).round() as u16 % 36000).to_be_bytes());
+    // m + 1000
+    payload.extend_from_slice(&((msg.alt + 1000.0).round().clamp(0.0, 65535.0) as u16).to_be_bytes());
+    payload.push(msg.satellites);
+    build_frame(FrameType::Gps, &payload)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStatistics {
+    pub uplink_rssi_1: u8, // unit:-dBm
+    pub uplink_rssi_2: u8,
+    pub uplink_link_quality: u8, // unit:%
+    pub uplink_snr: i8, // unit:dB
+    pub active_antenna: u8,
+    pub rf_mode: u8,
+    pub uplink_tx_power: u8,
+    pub downlink_rssi: u8,
+    pub downlink_link_quality: u8,
+    pub downlink_snr: i8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrsfPacket {
+    RcChannels([u16; 16]), // 172~1811
+    LinkStatistics(LinkStatistics),
+}
+
+/*
+    splits the byte stream into frames. a frame with a bad crc drops its first byte only,
+    the search goes on inside it. the frames of other types are skipped.
+*/
+#[derive(Default)]
+pub struct CrsfFramer {
+    buf: Vec<u8>,
+    pub stats: ParserStats,
+}
+
+impl CrsfFramer {
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn discard(&mut self, n: usize) {
+        self.buf.drain(..n);
+        self.stats.discarded_bytes += n as u32;
+    }
+
+    pub fn next_packet(&mut self) -> Option<CrsfPacket> {
+        loop {
+            let start = self.buf.iter().position(|x| CRSF_ADDRESSES.contains(x)).unwrap_or(self.buf.len());
+            self.discard(start);
+            if self.buf.len() < 2 {
+                return None;
+            }
+            let len = self.buf[1] as usize;
+            if !(CRSF_MIN_LEN..=CRSF_MAX_LEN).contains(&len) {
+                self.discard(1);
+                continue;
+            }
+            if self.buf.len() < len + 2 {
+                return None;
+            }
+            if crc8_dvb_s2(&self.buf[2..len + 1]) != self.buf[len + 1] {
+                self.stats.crc_errors += 1;
+                self.discard(1);
+                continue;
+            }
+            let frame: Vec<u8> = self.buf.drain(..len + 2).collect();
+            let payload = &frame[3..len + 1];
+            match frame[2] {
+                x if x == FrameType::RcChannelsPacked as u8 && payload.len() == 22 => {
+                    return Some(CrsfPacket::RcChannels(unpack_channels(payload)));
+                }
+                x if x == FrameType::LinkStatistics as u8 && payload.len() == 10 => {
+                    return Some(CrsfPacket::LinkStatistics(LinkStatistics {
+                        uplink_rssi_1: payload[0],
+                        uplink_rssi_2: payload[1],
+                        uplink_link_quality: payload[2],
+                        uplink_snr: payload[3] as i8,
+                        active_antenna: payload[4],
+                        rf_mode: payload[5],
+                        uplink_tx_power: payload[6],
+                        downlink_rssi: payload[7],
+                        downlink_link_quality: payload[8],
+                        downlink_snr: payload[9] as i8,
+                    }));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/*
+    the latest vehicle state to report, next_frame() returns one frame a time in turn,
+    the states never received are skipped.
+*/
+#[derive(Default)]
+pub struct Telemetry {
+    pub battery: Option<BatteryStatusMsg>,
+    pub gps: Option<GpsMsg>,
+    pub attitude: Option<Q<f32>>,
+    pub mode: Option<FlightMode>,
+    index: usize,
+}
+
+impl Telemetry {
+    const FRAME_KINDS: usize = 4;
+
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        for _ in 0..Self::FRAME_KINDS {
+            let index = self.index;
+            self.index = (self.index + 1) % Self::FRAME_KINDS;
+            let frame = match index {
+                0 => self.attitude.map(attitude_frame),
+                1 => self.mode.map(flight_mode_frame),
+                2 => self.battery.as_ref().map(battery_frame),
+                _ => self.gps.as_ref().map(gps_frame),
+            };
+            if frame.is_some() {
+                return frame;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quaternion_core::from_axis_angle;
+
+    #[test]
+    fn test_crc8() {
+        // check value of CRC-8/DVB-S2
+        assert_eq!(crc8_dvb_s2(b"123456789"), 0xBC);
+        let frame = flight_mode_frame(FlightMode::Acro);
+        assert_eq!(frame, [CRSF_SYNC, 7, 0x21, b'A', b'C', b'R', b'O', 0, crc8_dvb_s2(&frame[2..8])]);
+    }
+
+    #[test]
+    fn test_telemetry_frames() {
+        let battery = BatteryStatusMsg { voltage: 16.8, current: 12.34, used_mah: 1500.0, remaining: 0.75, ..Default::default() };
+        assert_eq!(battery_frame(&battery)[3..11], [0x00, 168, 0x00, 123, 0x00, 0x05, 0xDC, 75]);
+
+        // nose up 0.1 rad(around the right axis), heading east(90 degrees right of the north)
+        let q = quaternion_core::mul(
+            from_axis_angle([0.0, 0.0, 1.0], -std::f32::consts::FRAC_PI_2),
+            from_axis_angle([1.0, 0.0, 0.0], 0.1),
+        );
+        let frame = attitude_frame(q);
+        let angles: Vec<i16> = frame[3..9].chunks(2).map(|x| i16::from_be_bytes([x[0], x[1]])).collect();
+        assert!((angles[0] - 1000).abs() <= 1 && angles[1].abs() <= 1 && (angles[2] - 15708).abs() <= 1, "{:?}", angles);
+
+        let gps = GpsMsg {
+            fix_type: GpsFixType::Fix3D,
+            lat: 22.5,
+            lon: -113.25,
+            alt: 50.0,
+            vel_n: 0.0,
+            vel_e: 10.0,
+            satellites: 12,
+            ..Default::default()
+        };
+        let frame = gps_frame(&gps);
+        assert_eq!(frame[1] as usize, frame.len() - 2);
+        assert_eq!(i32::from_be_bytes(frame[3..7].try_into().unwrap()), 225_000_000);
+        assert_eq!(i32::from_be_bytes(frame[7..11].try_into().unwrap()), -1_132_500_000);
+        assert_eq!(frame[11..18], [0x01, 0x68, 0x23, 0x28, 0x04, 0x1A, 12]);
+    }
+
+    #[test]
+    fn test_crsf_framer() {
+        let mut channels = [0u8; 22];
+        // channel 0: 992 = 0b011_1110_0000, channel 1: 0x7FF
+        channels[0] = 0xE0;
+        channels[1] = 0xFB;
+        channels[2] = 0x3F;
+        let mut stream = vec![0x00, 0x11];
+        stream.extend(build_frame(FrameType::RcChannelsPacked, &channels));
+        // crc error
+        let mut broken = build_frame(FrameType::RcChannelsPacked, &channels);
+        broken[5] ^= 0x01;
+        stream.extend(broken);
+        // skipped
+        stream.extend(flight_mode_frame(FlightMode::Acro));
+        stream.extend(build_frame(FrameType::LinkStatistics, &[80, 90, 100, 0xFB, 1, 2, 3, 70, 50, 4]));
+
+        let mut framer = CrsfFramer::default();
+        let mut packets = Vec::new();
+        for chunk in stream.chunks(9) {
+            framer.push_bytes(chunk);
+            while let Some(packet) = framer.next_packet() {
+                packets.push(packet);
+            }
+        }
+        assert_eq!(packets.len(), 2);
+        match packets[0] {
+            CrsfPacket::RcChannels(x) => assert_eq!(x[..3], [992, 0x7FF, 0]),
+            _ => panic!("expect rc channels"),
+        }
+        match packets[1] {
+            CrsfPacket::LinkStatistics(x) => {
+                assert_eq!((x.uplink_rssi_2, x.uplink_link_quality, x.uplink_snr, x.downlink_link_quality), (90, 100, -5, 50))
+            }
+            _ => panic!("expect link statistics"),
+        }
+        assert_eq!(framer.stats.crc_errors, 1);
+        assert!(framer.stats.discarded_bytes >= 2 + 1);
+    }
+
+    #[test]
+    fn test_telemetry_rotation() {
+        let mut telemetry = Telemetry::default();
+        assert!(telemetry.next_frame().is_none());
+        telemetry.mode = Some(FlightMode::Stabilize);
+        telemetry.battery = Some(BatteryStatusMsg::default());
+        let types: Vec<u8> = (0..4).map(|_| telemetry.next_frame().unwrap()[2]).collect();
+        assert_eq!(types, [0x21, 0x08, 0x21, 0x08]);
+    }
+}
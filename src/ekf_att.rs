@@ -0,0 +1,411 @@
+use std::{ffi::c_void, ptr::null_mut, sync::Arc};
+
+use quaternion_core::{normalize, point_rotation, frame_rotation, Quaternion as Q};
+use rpos::{libc::c_long, msg::get_new_rx_of_message, pthread_scheduler::SchedulePthread};
+
+use crate::{
+    basic::rotation::mag_yaw_error,
+    msg_define::{Publisher, SensorAccelMsg, SensorGyroMsg, SensorMagMsg, Stamped, Vector4},
+    param::{self, ParameterData},
+};
+
+const GRAVITY: f32 = 9.80665;
+
+/*
+    error state extended kalman filter
+    nominal state: attitude q(body to world), velocity v(world), gyro bias bg, accel bias ba
+    error state(12): [d_theta(body), d_v, d_bg, d_ba]
+*/
+const N: usize = 12;
+const THETA: usize = 0;
+const VEL: usize = 3;
+const BG: usize = 6;
+const BA: usize = 9;
+
+type Mat = [[f32; N]; N];
+
+fn skew(v: [f32; 3]) -> [[f32; 3]; 3] {
+    [[0.0, -v[2], v[1]], [v[2], 0.0, -v[0]], [-v[1], v[0], 0.0]]
+}
+
+fn dcm(q: Q<f32>) -> [[f32; 3]; 3] {
+    let cols = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]].map(|x| point_rotation(q, x));
+    let mut r = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            r[i][j] = cols[j][i];
+        }
+    }
+    r
+}
+
+fn mat3_mul(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut r = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            r[i][j] = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    r
+}
+
+pub struct EkfParams {
+    pub gyro_noise: f32,     // rad/s
+    pub acc_noise: f32,      // m/s^2
+    pub gyro_bias_rw: f32,   // rad/s^2
+    pub acc_bias_rw: f32,    // m/s^3
+    pub acc_meas_noise: f32, // m/s^2, noise when the accelerometer is used as gravity
+    pub mag_heading_noise: f32, // rad
+    pub mag_decl: f32,       // rad
+    pub innov_gate: f32,     // sigma
+}
+
+impl Default for EkfParams {
+    fn default() -> Self {
+        EkfParams {
+            gyro_noise: 0.015,
+            acc_noise: 0.35,
+            gyro_bias_rw: 0.001,
+            acc_bias_rw: 0.003,
+            acc_meas_noise: 0.5,
+            mag_heading_noise: 0.1,
+            mag_decl: 0.0,
+            innov_gate: 5.0,
+        }
+    }
+}
+
+impl EkfParams {
+    fn from_params() -> Self {
+        let get = |name: &str, default: f32| param::get_param(name).map_or(default, |x| x.as_f32());
+        let default = EkfParams::default();
+        EkfParams {
+            gyro_noise: get("ekf_gyr_noise", default.gyro_noise),
+            acc_noise: get("ekf_acc_noise", default.acc_noise),
+            acc_meas_noise: get("ekf_acc_meas", default.acc_meas_noise),
+            mag_heading_noise: get("ekf_mag_noise", default.mag_heading_noise),
+            mag_decl: get("mag_decl", 0.0).to_radians(),
+            innov_gate: get("ekf_gate", default.innov_gate),
+            ..default
+        }
+    }
+}
+
+pub struct AttitudeEkf {
+    pub q: Q<f32>,
+    pub vel: [f32; 3],
+    pub gyro_bias: [f32; 3],
+    pub acc_bias: [f32; 3],
+    p: Mat,
+    pub params: EkfParams,
+    pub rejected_count: u32, // measurements rejected by the innovation check
+}
+
+impl AttitudeEkf {
+    pub fn new(params: EkfParams) -> Self {
+        let mut p = [[0.0; N]; N];
+        for i in 0..3 {
+            p[THETA + i][THETA + i] = 0.1;
+            p[VEL + i][VEL + i] = 1.0;
+            p[BG + i][BG + i] = 1e-3;
+            p[BA + i][BA + i] = 1e-2;
+        }
+        AttitudeEkf {
+            q: (1.0, [0.0; 3]),
+            vel: [0.0; 3],
+            gyro_bias: [0.0; 3],
+            acc_bias: [0.0; 3],
+            p,
+            params,
+            rejected_count: 0,
+        }
+    }
+
+    pub fn variance(&self, index: usize) -> f32 {
+        self.p[index][index]
+    }
+
+    pub fn predict(&mut self, gyro: [f32; 3], acc: [f32; 3], dt: f32) {
+        let w = [0, 1, 2].map(|i| gyro[i] - self.gyro_bias[i]);
+        let f = [0, 1, 2].map(|i| acc[i] - self.acc_bias[i]);
+
+        let dq = quaternion_core::from_axis_angle(w, quaternion_core::norm(w) * dt);
+        let r = dcm(self.q);
+        self.q = normalize(quaternion_core::mul(self.q, dq));
+
+        let acc_world = point_rotation(self.q, f);
+        for i in 0..3 {
+            self.vel[i] += acc_world[i] * dt;
+        }
+        self.vel[2] -= GRAVITY * dt;
+
+        // phi = I + F * dt
+        let mut phi = [[0.0; N]; N];
+        for i in 0..N {
+            phi[i][i] = 1.0;
+        }
+        let w_skew = skew(w);
+        let rf = mat3_mul(&r, &skew(f));
+        for i in 0..3 {
+            for j in 0..3 {
+                phi[THETA + i][THETA + j] -= w_skew[i][j] * dt;
+                phi[VEL + i][THETA + j] = -rf[i][j] * dt;
+                phi[VEL + i][BA + j] = -r[i][j] * dt;
+            }
+            phi[THETA + i][BG + i] = -dt;
+        }
+
+        // p = phi * p * phi^T + q
+        let mut tmp = [[0.0; N]; N];
+        for i in 0..N {
+            for j in 0..N {
+                tmp[i][j] = (0..N).map(|k| phi[i][k] * self.p[k][j]).sum();
+            }
+        }
+        for i in 0..N {
+            for j in 0..N {
+                self.p[i][j] = (0..N).map(|k| tmp[i][k] * phi[j][k]).sum();
+            }
+        }
+
+        let params = &self.params;
+        for i in 0..3 {
+            self.p[THETA + i][THETA + i] += (params.gyro_noise * dt).powi(2);
+            self.p[VEL + i][VEL + i] += (params.acc_noise * dt).powi(2);
+            self.p[BG + i][BG + i] += (params.gyro_bias_rw * dt).powi(2);
+            self.p[BA + i][BA + i] += (params.acc_bias_rw * dt).powi(2);
+        }
+    }
+
+    // scalar measurement update, return false if the innovation check failed.
+    fn fuse_scalar(&mut self, h: &[f32; N], innovation: f32, meas_var: f32) -> bool {
+        let mut ph = [0.0; N]; // P * H^T
+        for i in 0..N {
+            ph[i] = (0..N).map(|k| self.p[i][k] * h[k]).sum();
+        }
+        let s: f32 = (0..N).map(|k| h[k] * ph[k]).sum::<f32>() + meas_var;
+        if innovation * innovation > s * self.params.innov_gate.powi(2) {
+            self.rejected_count += 1;
+            return false;
+        }
+
+        let k = ph.map(|x| x / s);
+        for i in 0..N {
+            for j in 0..N {
+                self.p[i][j] -= k[i] * ph[j];
+            }
+        }
+        // keep the covariance symmetric
+        for i in 0..N {
+            for j in 0..i {
+                let avg = (self.p[i][j] + self.p[j][i]) / 2.0;
+                self.p[i][j] = avg;
+                self.p[j][i] = avg;
+            }
+        }
+
+        let dx = k.map(|x| x * innovation);
+        let d_theta = [dx[THETA], dx[THETA + 1], dx[THETA + 2]];
+        self.q = normalize(quaternion_core::mul(
+            self.q,
+            (1.0, [d_theta[0] / 2.0, d_theta[1] / 2.0, d_theta[2] / 2.0]),
+        ));
+        for i in 0..3 {
+            self.vel[i] += dx[VEL + i];
+            self.gyro_bias[i] += dx[BG + i];
+            self.acc_bias[i] += dx[BA + i];
+        }
+        true
+    }
+
+    // use the accelerometer as the gravity direction, skipped when the vehicle accelerates.
+    pub fn fuse_gravity(&mut self, acc: [f32; 3]) {
+        let norm = quaternion_core::norm(acc);
+        if (norm - GRAVITY).abs() > 0.2 * GRAVITY {
+            return;
+        }
+        for axis in 0..3 {
+            let g_body = frame_rotation(self.q, [0.0, 0.0, GRAVITY]);
+            let predict = g_body[axis] + self.acc_bias[axis];
+            let g_skew = skew(g_body);
+            let mut h = [0.0; N];
+            for j in 0..3 {
+                h[THETA + j] = g_skew[axis][j];
+            }
+            h[BA + axis] = 1.0;
+            self.fuse_scalar(&h, acc[axis] - predict, self.params.acc_meas_noise.powi(2));
+        }
+    }
+
+    // heading measured by the magnetometer, only the yaw(rotation around world z) is observed.
+    pub fn fuse_heading(&mut self, mag: [f32; 3]) {
+        if let Some(err) = mag_yaw_error(self.q, mag, self.params.mag_decl) {
+            // world z rotation = R * d_theta
+            let r = dcm(self.q);
+            let mut h = [0.0; N];
+            for j in 0..3 {
+                h[THETA + j] = r[2][j];
+            }
+            self.fuse_scalar(&h, err, self.params.mag_heading_noise.powi(2));
+        }
+    }
+
+    // world frame velocity measurement, eg. gps or a zero velocity pseudo measurement
+    pub fn fuse_velocity(&mut self, vel: [f32; 3], var: f32) {
+        for axis in 0..3 {
+            let mut h = [0.0; N];
+            h[VEL + axis] = 1.0;
+            self.fuse_scalar(&h, vel[axis] - self.vel[axis], var);
+        }
+    }
+}
+
+fn ekf_att_main(ptr: *mut c_void) -> *mut c_void {
+    let sp = unsafe { Arc::from_raw(ptr as *const SchedulePthread) };
+    let mut gyro_rx = get_new_rx_of_message::<SensorGyroMsg>("gyro").unwrap();
+    let mut acc_rx = get_new_rx_of_message::<SensorAccelMsg>("acc").unwrap();
+    let mut mag_rx = get_new_rx_of_message::<SensorMagMsg>("mag").unwrap();
+    let q_tx = Publisher::<Stamped<Vector4>>::new("attitude");
+
+    let mut ekf = AttitudeEkf::new(EkfParams::from_params());
+
+    const EKF_PERIOD_US: c_long = 2000;
+    const EKF_MAX_DT_US: u64 = 100_000;
+    // without any velocity source, keep the velocity bounded with a loose zero velocity measurement
+    const FAKE_VEL_VAR: f32 = 25.0;
+
+    let mut acc_data: [f32; 3] = [0.0, 0.0, GRAVITY];
+    let mut last_gyro_timestamp: u64 = 0;
+    let mut cnt: u32 = 0;
+
+    loop {
+        if let Some(acc_msg) = acc_rx.try_read() {
+            acc_data = [acc_msg.acc.x, acc_msg.acc.y, acc_msg.acc.z];
+        }
+        if let Some(gyro_msg) = gyro_rx.try_read() {
+            let gyro_data = [gyro_msg.rate.x, gyro_msg.rate.y, gyro_msg.rate.z];
+            let timestamp = gyro_msg.header.timestamp;
+            if last_gyro_timestamp != 0
+                && timestamp > last_gyro_timestamp
+                && timestamp - last_gyro_timestamp < EKF_MAX_DT_US
+            {
+                let dt = (timestamp - last_gyro_timestamp) as f32 / 1000_000.0;
+                ekf.predict(gyro_data, acc_data, dt);
+                ekf.fuse_gravity(acc_data);
+                if let Some(mag_msg) = mag_rx.try_read() {
+                    ekf.fuse_heading([mag_msg.mag.x, mag_msg.mag.y, mag_msg.mag.z]);
+                }
+                cnt += 1;
+                if cnt % 50 == 0 {
+                    ekf.fuse_velocity([0.0; 3], FAKE_VEL_VAR);
+                    ekf.params = EkfParams::from_params();
+                }
+
+                if param::get_param("est_type").map_or(0, |x| x.as_i32()) == 1 {
+                    q_tx.send(Stamped::new(Vector4 {
+                        w: ekf.q.0,
+                        x: ekf.q.1[0],
+                        y: ekf.q.1[1],
+                        z: ekf.q.1[2],
+                    }));
+                }
+            }
+            last_gyro_timestamp = timestamp;
+        }
+        sp.schedule_until(EKF_PERIOD_US);
+    }
+    #[allow(unreachable_code)]
+    null_mut()
+}
+
+pub fn init_ekf_att(_argc: u32, _argv: *const &str) {
+    SchedulePthread::new(1024 * 1024, 97, ekf_att_main, null_mut(), false);
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    let default = EkfParams::default();
+    param::add_param("ekf_gyr_noise", ParameterData::Float(default.gyro_noise));
+    param::add_param("ekf_acc_noise", ParameterData::Float(default.acc_noise));
+    param::add_param("ekf_acc_meas", ParameterData::Float(default.acc_meas_noise));
+    param::add_param("ekf_mag_noise", ParameterData::Float(default.mag_heading_noise));
+    param::add_param("ekf_gate", ParameterData::Float(default.innov_gate));
+    rpos::module::Module::register("ekf_att", init_ekf_att);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.005;
+
+    fn tilt_deg(q: Q<f32>) -> f32 {
+        let z = point_rotation(q, [0.0, 0.0, 1.0]);
+        z[2].clamp(-1.0, 1.0).acos().to_degrees()
+    }
+
+    #[test]
+    fn test_ekf_static_gyro_bias() {
+        let bias = [0.02, -0.015, 0.0];
+        let mut ekf = AttitudeEkf::new(EkfParams::default());
+        for _ in 0..(60.0 / DT) as usize {
+            ekf.predict(bias, [0.0, 0.0, GRAVITY], DT);
+            ekf.fuse_gravity([0.0, 0.0, GRAVITY]);
+        }
+        assert!(tilt_deg(ekf.q) < 0.5, "tilt:{}", tilt_deg(ekf.q));
+        assert!((ekf.gyro_bias[0] - bias[0]).abs() < 2e-3, "{:?}", ekf.gyro_bias);
+        assert!((ekf.gyro_bias[1] - bias[1]).abs() < 2e-3, "{:?}", ekf.gyro_bias);
+        assert!(ekf.variance(BG) < 1e-3);
+    }
+
+    #[test]
+    fn test_ekf_converge_to_tilt() {
+        // the vehicle is rolled 20 degree, while the filter starts level
+        let q_true = quaternion_core::from_axis_angle([0.0, 1.0, 0.0], 20.0f32.to_radians());
+        let acc = frame_rotation(q_true, [0.0, 0.0, GRAVITY]);
+        let mut ekf = AttitudeEkf::new(EkfParams::default());
+        for _ in 0..(10.0 / DT) as usize {
+            ekf.predict([0.0; 3], acc, DT);
+            ekf.fuse_gravity(acc);
+            ekf.fuse_velocity([0.0; 3], 0.25);
+        }
+        assert!((tilt_deg(ekf.q) - 20.0).abs() < 1.0, "tilt:{}", tilt_deg(ekf.q));
+    }
+
+    #[test]
+    fn test_ekf_innovation_gate() {
+        let mut ekf = AttitudeEkf::new(EkfParams::default());
+        for _ in 0..(10.0 / DT) as usize {
+            ekf.predict([0.0; 3], [0.0, 0.0, GRAVITY], DT);
+            ekf.fuse_gravity([0.0, 0.0, GRAVITY]);
+        }
+        assert_eq!(ekf.rejected_count, 0);
+
+        // a fault sensor which still reads 1g but in a wrong direction
+        let bad = [GRAVITY * 0.6, 0.0, GRAVITY * 0.8];
+        ekf.fuse_gravity(bad);
+        assert!(ekf.rejected_count > 0);
+        assert!(tilt_deg(ekf.q) < 0.5);
+    }
+
+    #[test]
+    fn test_ekf_mag_heading() {
+        let yaw_true = 1.0f32;
+        let q_true = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], yaw_true);
+        let mag = frame_rotation(q_true, [0.0, 0.2, -0.4]);
+        let bias = [0.0, 0.0, 0.01];
+        let mut ekf = AttitudeEkf::new(EkfParams::default());
+        ekf.q = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], yaw_true - 0.3);
+        for i in 0..(60.0 / DT) as usize {
+            ekf.predict(bias, [0.0, 0.0, GRAVITY], DT);
+            ekf.fuse_gravity([0.0, 0.0, GRAVITY]);
+            if i % 4 == 0 {
+                ekf.fuse_heading(mag);
+            }
+        }
+        let err = mag_yaw_error(ekf.q, mag, 0.0).unwrap();
+        assert!(err.abs() < 1.0f32.to_radians(), "yaw err:{}", err);
+        assert!((ekf.gyro_bias[2] - bias[2]).abs() < 2e-3, "{:?}", ekf.gyro_bias);
+        assert!(tilt_deg(ekf.q) < 0.5);
+    }
+}
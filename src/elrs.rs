@@ -1,9 +1,16 @@
-use std::{fs::OpenOptions, io::Read, time::Duration};
-
 use clap::Parser;
-use rpos::{channel::Sender, msg::get_new_tx_of_message, thread_logln, pthread_scheduler::SchedulePthread};
+use rpos::{channel::Receiver, msg::get_new_rx_of_message, pthread_scheduler::SchedulePthread, thread_logln};
+use serialport::{Parity, StopBits};
 
-use crate::msg_define::RcInputMsg;
+use crate::{
+    crsf_frame::{CrsfFramer, CrsfPacket, Telemetry},
+    mode::FlightMode,
+    msg_define::{
+        BatteryStatusMsg, GpsMsg, MsgHeader, Publisher, RcInputMsg, RcLinkQualityMsg, RcSource, Stamped, Vector4,
+        RC_INPUT_MAX_CHANNELS,
+    },
+    rc_input::{crsf_to_us, open_device, ParserStats, RcProtocol, RcReader},
+};
 
 #[derive(Parser)]
 #[command(name="erls", about = None, long_about = None)]
@@ -14,67 +21,134 @@ struct Cli {
     dev_name: String,
 }
 
-struct Elrs {
-    tx: Sender<RcInputMsg>,
-    dev: Box<dyn Read>,
-    parser: crsf::CrsfPacketParser,
+struct Crsf {
+    framer: CrsfFramer,
+    link_tx: Publisher<RcLinkQualityMsg>,
+    failsafe: bool,
 }
 
-impl Elrs {
-    fn new(dev:Box<dyn Read>) -> Self {
-        Elrs {
-            tx: get_new_tx_of_message("rc_input").unwrap(),
-            dev,
-            parser: crsf::CrsfPacketParser::default(),
-        }
+impl RcProtocol for Crsf {
+    fn source(&self) -> RcSource {
+        RcSource::Crsf
     }
 
-    fn process(&mut self) {
-        let mut buf = [0; 1024];
-        let ret = self.dev.read(&mut buf);
-        if ret.is_err(){
-            return ;
-        }
-        let len = ret.unwrap();
-        self.parser.push_bytes(&buf[..len]);
+    fn stats(&self) -> ParserStats {
+        self.framer.stats
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.framer.push_bytes(bytes);
+    }
 
-        while let Some(packet) = self.parser.next_packet() {
+    fn next_frame(&mut self) -> Option<RcInputMsg> {
+        while let Some(packet) = self.framer.next_packet() {
             match packet {
-                crsf::Packet::RcChannelsPacked(channels) => {
-                    // crsf channel value range: 0 ~ 1984
-                    // output rcInput msg must be: -1000 ~ 1000
-                    // ignore some value near 1000 to simplify the program.
-                    let mut v: [u16; 8] = [0; 8];
-                    v.copy_from_slice(&channels[0..8]);
-                    self.tx.send(RcInputMsg { channel_vals: v.map(|x| (x as i16 - 1000).clamp(-1000, 1000) ) })
+                CrsfPacket::RcChannels(channels) => {
+                    return Some(RcInputMsg {
+                        header: MsgHeader::default(),
+                        source: RcSource::Crsf,
+                        channel_count: RC_INPUT_MAX_CHANNELS as u8,
+                        failsafe: self.failsafe,
+                        frame_lost: false,
+                        channel_vals: channels.map(crsf_to_us),
+                    });
+                }
+                CrsfPacket::LinkStatistics(stats) => {
+                    // the receiver keeps sending the statistics with 0 link quality after the link is lost
+                    self.failsafe = stats.uplink_link_quality == 0;
+                    let rssi = if stats.active_antenna == 0 { stats.uplink_rssi_1 } else { stats.uplink_rssi_2 };
+                    self.link_tx.send(RcLinkQualityMsg {
+                        header: MsgHeader::default(),
+                        source: RcSource::Crsf,
+                        rssi: -(rssi as i16),
+                        link_quality: stats.uplink_link_quality,
+                        snr: stats.uplink_snr,
+                        downlink_link_quality: stats.downlink_link_quality,
+                    });
                 }
-                _ => {}
             }
         }
+        None
+    }
+}
+
+impl Crsf {
+    fn new() -> Self {
+        Crsf { framer: CrsfFramer::default(), link_tx: Publisher::new("rc_link_quality"), failsafe: false }
     }
 }
 
+// the receiver reads the rc channels and takes the telemetry frames back
+struct Elrs {
+    reader: RcReader<Crsf>,
+    telemetry: Telemetry,
+    battery_rx: Receiver<BatteryStatusMsg>,
+    gps_rx: Receiver<GpsMsg>,
+    att_rx: Receiver<Stamped<Vector4>>,
+}
+
+impl Elrs {
+    #[cfg(test)]
+    fn new(dev: Box<dyn crate::rc_input::SerialDevice>, rc_topic: &str) -> Self {
+        Self::with_reader(RcReader::new(dev, Crsf::new(), rc_topic))
+    }
+
+    fn with_reader(reader: RcReader<Crsf>) -> Self {
+        Elrs {
+            reader,
+            telemetry: Telemetry::default(),
+            battery_rx: get_new_rx_of_message("battery_status").unwrap(),
+            gps_rx: get_new_rx_of_message("gps").unwrap(),
+            att_rx: get_new_rx_of_message("attitude").unwrap(),
+        }
+    }
+
+    fn process(&mut self) {
+        self.reader.process();
+    }
 
+    // one telemetry frame a call
+    fn send_telemetry(&mut self) {
+        if let Some(msg) = self.battery_rx.try_read() {
+            self.telemetry.battery = Some(msg);
+        }
+        if let Some(msg) = self.gps_rx.try_read() {
+            self.telemetry.gps = Some(msg);
+        }
+        if let Some(att) = self.att_rx.try_read() {
+            self.telemetry.attitude = Some((att.w, [att.x, att.y, att.z]));
+        }
+        self.telemetry.mode = Some(FlightMode::current());
+        if !self.reader.connected() {
+            return;
+        }
+        if let Some(frame) = self.telemetry.next_frame() {
+            // a write error closes the device, the reader reopens it
+            let _ = self.reader.write(&frame);
+        }
+    }
+}
 
 pub fn elrs_main(argc: u32, argv: *const &str) {
     if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
-        let dev_name = &args.dev_name;
-        let dev:Box<dyn Read>;
-        if dev_name.contains("/dev/"){
-            let serial = serialport::new(dev_name, args.baudrate);
-            dev = serial.timeout(Duration::from_millis(1000)).open().unwrap();
-        }else{
-            let file = OpenOptions::new().read(true).open(dev_name).unwrap();
-            dev = Box::new(file);
-        }
-        let mut elrs = Elrs::new(dev);
+        let dev_name = args.dev_name.clone();
+        let baudrate = args.baudrate;
+        // opened in the thread, a receiver plugged in later is picked up
+        let reader = RcReader::with_opener(move || open_device(&dev_name, baudrate, Parity::None, StopBits::One), Crsf::new());
+        let mut elrs = Elrs::with_reader(reader);
         SchedulePthread::new_simple(Box::new(move |s|{
+            let mut cnt: u32 = 0;
             loop{
                 elrs.process();
+                // 20 frames per second, each kind is updated at 5Hz
+                cnt += 1;
+                if cnt % 25 == 0 {
+                    elrs.send_telemetry();
+                }
                 s.schedule_until(2000);
             }
         }));
-        thread_logln!("dev:{}", dev_name);
+        thread_logln!("elrs dev:{}", args.dev_name);
     }
 }
 
@@ -122,7 +196,7 @@ mod tests {
     use crsf::PacketType;
     use rand::thread_rng;
     use rand::Rng;
-    use rpos::msg::get_new_rx_of_message;
+    use rpos::msg::{add_message, get_new_rx_of_message};
 
     fn new_rc_channel_packet(dest: Destination, channel_vals: &[u16]) -> [u8; 26] {
         let mut buf: [u8; 26] = [0; 26];
@@ -153,13 +227,21 @@ mod tests {
             _ => panic!("failed"),
         }
     }
+    #[test]
+    fn test_crsf_to_us() {
+        assert_eq!(crsf_to_us(172), 988);
+        assert_eq!(crsf_to_us(992), 1500);
+        assert_eq!(crsf_to_us(1811), 2011);
+    }
+
     #[test]
     fn elrs_basic_test() {
+        let path = std::env::temp_dir().join("elrs_test");
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open("elrs_test")
+            .open(&path)
             .unwrap();
         let mut rng = thread_rng();
         for i in 0..5 {
@@ -194,13 +276,59 @@ mod tests {
         }
         drop(file);
 
-        let file = OpenOptions::new().read(true).open("elrs_test").unwrap();
+        let file = OpenOptions::new().read(true).open(&path).unwrap();
         let dev = Box::new(file);
-        let mut elrs = Elrs::new(dev);
-        let mut rx = get_new_rx_of_message::<RcInputMsg>("rc_input").unwrap();
+        // a topic of its own, other tests publish rc_input too
+        add_message::<RcInputMsg>("elrs_basic_test");
+        let mut elrs = Elrs::new(dev, "elrs_basic_test");
+        let mut rx = get_new_rx_of_message::<RcInputMsg>("elrs_basic_test").unwrap();
         elrs.process();
         let msg = rx.read();
-        assert_eq!(msg.channel_vals[0], ((cnt - 1) * 100) as i16);
+        assert_eq!(msg.channel_vals[0], crsf_to_us(1000 + (cnt - 1) * 100));
+        assert_eq!(msg.channel_vals[15], crsf_to_us(1000 + (cnt - 1) * 100));
+        assert_eq!((msg.source, msg.channel_count, msg.failsafe), (RcSource::Crsf, 16, false));
         println!("{:?}", msg.channel_vals);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    struct FakeDev {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for FakeDev {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakeDev {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn elrs_link_statistics_test() {
+        use crate::crsf_frame::crc8_dvb_s2;
+        // rssi -80/-90dBm, lq 0, snr -5, antenna 1, ... downlink lq 50
+        let mut input = vec![0xC8, 12, 0x14, 80, 90, 0, (-5i8) as u8, 1, 2, 3, 70, 50, 4];
+        input.push(crc8_dvb_s2(&input[2..]));
+        input.extend(new_rc_channel_packet(Destination::Controller, &[992; 16]));
+        let dev = FakeDev { input: std::io::Cursor::new(input), output: Vec::new() };
+        add_message::<RcInputMsg>("elrs_link_statistics_test");
+        let mut elrs = Elrs::new(Box::new(dev), "elrs_link_statistics_test");
+        let mut rc_rx = get_new_rx_of_message::<RcInputMsg>("elrs_link_statistics_test").unwrap();
+        let mut link_rx = get_new_rx_of_message::<RcLinkQualityMsg>("rc_link_quality").unwrap();
+        elrs.process();
+
+        let link = link_rx.read();
+        assert_eq!((link.rssi, link.link_quality, link.snr, link.downlink_link_quality), (-90, 0, -5, 50));
+        let msg = rc_rx.read();
+        assert!(msg.failsafe);
+        assert_eq!(msg.channel_vals[0], 1500);
     }
 }
@@ -1,31 +1,127 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
 
-use rpos::server_client::setup_client_stdin_out;
+use rpos::{libc::c_long, pthread_scheduler::SchedulePthread, server_client::setup_client_stdin_out};
 use termion::{self, event::Key, input::TermRead, raw::IntoRawMode};
 
-struct Channel {
-    name: String,
-    add_key: char,
-    sub_key: char,
-    value: i16,
+use crate::msg_define::{MsgHeader, Publisher, RcInputMsg, RcSource, RC_INPUT_MAX_CHANNELS};
+
+/*
+    a virtual rc driven by the keyboard, published as rc_input in the default channel order of rc_update:
+    roll, pitch, throttle, yaw, mode switch, arm switch(set rc_map_arm to 6, then the motors
+    are stopped until it is switched on with the throttle at the bottom, see arming).
+    the terminal reports no key release, so roll, pitch and yaw return to the centre
+    when their keys are not pressed(repeated) for a while.
+*/
+const PUBLISH_PERIOD_US: c_long = 20_000;
+const STICK_STEP: f32 = 0.25;
+const THROTTLE_STEP: f32 = 0.05;
+// longer than the delay before the key repeats, 500~660ms on most desktops
+const HOLD_TIME: f32 = 0.8;
+// full stick to centre in 0.25s
+const CENTRING_RATE: f32 = 4.0;
+
+const ROLL: usize = 0;
+const PITCH: usize = 1;
+const THROTTLE: usize = 2;
+const YAW: usize = 3;
+
+pub struct VirtualRc {
+    // [roll, pitch, throttle, yaw] in [-1,1], positive: right, forward, up, right
+    sticks: [f32; 4],
+    // time since the last press of each stick
+    idle: [f32; 4],
+    mode_switch: usize, // 0~2
+    armed: bool,
+}
+
+impl Default for VirtualRc {
+    fn default() -> Self {
+        VirtualRc { sticks: [0.0, 0.0, -1.0, 0.0], idle: [0.0; 4], mode_switch: 0, armed: false }
+    }
 }
 
-impl Channel {
-    fn new(name: &str, add_key: char, sub_key: char, init_val: i16) -> Self {
-        Self {
-            name: name.to_string(),
-            add_key,
-            sub_key,
-            value: init_val,
+impl VirtualRc {
+    fn push_stick(&mut self, index: usize, step: f32) {
+        self.sticks[index] = (self.sticks[index] + step).clamp(-1.0, 1.0);
+        self.idle[index] = 0.0;
+    }
+
+    // returns false if the key is not used
+    pub fn press(&mut self, key: char) -> bool {
+        match key {
+            'w' => self.push_stick(THROTTLE, THROTTLE_STEP),
+            's' => self.push_stick(THROTTLE, -THROTTLE_STEP),
+            'a' => self.push_stick(YAW, -STICK_STEP),
+            'd' => self.push_stick(YAW, STICK_STEP),
+            'h' => self.push_stick(ROLL, -STICK_STEP),
+            'l' => self.push_stick(ROLL, STICK_STEP),
+            'k' => self.push_stick(PITCH, STICK_STEP),
+            'j' => self.push_stick(PITCH, -STICK_STEP),
+            '1'..='3' => self.mode_switch = key as usize - '1' as usize,
+            'r' => self.armed = !self.armed,
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        for i in [ROLL, PITCH, YAW] {
+            self.idle[i] += dt;
+            if self.idle[i] > HOLD_TIME {
+                let x = self.sticks[i];
+                self.sticks[i] = x - x.signum() * (CENTRING_RATE * dt).min(x.abs());
+            }
+        }
+    }
+
+    pub fn to_msg(&self) -> RcInputMsg {
+        let us = |x: f32| (1500.0 + x * 500.0).round() as i16;
+        let mut channel_vals = [1500; RC_INPUT_MAX_CHANNELS];
+        for (i, x) in self.sticks.iter().enumerate() {
+            channel_vals[i] = us(*x);
+        }
+        channel_vals[4] = [1000, 1500, 2000][self.mode_switch];
+        channel_vals[5] = if self.armed { 2000 } else { 1000 };
+        RcInputMsg {
+            header: MsgHeader::default(),
+            source: RcSource::Keyboard,
+            channel_count: 8,
+            failsafe: false,
+            frame_lost: false,
+            channel_vals,
         }
     }
 }
 
 pub fn init_fake_linux_input(_argc: u32, _argv: *const &str) {
-    let thust_chn = Channel::new("Thrust", 'w', 's', 0);
-    let direction_chn = Channel::new("Direction", 'd', 'a', 5000);
-    let ele_chn = Channel::new("Elevator", 'j', 'k', 5000);
-    let aile_chn = Channel::new("Aileron", 'h', 'l', 5000);
-    let mut channels = [thust_chn, direction_chn, ele_chn, aile_chn];
+    let rc = Arc::new(Mutex::new(VirtualRc::default()));
+    let running = Arc::new(AtomicBool::new(true));
+
+    // published at a fixed rate, the keys come at any time
+    {
+        let rc = rc.clone();
+        let running = running.clone();
+        SchedulePthread::new_simple(Box::new(move |s| {
+            let tx = Publisher::<RcInputMsg>::new("rc_input");
+            let dt = PUBLISH_PERIOD_US as f32 / 1000_000.0;
+            while running.load(Ordering::Relaxed) {
+                let msg = {
+                    let mut rc = rc.lock().unwrap();
+                    rc.update(dt);
+                    rc.to_msg()
+                };
+                tx.send(msg);
+                s.schedule_until(PUBLISH_PERIOD_US);
+            }
+            // the pilot is gone
+            let mut msg = VirtualRc::default().to_msg();
+            msg.failsafe = true;
+            tx.send(msg);
+        }));
+    }
 
     setup_client_stdin_out().unwrap();
 
@@ -33,7 +129,7 @@ pub fn init_fake_linux_input(_argc: u32, _argv: *const &str) {
     let _stdout = std::io::stdout().into_raw_mode().unwrap();
 
     print!(
-        "{}{}q to exit.\n\r",
+        "{}{}w/s:throttle a/d:yaw h/l:roll k/j:pitch 1/2/3:mode switch r:arm q:exit\n\r",
         termion::clear::All,
         termion::cursor::Goto(1, 1)
     );
@@ -43,25 +139,27 @@ pub fn init_fake_linux_input(_argc: u32, _argv: *const &str) {
         if event == Key::Char('q') {
             break;
         }
-        for channel in &mut channels {
-            if event == Key::Char(channel.add_key) {
-                channel.value += 100;
-            } else if event == Key::Char(channel.sub_key) {
-                channel.value -= 100;
+        let msg = match event {
+            Key::Char(key) => {
+                let mut rc = rc.lock().unwrap();
+                if !rc.press(key) {
+                    continue;
+                }
+                rc.to_msg()
             }
-
-            channel.value = channel.value.clamp(0, 10000);
-        }
+            _ => continue,
+        };
 
         print!(
             "{}{}",
             termion::cursor::Goto(1, 2),
             termion::clear::AfterCursor
         );
-        for channel in &channels {
-            println!("{}:{}\r", channel.name, channel.value);
+        for (name, x) in ["Roll", "Pitch", "Throttle", "Yaw", "Mode", "Arm"].iter().zip(msg.channel_vals) {
+            println!("{}:{}\r", name, x);
         }
     }
+    running.store(false, Ordering::Relaxed);
     println!("finished!\r");
 }
 
@@ -69,3 +167,36 @@ pub fn init_fake_linux_input(_argc: u32, _argv: *const &str) {
 fn register() {
     rpos::module::Module::register("fake_linux_input", init_fake_linux_input);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_rc() {
+        let mut rc = VirtualRc::default();
+        let msg = rc.to_msg();
+        assert_eq!(msg.channel_vals[..6], [1500, 1500, 1000, 1500, 1000, 1000]);
+        assert_eq!((msg.source, msg.channel_count), (RcSource::Keyboard, 8));
+
+        for _ in 0..10 {
+            rc.press('w');
+        }
+        rc.press('l');
+        rc.press('l');
+        rc.press('j');
+        rc.press('2');
+        rc.press('r');
+        assert!(!rc.press('x'));
+        let msg = rc.to_msg();
+        assert_eq!(msg.channel_vals[..6], [1750, 1375, 1250, 1500, 1500, 2000]);
+
+        // held within the hold time, then centred, the throttle stays
+        rc.update(HOLD_TIME - 0.01);
+        assert_eq!(rc.to_msg().channel_vals[0], 1750);
+        for _ in 0..20 {
+            rc.update(0.02);
+        }
+        assert_eq!(rc.to_msg().channel_vals[..4], [1500, 1500, 1250, 1500]);
+    }
+}
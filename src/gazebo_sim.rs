@@ -1,30 +1,38 @@
 use crate::msg_define::*;
 use crate::basic::rotation::Rotation;
+use crate::param::CachedParams;
+use crate::sensor_calib::SensorCorrection;
 use core::slice;
 use gz::msgs::imu::IMU;
-use rpos::channel::Sender;
+use gz::msgs::fluid_pressure::FluidPressure;
+use gz::msgs::magnetometer::Magnetometer;
+use gz::msgs::pose_v::Pose_V;
 use rpos::ctor::ctor;
 use rpos::hrt::Timespec;
 use rpos::lock_step::lock_step_update_time;
 use rpos::module::Module;
-use rpos::msg::get_new_tx_of_message;
 use std::{cell::RefCell, sync::Arc, time::Duration};
 
 struct GazeboSim {
     gz_node: RefCell<gz::transport::Node>,
-    #[allow(unused)]
     pose_index: RefCell<i32>,
     gz_sub_info: GzSubInfo,
-    gyro_tx: Sender<Vector3>,
-    acc_tx: Sender<Vector3>,
-    attitude_tx: Sender<Vector4>,
+    last_imu_time: RefCell<u64>,
+    last_pose: RefCell<(u64, [f32; 3])>, // time, NED position
+    correction: RefCell<CachedParams<SensorCorrection>>,
+    gyro_tx: Publisher<SensorGyroMsg>,
+    acc_tx: Publisher<SensorAccelMsg>,
+    mag_tx: Publisher<SensorMagMsg>,
+    baro_tx: Publisher<SensorBaroMsg>,
+    attitude_tx: Publisher<Stamped<Vector4>>,
+    alt_gt_tx: Publisher<VehicleAltitudeMsg>,
+    local_pos_gt_tx: Publisher<LocalPositionMsg>,
 }
 
 #[derive(serde::Deserialize)]
 struct GzSubInfo {
     world_name: String,
 
-    #[allow(unused)]
     pose_obj_name: String,
 }
 
@@ -42,16 +50,41 @@ impl GazeboSim {
         let acc_data = s.linear_acceleration;
         let attitude_data = s.orientation;
         let rotation = Rotation::Yaw270;
-        self.gyro_tx.send(rotation.rotate_v(Vector3 {
+
+        let now = crate::basic::hrt_now_us();
+        let last = self.last_imu_time.replace(now);
+        let delta_dt = if last == 0 { 0 } else { now.saturating_sub(last) as u32 };
+        let dt = delta_dt as f32 / 1000_000.0;
+
+        let correction = *self.correction.borrow_mut().get();
+        let rate = correction.correct_gyro(rotation.rotate_v(Vector3 {
             x: gyro_data.x as f32,
             y: gyro_data.y as f32,
             z: gyro_data.z as f32,
         }));
-        self.acc_tx.send(rotation.rotate_v(Vector3 {
+        let acc = correction.correct_acc(rotation.rotate_v(Vector3 {
             x: acc_data.x as f32,
             y: acc_data.y as f32,
             z: acc_data.z as f32,
         }));
+        self.gyro_tx.send(SensorGyroMsg {
+            device_id: SENSOR_DEVICE_ID_SIM,
+            temperature: f32::NAN,
+            samples: 1,
+            rate,
+            delta_angle: Vector3 { x: rate.x * dt, y: rate.y * dt, z: rate.z * dt },
+            delta_dt,
+            ..Default::default()
+        });
+        self.acc_tx.send(SensorAccelMsg {
+            device_id: SENSOR_DEVICE_ID_SIM,
+            temperature: f32::NAN,
+            samples: 1,
+            acc,
+            delta_velocity: Vector3 { x: acc.x * dt, y: acc.y * dt, z: acc.z * dt },
+            delta_dt,
+            ..Default::default()
+        });
         let imu_q: quaternion_core::Quaternion<f32> = (
             attitude_data.w as f32,
             [
@@ -67,11 +100,81 @@ impl GazeboSim {
         rotate_q is the rotate quaternion from gazebo axis to world axis(our defination): x -> -y_old ,  y -> x_old. 
         */
 
-        self.attitude_tx.send(Vector4 {
+        self.attitude_tx.send(Stamped::new(Vector4 {
             w: imu_q.0,
             x: imu_q.1[0],
             y: imu_q.1[1],
             z: imu_q.1[2],
+        }));
+    }
+
+    fn update_mag(self: &Arc<Self>, s: Magnetometer) {
+        const TESLA_TO_GAUSS: f32 = 10000.0;
+        let field = s.field_tesla;
+        let mag = self.correction.borrow_mut().get().correct_mag(Rotation::Yaw270.rotate_v(Vector3 {
+            x: field.x as f32 * TESLA_TO_GAUSS,
+            y: field.y as f32 * TESLA_TO_GAUSS,
+            z: field.z as f32 * TESLA_TO_GAUSS,
+        }));
+        self.mag_tx.send(SensorMagMsg {
+            device_id: SENSOR_DEVICE_ID_SIM,
+            temperature: f32::NAN,
+            mag,
+            ..Default::default()
+        });
+    }
+
+    fn update_baro(self: &Arc<Self>, s: FluidPressure) {
+        self.baro_tx.send(SensorBaroMsg {
+            device_id: SENSOR_DEVICE_ID_SIM,
+            temperature: f32::NAN,
+            pressure: s.pressure as f32,
+            ..Default::default()
+        });
+    }
+
+    fn update_pose(self: &Arc<Self>, s: Pose_V) {
+        // cache the index of the vehicle in the pose list
+        let mut index = self.pose_index.borrow_mut();
+        let cached = usize::try_from(*index).ok().and_then(|i| s.pose.get(i));
+        let pose = match cached {
+            Some(pose) if pose.name == self.gz_sub_info.pose_obj_name => pose,
+            _ => match s.pose.iter().position(|x| x.name == self.gz_sub_info.pose_obj_name) {
+                Some(i) => {
+                    *index = i as i32;
+                    &s.pose[i]
+                }
+                None => return,
+            },
+        };
+
+        // the vehicle starts heading to gazebo x, which is our north. gazebo y is west, z is up.
+        let p = &pose.position;
+        let pos = [p.x as f32, -p.y as f32, -p.z as f32];
+        let now = crate::basic::hrt_now_us();
+        let (last_time, last_pos) = self.last_pose.replace((now, pos));
+        let vel = if last_time != 0 && now > last_time {
+            let dt = (now - last_time) as f32 / 1000_000.0;
+            [0, 1, 2].map(|i| (pos[i] - last_pos[i]) / dt)
+        } else {
+            [0.0; 3]
+        };
+        self.alt_gt_tx.send(VehicleAltitudeMsg {
+            alt: -pos[2],
+            vz: -vel[2],
+            baro_alt: -pos[2],
+            ..Default::default()
+        });
+        self.local_pos_gt_tx.send(LocalPositionMsg {
+            x: pos[0],
+            y: pos[1],
+            z: pos[2],
+            vx: vel[0],
+            vy: vel[1],
+            vz: vel[2],
+            xy_valid: true,
+            z_valid: true,
+            ..Default::default()
         });
     }
 
@@ -84,14 +187,25 @@ impl GazeboSim {
                 gz_node: RefCell::new(gz::transport::Node::new().unwrap()),
                 pose_index: RefCell::new(-1),
                 gz_sub_info: sub_info,
-                gyro_tx: get_new_tx_of_message("gyro").unwrap(),
-                acc_tx: get_new_tx_of_message("acc").unwrap(),
-                attitude_tx: get_new_tx_of_message("attitude").unwrap(),
+                last_imu_time: RefCell::new(0),
+                last_pose: RefCell::new((0, [0.0; 3])),
+                correction: RefCell::new(SensorCorrection::cached()),
+                gyro_tx: Publisher::new("gyro"),
+                acc_tx: Publisher::new("acc"),
+                mag_tx: Publisher::new("mag"),
+                baro_tx: Publisher::new("baro"),
+                attitude_tx: Publisher::new("attitude_groundtruth"),
+                alt_gt_tx: Publisher::new("vehicle_altitude_groundtruth"),
+                local_pos_gt_tx: Publisher::new("vehicle_local_position_groundtruth"),
             };
             a
         });
         assert!(sim.subscribe("/clock", Self::update_time));
         assert!(sim.subscribe("/imu", Self::update_imu));
+        assert!(sim.subscribe("/mag", Self::update_mag));
+        assert!(sim.subscribe("/air_pressure", Self::update_baro));
+        let pose_topic = format!("/world/{}/dynamic_pose/info", sim.gz_sub_info.world_name);
+        assert!(sim.subscribe(&pose_topic, Self::update_pose));
         sim
     }
 
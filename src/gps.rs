@@ -0,0 +1,443 @@
+use std::{fs::OpenOptions, io::Read, time::Duration};
+
+use clap::Parser;
+use rpos::{pthread_scheduler::SchedulePthread, thread_logln};
+
+use crate::msg_define::{GpsFixType, GpsMsg, Publisher};
+
+#[derive(Parser)]
+#[command(name = "gps", about = "gps driver, parse u-blox NAV-PVT and NMEA GGA/RMC, publish gps")]
+struct Cli {
+    #[arg(short, long, default_value_t = 115200)]
+    baudrate: u32,
+
+    dev_name: String,
+}
+
+const UBX_SYNC: [u8; 2] = [0xB5, 0x62];
+const UBX_CLASS_NAV: u8 = 0x01;
+const UBX_ID_NAV_PVT: u8 = 0x07;
+const UBX_NAV_PVT_LEN: usize = 92;
+const UBX_MAX_LEN: usize = 1024;
+const NMEA_MAX_LEN: usize = 100;
+// NMEA has no accuracy, estimate it from hdop with the typical user range error, unit:m
+const NMEA_UERE: f32 = 2.5;
+const KNOT_TO_MS: f32 = 0.514444;
+
+// days since 1970-01-01 of a civil date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn utc_to_unix_us(date: (i64, i64, i64), sec_of_day: f64) -> u64 {
+    let days = days_from_civil(date.0, date.1, date.2);
+    (days as f64 * 86400.0 * 1e6 + sec_of_day * 1e6) as u64
+}
+
+fn ubx_checksum(data: &[u8]) -> [u8; 2] {
+    let mut ck = [0u8; 2];
+    for b in data {
+        ck[0] = ck[0].wrapping_add(*b);
+        ck[1] = ck[1].wrapping_add(ck[0]);
+    }
+    ck
+}
+
+fn parse_nav_pvt(p: &[u8]) -> GpsMsg {
+    let u16_at = |i: usize| u16::from_le_bytes([p[i], p[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([p[i], p[i + 1], p[i + 2], p[i + 3]]);
+    let i32_at = |i: usize| u32_at(i) as i32;
+
+    let flags = p[21];
+    let gnss_fix_ok = flags & 0x01 != 0;
+    let diff_soln = flags & 0x02 != 0;
+    let fix_type = match (gnss_fix_ok, flags >> 6, p[20]) {
+        (false, _, _) => GpsFixType::NoFix,
+        (true, 1, _) => GpsFixType::RtkFloat,
+        (true, 2, _) => GpsFixType::RtkFixed,
+        (true, _, 2) => GpsFixType::Fix2D,
+        (true, _, 3 | 4) if diff_soln => GpsFixType::Dgps,
+        (true, _, 3 | 4) => GpsFixType::Fix3D,
+        _ => GpsFixType::NoFix,
+    };
+
+    // valid date and time
+    let time_utc_us = if p[11] & 0x03 == 0x03 {
+        let sec_of_day = p[8] as f64 * 3600.0 + p[9] as f64 * 60.0 + p[10] as f64 + i32_at(16) as f64 * 1e-9;
+        utc_to_unix_us((u16_at(4) as i64, p[6] as i64, p[7] as i64), sec_of_day)
+    } else {
+        0
+    };
+
+    GpsMsg {
+        time_utc_us,
+        fix_type,
+        lat: i32_at(28) as f64 * 1e-7,
+        lon: i32_at(24) as f64 * 1e-7,
+        alt: i32_at(36) as f32 / 1000.0,
+        vel_n: i32_at(48) as f32 / 1000.0,
+        vel_e: i32_at(52) as f32 / 1000.0,
+        vel_d: i32_at(56) as f32 / 1000.0,
+        h_acc: u32_at(40) as f32 / 1000.0,
+        v_acc: u32_at(44) as f32 / 1000.0,
+        s_acc: u32_at(68) as f32 / 1000.0,
+        satellites: p[23],
+        ..Default::default()
+    }
+}
+
+// ddmm.mmmm + hemisphere to degree
+fn parse_nmea_coord(value: &str, hemisphere: &str) -> Option<f64> {
+    let dot = value.find('.').unwrap_or(value.len());
+    if dot < 2 {
+        return None;
+    }
+    let deg = value[..dot - 2].parse::<f64>().ok()?;
+    let min = value[dot - 2..].parse::<f64>().ok()?;
+    let coord = deg + min / 60.0;
+    match hemisphere {
+        "N" | "E" => Some(coord),
+        "S" | "W" => Some(-coord),
+        _ => None,
+    }
+}
+
+// hhmmss.ss to seconds of the day
+fn parse_nmea_time(value: &str) -> Option<f64> {
+    if value.len() < 6 {
+        return None;
+    }
+    let h = value[0..2].parse::<f64>().ok()?;
+    let m = value[2..4].parse::<f64>().ok()?;
+    let s = value[4..].parse::<f64>().ok()?;
+    Some(h * 3600.0 + m * 60.0 + s)
+}
+
+// ddmmyy
+fn parse_nmea_date(value: &str) -> Option<(i64, i64, i64)> {
+    if value.len() != 6 {
+        return None;
+    }
+    let d = value[0..2].parse::<i64>().ok()?;
+    let m = value[2..4].parse::<i64>().ok()?;
+    let y = value[4..6].parse::<i64>().ok()?;
+    // two digit year, 80~99 are 19xx
+    Some((if y < 80 { 2000 + y } else { 1900 + y }, m, d))
+}
+
+// split a byte stream into UBX frames and NMEA sentences
+#[derive(Default)]
+pub struct GpsParser {
+    buf: Vec<u8>,
+    nmea: GpsMsg, // GGA and RMC are merged into one message
+    nmea_date: Option<(i64, i64, i64)>,
+    pub error_count: u32,
+}
+
+impl GpsParser {
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn next_msg(&mut self) -> Option<GpsMsg> {
+        loop {
+            // drop everything before a frame start
+            let start = self.buf.iter().position(|x| *x == UBX_SYNC[0] || *x == b'$');
+            match start {
+                Some(start) => {
+                    self.buf.drain(..start);
+                }
+                None => {
+                    self.buf.clear();
+                    return None;
+                }
+            }
+
+            let (consumed, msg) = if self.buf[0] == b'$' {
+                self.next_nmea()?
+            } else {
+                self.next_ubx()?
+            };
+            self.buf.drain(..consumed);
+            if msg.is_some() {
+                return msg;
+            }
+        }
+    }
+
+    // None if more bytes are needed, otherwise (consumed bytes, parsed message)
+    fn next_ubx(&mut self) -> Option<(usize, Option<GpsMsg>)> {
+        if self.buf.len() < 6 {
+            return None;
+        }
+        if self.buf[1] != UBX_SYNC[1] {
+            return Some((1, None));
+        }
+        let len = u16::from_le_bytes([self.buf[4], self.buf[5]]) as usize;
+        if len > UBX_MAX_LEN {
+            self.error_count += 1;
+            return Some((1, None));
+        }
+        if self.buf.len() < 8 + len {
+            return None;
+        }
+        let ck = ubx_checksum(&self.buf[2..6 + len]);
+        if ck != [self.buf[6 + len], self.buf[7 + len]] {
+            self.error_count += 1;
+            return Some((1, None));
+        }
+        let msg = if self.buf[2] == UBX_CLASS_NAV && self.buf[3] == UBX_ID_NAV_PVT && len == UBX_NAV_PVT_LEN {
+            Some(parse_nav_pvt(&self.buf[6..6 + len]))
+        } else {
+            None
+        };
+        Some((8 + len, msg))
+    }
+
+    fn next_nmea(&mut self) -> Option<(usize, Option<GpsMsg>)> {
+        let end = match self.buf.iter().take(NMEA_MAX_LEN).position(|x| *x == b'\n') {
+            Some(end) => end,
+            None if self.buf.len() >= NMEA_MAX_LEN => {
+                self.error_count += 1;
+                return Some((1, None));
+            }
+            None => return None,
+        };
+        let line = String::from_utf8_lossy(&self.buf[1..end]).trim_end().to_string();
+        let msg = match line.split_once('*') {
+            Some((body, cs)) if u8::from_str_radix(cs, 16).ok() == Some(body.bytes().fold(0, |a, b| a ^ b)) => {
+                self.parse_nmea(body)
+            }
+            _ => {
+                self.error_count += 1;
+                None
+            }
+        };
+        Some((end + 1, msg))
+    }
+
+    fn parse_nmea(&mut self, body: &str) -> Option<GpsMsg> {
+        let fields: Vec<&str> = body.split(',').collect();
+        if !body.is_ascii() || fields[0].len() != 5 {
+            return None;
+        }
+        match &fields[0][2..] {
+            "GGA" if fields.len() >= 10 => {
+                let quality = fields[6].parse::<u8>().unwrap_or(0);
+                self.nmea.fix_type = match quality {
+                    1 => GpsFixType::Fix3D,
+                    2 => GpsFixType::Dgps,
+                    4 => GpsFixType::RtkFixed,
+                    5 => GpsFixType::RtkFloat,
+                    _ => GpsFixType::NoFix,
+                };
+                self.nmea.satellites = fields[7].parse().unwrap_or(0);
+                let hdop = fields[8].parse::<f32>().unwrap_or(99.0);
+                self.nmea.h_acc = hdop * NMEA_UERE;
+                self.nmea.v_acc = hdop * NMEA_UERE * 1.5;
+                if let (Some(lat), Some(lon)) = (
+                    parse_nmea_coord(fields[2], fields[3]),
+                    parse_nmea_coord(fields[4], fields[5]),
+                ) {
+                    self.nmea.lat = lat;
+                    self.nmea.lon = lon;
+                }
+                self.nmea.alt = fields[9].parse().unwrap_or(self.nmea.alt);
+                self.nmea.time_utc_us = match (self.nmea_date, parse_nmea_time(fields[1])) {
+                    (Some(date), Some(t)) => utc_to_unix_us(date, t),
+                    _ => 0,
+                };
+                Some(self.nmea)
+            }
+            "RMC" if fields.len() >= 10 => {
+                self.nmea_date = parse_nmea_date(fields[9]);
+                if fields[2] == "A" {
+                    let speed = fields[7].parse::<f32>().unwrap_or(0.0) * KNOT_TO_MS;
+                    let course = fields[8].parse::<f32>().unwrap_or(0.0).to_radians();
+                    self.nmea.vel_n = speed * course.cos();
+                    self.nmea.vel_e = speed * course.sin();
+                    self.nmea.vel_d = 0.0;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Gps {
+    tx: Publisher<GpsMsg>,
+    dev: Box<dyn Read>,
+    parser: GpsParser,
+}
+
+impl Gps {
+    fn new(dev: Box<dyn Read>) -> Self {
+        Gps {
+            tx: Publisher::new("gps"),
+            dev,
+            parser: GpsParser::default(),
+        }
+    }
+
+    fn process(&mut self) {
+        let mut buf = [0; 1024];
+        let len = match self.dev.read(&mut buf) {
+            Ok(len) => len,
+            Err(_) => return,
+        };
+        self.parser.push_bytes(&buf[..len]);
+        while let Some(msg) = self.parser.next_msg() {
+            self.tx.send(GpsMsg {
+                error_count: self.parser.error_count,
+                ..msg
+            });
+        }
+    }
+}
+
+pub fn gps_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        let dev_name = &args.dev_name;
+        let dev: Box<dyn Read>;
+        if dev_name.contains("/dev/") {
+            let serial = serialport::new(dev_name, args.baudrate);
+            dev = match serial.timeout(Duration::from_millis(1000)).open() {
+                Ok(dev) => dev,
+                Err(e) => {
+                    thread_logln!("open {} failed: {}", dev_name, e);
+                    return;
+                }
+            };
+        } else {
+            dev = match OpenOptions::new().read(true).open(dev_name) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    thread_logln!("open {} failed: {}", dev_name, e);
+                    return;
+                }
+            };
+        }
+        let mut gps = Gps::new(dev);
+        SchedulePthread::new_simple(Box::new(move |s| loop {
+            gps.process();
+            s.schedule_until(20_000);
+        }));
+        thread_logln!("gps dev:{}", dev_name);
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    rpos::module::Module::register("gps", gps_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use rpos::msg::get_new_rx_of_message;
+
+    use super::*;
+
+    const GGA: &str = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+    const RMC: &str = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A\r\n";
+
+    fn nav_pvt_frame() -> Vec<u8> {
+        let mut p = [0u8; UBX_NAV_PVT_LEN];
+        p[4..6].copy_from_slice(&2024u16.to_le_bytes());
+        p[6..11].copy_from_slice(&[5, 6, 7, 8, 9]);
+        p[11] = 0x07; // valid date, time, fully resolved
+        p[20] = 3; // 3d fix
+        p[21] = 0x01; // gnss fix ok
+        p[23] = 17;
+        p[24..28].copy_from_slice(&1_163_333_333i32.to_le_bytes());
+        p[28..32].copy_from_slice(&(-337_000_000i32).to_le_bytes());
+        p[36..40].copy_from_slice(&123_456i32.to_le_bytes());
+        p[40..44].copy_from_slice(&1_200u32.to_le_bytes());
+        p[44..48].copy_from_slice(&2_500u32.to_le_bytes());
+        p[48..52].copy_from_slice(&1_500i32.to_le_bytes());
+        p[52..56].copy_from_slice(&(-2_000i32).to_le_bytes());
+        p[56..60].copy_from_slice(&300i32.to_le_bytes());
+        p[68..72].copy_from_slice(&150u32.to_le_bytes());
+
+        let mut frame = vec![UBX_SYNC[0], UBX_SYNC[1], UBX_CLASS_NAV, UBX_ID_NAV_PVT];
+        frame.extend_from_slice(&(UBX_NAV_PVT_LEN as u16).to_le_bytes());
+        frame.extend_from_slice(&p);
+        let ck = ubx_checksum(&frame[2..]);
+        frame.extend_from_slice(&ck);
+        frame
+    }
+
+    #[test]
+    fn test_ubx_nav_pvt() {
+        let frame = nav_pvt_frame();
+        let mut corrupted = frame.clone();
+        corrupted[30] ^= 0xff;
+
+        let mut parser = GpsParser::default();
+        // garbage, a corrupted frame, then the frame split into two pieces
+        parser.push_bytes(&[0x00, 0xB5, 0x13, 0x42]);
+        parser.push_bytes(&corrupted);
+        parser.push_bytes(&frame[..40]);
+        assert!(parser.next_msg().is_none());
+        parser.push_bytes(&frame[40..]);
+        let msg = parser.next_msg().unwrap();
+        assert!(parser.next_msg().is_none());
+        assert_eq!(parser.error_count, 1);
+
+        assert_eq!(msg.fix_type, GpsFixType::Fix3D);
+        assert_eq!(msg.satellites, 17);
+        assert!((msg.lat + 33.7).abs() < 1e-7);
+        assert!((msg.lon - 116.3333333).abs() < 1e-7);
+        assert!((msg.alt - 123.456).abs() < 1e-3);
+        assert_eq!((msg.vel_n, msg.vel_e, msg.vel_d), (1.5, -2.0, 0.3));
+        assert_eq!((msg.h_acc, msg.v_acc, msg.s_acc), (1.2, 2.5, 0.15));
+        assert_eq!(msg.time_utc_us, 1_714_979_289_000_000);
+    }
+
+    #[test]
+    fn test_nmea() {
+        let mut parser = GpsParser::default();
+        parser.push_bytes(RMC.as_bytes());
+        parser.push_bytes(b"$GPGSV,3,1,11,03,03,111,00,04,15,270,00*00\r\n"); // bad checksum
+        parser.push_bytes(GGA.as_bytes());
+        let msg = parser.next_msg().unwrap();
+        assert_eq!(parser.error_count, 1);
+
+        assert_eq!(msg.fix_type, GpsFixType::Fix3D);
+        assert_eq!(msg.satellites, 8);
+        assert!((msg.lat - 48.1173).abs() < 1e-6);
+        assert!((msg.lon - 11.516667).abs() < 1e-6);
+        assert!((msg.alt - 545.4).abs() < 1e-3);
+        assert!((msg.h_acc - 0.9 * NMEA_UERE).abs() < 1e-5);
+        let speed = (msg.vel_n * msg.vel_n + msg.vel_e * msg.vel_e).sqrt();
+        assert!((speed - 22.4 * KNOT_TO_MS).abs() < 1e-3);
+        assert!((msg.vel_e.atan2(msg.vel_n).to_degrees() - 84.4).abs() < 1e-3);
+        assert_eq!(msg.time_utc_us, 764_426_119_000_000);
+    }
+
+    #[test]
+    fn test_gps_stream_file() {
+        let path = std::env::temp_dir().join(format!("gps_test_{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(GGA.as_bytes()).unwrap();
+        file.write_all(&[0x55, 0xB5, 0xAA]).unwrap();
+        file.write_all(&nav_pvt_frame()).unwrap();
+        drop(file);
+
+        let mut rx = get_new_rx_of_message::<GpsMsg>("gps").unwrap();
+        let mut gps = Gps::new(Box::new(std::fs::File::open(&path).unwrap()));
+        gps.process();
+        // the latest message is the ubx one
+        let msg = rx.read();
+        assert_eq!(msg.satellites, 17);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use clap::Parser;
+use rpos::thread_logln;
+use serialport::{Parity, StopBits};
+
+use crate::{
+    msg_define::{MsgHeader, RcInputMsg, RcSource, RC_INPUT_MAX_CHANNELS},
+    rc_input::{spawn_reader, ParserStats, RcProtocol},
+};
+
+/*
+    flysky ibus servo output, 115200 baud 8N1. there is no failsafe flag in the frame,
+    the receiver stops sending when the link is lost(failsafe not set), RcReader detects the timeout.
+*/
+#[derive(Parser)]
+#[command(name = "ibus", about = None, long_about = None)]
+struct Cli {
+    #[arg(short, long, default_value_t = 115200)]
+    baudrate: u32,
+
+    dev_name: String,
+}
+
+// [0x20, 0x40, 14 channels(u16 le, us), checksum(u16 le)]
+const FRAME_LEN: usize = 32;
+const HEADER: [u8; 2] = [0x20, 0x40];
+const CHANNELS: usize = 14;
+
+fn checksum(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFFu16, |sum, x| sum.wrapping_sub(*x as u16))
+}
+
+#[derive(Default)]
+pub struct Ibus {
+    buf: Vec<u8>,
+    stats: ParserStats,
+}
+
+impl RcProtocol for Ibus {
+    fn source(&self) -> RcSource {
+        RcSource::Ibus
+    }
+
+    fn stats(&self) -> ParserStats {
+        self.stats
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn next_frame(&mut self) -> Option<RcInputMsg> {
+        loop {
+            let start = self.buf.windows(2).position(|x| x == HEADER).unwrap_or(self.buf.len().saturating_sub(1));
+            self.buf.drain(..start);
+            self.stats.discarded_bytes += start as u32;
+            if self.buf.len() < FRAME_LEN {
+                return None;
+            }
+            let frame = &self.buf[..FRAME_LEN];
+            if checksum(&frame[..FRAME_LEN - 2]) != u16::from_le_bytes([frame[30], frame[31]]) {
+                self.stats.crc_errors += 1;
+                self.stats.discarded_bytes += 1;
+                self.buf.drain(..1);
+                continue;
+            }
+            let mut channel_vals = [1500; RC_INPUT_MAX_CHANNELS];
+            for (i, x) in frame[2..2 + CHANNELS * 2].chunks(2).enumerate() {
+                // the high nibble is used by some receivers for the channels after 14
+                channel_vals[i] = (u16::from_le_bytes([x[0], x[1]]) & 0x0FFF) as i16;
+            }
+            self.buf.drain(..FRAME_LEN);
+            return Some(RcInputMsg {
+                header: MsgHeader::default(),
+                source: RcSource::Ibus,
+                channel_count: CHANNELS as u8,
+                failsafe: false,
+                frame_lost: false,
+                channel_vals,
+            });
+        }
+    }
+}
+
+pub fn ibus_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        spawn_reader(&args.dev_name, args.baudrate, Parity::None, StopBits::One, Ibus::default());
+        thread_logln!("ibus dev:{}", args.dev_name);
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    rpos::module::Module::register("ibus", ibus_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ibus_frame(channels: &[u16; CHANNELS]) -> [u8; FRAME_LEN] {
+        let mut frame = [0; FRAME_LEN];
+        frame[..2].copy_from_slice(&HEADER);
+        for (i, x) in channels.iter().enumerate() {
+            frame[2 + i * 2..4 + i * 2].copy_from_slice(&x.to_le_bytes());
+        }
+        let sum = checksum(&frame[..FRAME_LEN - 2]);
+        frame[30..].copy_from_slice(&sum.to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn ibus_stream_test() {
+        let channels: [u16; CHANNELS] = std::array::from_fn(|i| 1000 + i as u16 * 50);
+        let mut stream = vec![0x40, 0x20];
+        stream.extend(ibus_frame(&channels));
+        // a lost byte, the checksum fails
+        let mut broken = ibus_frame(&[1500; CHANNELS]).to_vec();
+        broken.remove(10);
+        stream.extend(broken);
+        stream.extend(ibus_frame(&[2000; CHANNELS]));
+
+        let mut ibus = Ibus::default();
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(5) {
+            ibus.push_bytes(chunk);
+            while let Some(msg) = ibus.next_frame() {
+                frames.push(msg);
+            }
+        }
+        assert_eq!(frames.len(), 2);
+        assert_eq!(ibus.stats().crc_errors, 1);
+        assert!(ibus.stats().discarded_bytes >= 2 + 31);
+        assert_eq!(frames[0].channel_vals[..CHANNELS], channels.map(|x| x as i16));
+        assert_eq!((frames[0].source, frames[0].channel_count, frames[0].failsafe), (RcSource::Ibus, 14, false));
+        assert_eq!(frames[1].channel_vals[13], 2000);
+    }
+}
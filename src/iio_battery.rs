@@ -0,0 +1,205 @@
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use rpos::{libc::c_long, pthread_scheduler::SchedulePthread, thread_logln};
+
+use crate::{
+    msg_define::{BatteryStatusMsg, MsgHeader, Publisher},
+    param::{self, ParameterData},
+};
+
+/*
+    battery monitor on an adc of the linux iio subsystem, publish battery_status.
+    in_voltage{N}_raw * in_voltage{N}_scale(mV per lsb) is the voltage on the adc pin,
+    bat_v_div is the ratio of the voltage divider, bat_a_per_v converts the current sensor output.
+*/
+
+#[derive(Parser, Clone)]
+#[command(name = "iio_battery", about = "linux iio adc battery monitor, publish battery_status")]
+struct Cli {
+    #[arg(short, long, default_value_t = 100_000, help = "publish period, us")]
+    period: u32,
+
+    #[arg(short, long, default_value_t = 0, help = "adc channel of the voltage")]
+    voltage: u32,
+
+    #[arg(short, long, help = "adc channel of the current, not measured if absent")]
+    current: Option<u32>,
+
+    #[arg(help = "iio device dir, eg. /sys/bus/iio/devices/iio:device0")]
+    dev_dir: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BatteryParams {
+    v_div: f32,
+    a_per_v: f32,
+    cells: i32,
+    v_empty: f32, // per cell
+    v_full: f32,
+}
+
+impl BatteryParams {
+    fn from_params() -> Option<Self> {
+        Some(BatteryParams {
+            v_div: param::get_param("bat_v_div")?.as_f32(),
+            a_per_v: param::get_param("bat_a_per_v")?.as_f32(),
+            cells: param::get_param("bat_cells")?.as_i32(),
+            v_empty: param::get_param("bat_v_empty")?.as_f32(),
+            v_full: param::get_param("bat_v_full")?.as_f32(),
+        })
+    }
+
+    // linear in the cell voltage, negative if the cell count is unknown
+    fn remaining(&self, voltage: f32) -> f32 {
+        if self.cells <= 0 || self.v_full <= self.v_empty {
+            return -1.0;
+        }
+        ((voltage / self.cells as f32 - self.v_empty) / (self.v_full - self.v_empty)).clamp(0.0, 1.0)
+    }
+}
+
+// an adc channel, the raw file and the scale(V per lsb)
+struct AdcChannel {
+    raw: PathBuf,
+    scale: f32,
+}
+
+fn read_value(path: &Path) -> io::Result<f32> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse::<f32>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl AdcChannel {
+    fn open(dir: &Path, index: u32) -> io::Result<Self> {
+        // a scale of the channel, or a shared one
+        let scale = read_value(&dir.join(format!("in_voltage{}_scale", index)))
+            .or_else(|_| read_value(&dir.join("in_voltage_scale")))?;
+        Ok(AdcChannel { raw: dir.join(format!("in_voltage{}_raw", index)), scale: scale / 1000.0 })
+    }
+
+    fn read(&self) -> io::Result<f32> {
+        Ok(read_value(&self.raw)? * self.scale)
+    }
+}
+
+struct IioBattery {
+    voltage: AdcChannel,
+    current: Option<AdcChannel>,
+    params: BatteryParams,
+    used_mah: f32,
+}
+
+impl IioBattery {
+    // dt: the time since the last call, unit:s
+    fn collect(&mut self, dt: f32) -> Option<BatteryStatusMsg> {
+        let voltage = self.voltage.read().ok()? * self.params.v_div;
+        let current = match &self.current {
+            Some(x) => x.read().ok()? * self.params.a_per_v,
+            None => f32::NAN,
+        };
+        if current.is_finite() {
+            self.used_mah += current * dt * 1000.0 / 3600.0;
+        }
+        Some(BatteryStatusMsg {
+            header: MsgHeader::default(),
+            voltage,
+            current,
+            used_mah: if self.current.is_some() { self.used_mah } else { f32::NAN },
+            remaining: self.params.remaining(voltage),
+        })
+    }
+}
+
+pub fn iio_battery_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        let dir = Path::new(&args.dev_dir);
+        let channels = AdcChannel::open(dir, args.voltage)
+            .and_then(|v| Ok((v, args.current.map(|i| AdcChannel::open(dir, i)).transpose()?)));
+        let (voltage, current) = match channels {
+            Ok(x) => x,
+            Err(e) => {
+                thread_logln!("open {} failed: {}", args.dev_dir, e);
+                return;
+            }
+        };
+        let Some(params) = BatteryParams::from_params() else {
+            thread_logln!("iio_battery: failed to read the battery params");
+            return;
+        };
+        let mut battery = IioBattery { voltage, current, params, used_mah: 0.0 };
+
+        let tx = Publisher::<BatteryStatusMsg>::new("battery_status");
+        let period = args.period as c_long;
+        let dt = args.period as f32 / 1e6;
+        SchedulePthread::new_simple(Box::new(move |s| loop {
+            if let Some(msg) = battery.collect(dt) {
+                tx.send(msg);
+            }
+            s.schedule_until(period);
+        }));
+        thread_logln!("iio battery on {} started!", args.dev_dir);
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    param::add_param("bat_v_div", ParameterData::Float(11.0));
+    param::add_param("bat_a_per_v", ParameterData::Float(36.0));
+    param::add_param("bat_cells", ParameterData::Int(0));
+    param::add_param("bat_v_empty", ParameterData::Float(3.5));
+    param::add_param("bat_v_full", ParameterData::Float(4.2));
+    rpos::module::Module::register("iio_battery", iio_battery_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iio_battery() {
+        let dir = std::env::temp_dir().join(format!("iio_battery_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, content) in [
+            ("in_voltage_scale", "0.5\n"),
+            ("in_voltage0_raw", "3000\n"),
+            ("in_voltage1_scale", "1.0\n"),
+            ("in_voltage1_raw", "500\n"),
+        ] {
+            fs::write(dir.join(file), content).unwrap();
+        }
+        let params = BatteryParams { v_div: 11.0, a_per_v: 36.0, cells: 4, v_empty: 3.5, v_full: 4.2 };
+        let mut battery = IioBattery {
+            voltage: AdcChannel::open(&dir, 0).unwrap(),
+            current: Some(AdcChannel::open(&dir, 1).unwrap()),
+            params,
+            used_mah: 0.0,
+        };
+        // 1.5V * 11, 0.5V * 36
+        let msg = battery.collect(1.0).unwrap();
+        assert!((msg.voltage - 16.5).abs() < 1e-4);
+        assert!((msg.current - 18.0).abs() < 1e-4);
+        assert!((msg.used_mah - 5.0).abs() < 1e-4);
+        assert!((msg.remaining - (16.5 / 4.0 - 3.5) / 0.7).abs() < 1e-4);
+        assert!((battery.collect(1.0).unwrap().used_mah - 10.0).abs() < 1e-4);
+
+        // no current sensor, unknown cell count
+        battery.current = None;
+        battery.params.cells = 0;
+        let msg = battery.collect(1.0).unwrap();
+        assert!(msg.current.is_nan() && msg.used_mah.is_nan());
+        assert!(msg.remaining < 0.0);
+
+        fs::remove_file(dir.join("in_voltage0_raw")).unwrap();
+        assert!(battery.collect(1.0).is_none());
+        assert!(AdcChannel::open(&dir, 2).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(AdcChannel::open(&dir, 0).is_err());
+    }
+}
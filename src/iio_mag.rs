@@ -0,0 +1,217 @@
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use rpos::{libc::c_long, pthread_scheduler::SchedulePthread, thread_logln};
+
+use crate::{
+    basic::rotation::Rotation,
+    msg_define::{Publisher, SensorMagMsg, Vector3},
+    param::CachedParams,
+    sensor_calib::SensorCorrection,
+};
+
+/*
+    magnetometer driver on top of the linux iio subsystem, the i2c chip(hmc5883l, qmc5883l, ist8310, ak8975...)
+    is handled by the kernel driver, we read the sysfs files:
+        in_magn_{x,y,z}_raw, in_magn_scale or in_magn_{x,y,z}_scale(gauss per lsb), in_temp_input(optional)
+*/
+
+#[derive(Parser, Clone)]
+#[command(name = "iio_mag", about = "linux iio magnetometer driver, publish mag")]
+struct Cli {
+    #[arg(short, long, default_value_t = 20_000, help = "publish period, us")]
+    period: u32,
+
+    #[arg(help = "iio device dir, eg. /sys/bus/iio/devices/iio:device0")]
+    dev_dir: String,
+}
+
+const AXES: [&str; 3] = ["x", "y", "z"];
+
+struct IioMag {
+    dir: PathBuf,
+    scale: [f32; 3],
+    device_id: u32,
+    error_count: u32,
+    rotation: Rotation,
+    correction: CachedParams<SensorCorrection>,
+}
+
+fn read_value(path: &Path) -> io::Result<f32> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse::<f32>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl IioMag {
+    fn open(dir: &Path, device_id: u32) -> io::Result<Self> {
+        // a shared scale, or a scale for each axis
+        let scale = match read_value(&dir.join("in_magn_scale")) {
+            Ok(scale) => [scale; 3],
+            Err(_) => {
+                let mut scale = [0.0; 3];
+                for i in 0..3 {
+                    scale[i] = read_value(&dir.join(format!("in_magn_{}_scale", AXES[i])))?;
+                }
+                scale
+            }
+        };
+        Ok(IioMag {
+            dir: dir.to_path_buf(),
+            scale,
+            device_id,
+            error_count: 0,
+            rotation: Rotation::None,
+            correction: SensorCorrection::cached(),
+        })
+    }
+
+    fn read_raw(&self) -> io::Result<[f32; 3]> {
+        let mut raw = [0.0; 3];
+        for i in 0..3 {
+            raw[i] = read_value(&self.dir.join(format!("in_magn_{}_raw", AXES[i])))?;
+        }
+        Ok(raw)
+    }
+
+    fn collect(&mut self) -> Option<SensorMagMsg> {
+        let raw = match self.read_raw() {
+            Ok(raw) => raw,
+            Err(_) => {
+                self.error_count += 1;
+                return None;
+            }
+        };
+        // in_temp_input is milli degC
+        let temperature = read_value(&self.dir.join("in_temp_input")).map_or(f32::NAN, |x| x / 1000.0);
+        let mag = self.correction.get().correct_mag(self.rotation.rotate_v(Vector3 {
+            x: raw[0] * self.scale[0],
+            y: raw[1] * self.scale[1],
+            z: raw[2] * self.scale[2],
+        }));
+        Some(SensorMagMsg {
+            device_id: self.device_id,
+            temperature,
+            error_count: self.error_count,
+            mag,
+            ..Default::default()
+        })
+    }
+}
+
+// device id: [iio bus:8][iio device index:8]
+fn get_device_id(dev_dir: &str) -> u32 {
+    const IIO_BUS_ID: u32 = 2;
+    let index = dev_dir
+        .trim_end_matches('/')
+        .rsplit("iio:device")
+        .next()
+        .and_then(|x| x.parse::<u32>().ok())
+        .unwrap_or(0);
+    IIO_BUS_ID << 8 | index
+}
+
+pub fn iio_mag_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        let mut mag = match IioMag::open(Path::new(&args.dev_dir), get_device_id(&args.dev_dir)) {
+            Ok(mag) => mag,
+            Err(e) => {
+                thread_logln!("open {} failed: {}", args.dev_dir, e);
+                return;
+            }
+        };
+        mag.rotation = Rotation::from_params("mag_rot");
+
+        let mag_tx = Publisher::<SensorMagMsg>::new("mag");
+        let period = args.period as c_long;
+        SchedulePthread::new_fifo(
+            1024 * 1024,
+            90,
+            Box::new(move |s| loop {
+                if let Some(msg) = mag.collect() {
+                    mag_tx.send(msg);
+                }
+                s.schedule_until(period);
+            }),
+        );
+        thread_logln!("iio mag on {} started!", args.dev_dir);
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    Rotation::add_params("mag_rot", Rotation::None);
+    rpos::module::Module::register("iio_mag", iio_mag_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_dev(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iio_mag_test_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_iio_mag_read() {
+        let dir = make_dev(
+            "shared",
+            &[
+                ("in_magn_scale", "0.001\n"),
+                ("in_magn_x_raw", "100\n"),
+                ("in_magn_y_raw", "-250\n"),
+                ("in_magn_z_raw", "400\n"),
+                ("in_temp_input", "25500\n"),
+            ],
+        );
+        let mut mag = IioMag::open(&dir, 0).unwrap();
+        mag.rotation = Rotation::Yaw270;
+        let msg = mag.collect().unwrap();
+        // Yaw270: [x,y,z] -> [-y,x,z]
+        assert!((msg.mag.x - 0.25).abs() < 1e-6);
+        assert!((msg.mag.y - 0.1).abs() < 1e-6);
+        assert!((msg.mag.z - 0.4).abs() < 1e-6);
+        assert!((msg.temperature - 25.5).abs() < 1e-6);
+
+        // the raw file disappears(device removed)
+        fs::remove_file(dir.join("in_magn_x_raw")).unwrap();
+        assert!(mag.collect().is_none());
+        assert_eq!(mag.error_count, 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_iio_mag_axis_scale() {
+        let dir = make_dev(
+            "axis",
+            &[
+                ("in_magn_x_scale", "0.002"),
+                ("in_magn_y_scale", "0.003"),
+                ("in_magn_z_scale", "0.004"),
+                ("in_magn_x_raw", "10"),
+                ("in_magn_y_raw", "10"),
+                ("in_magn_z_raw", "10"),
+            ],
+        );
+        let mut mag = IioMag::open(&dir, 0).unwrap();
+        let msg = mag.collect().unwrap();
+        assert!((msg.mag.x - 0.02).abs() < 1e-6);
+        assert!((msg.mag.y - 0.03).abs() < 1e-6);
+        assert!((msg.mag.z - 0.04).abs() < 1e-6);
+        assert!(msg.temperature.is_nan());
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(IioMag::open(Path::new("/nonexistent/iio:device9"), 0).is_err());
+        assert_eq!(get_device_id("/sys/bus/iio/devices/iio:device3/"), 2 << 8 | 3);
+    }
+}
@@ -1,31 +1,69 @@
 use std::{ffi::c_void, ptr::null_mut, sync::Arc};
 
 use rpos::{
-    channel::Receiver, msg::get_new_rx_of_message, pthread_scheduler::SchedulePthread
+    msg::get_new_rx_of_message, pthread_scheduler::SchedulePthread
 };
 
-use crate::basic::rotation::get_euler_degree;
+use crate::basic::rotation::{get_euler_degree, mag_yaw_error};
 use crate::utils::udp_scope::UdpScope;
 
 use quaternion_core::{normalize, Quaternion as Q};
 
-use crate::msg_define::{Vector4, Vector3};
+use crate::msg_define::{Publisher, SensorAccelMsg, SensorGyroMsg, SensorMagMsg, Stamped, Vector4};
+use crate::param::{self, ParameterData};
 
 use rpos::libc::c_long;
 
+const GRAVITY: f32 = 9.80665;
+
 struct IMUUpdate {
     q: Q<f32>,
     imu_update_ki: f32,
     imu_update_kp: f32,
+    acc_tol: f32, // the correction is faded out when |acc| deviates from 1g by this ratio
+    err_i_max: f32, // rad/s
+    mag_kp: f32,
+    mag_decl: f32, // rad
     err_i:[f32;3]
     // scope:UdpScope // for debug
 }
 
 impl IMUUpdate {
+    fn new() -> Self {
+        IMUUpdate {
+            q: (1.0, [0.0; 3]),
+            imu_update_ki: 0.2,
+            imu_update_kp: 2.0,
+            acc_tol: 0.1,
+            err_i_max: 0.1,
+            mag_kp: 0.5,
+            mag_decl: 0.0,
+            err_i: [0.0; 3],
+        }
+    }
+
+    fn load_params(&mut self) {
+        // a param being written is skipped, the current value is kept until the next load
+        let get = |name: &str, cur: f32| param::get_param(name).map_or(cur, |x| x.as_f32());
+        self.imu_update_kp = get("cf_kp", self.imu_update_kp);
+        self.imu_update_ki = get("cf_ki", self.imu_update_ki);
+        self.acc_tol = get("cf_acc_tol", self.acc_tol);
+        self.err_i_max = get("cf_i_max", self.err_i_max);
+        self.mag_kp = get("cf_mag_kp", self.mag_kp);
+        self.mag_decl = get("mag_decl", self.mag_decl.to_degrees()).to_radians();
+    }
+
+    // weight of the accelerometer correction, 1 when |acc| == 1g, 0 when it deviates more than acc_tol.
+    fn acc_weight(&self, acc: [f32; 3]) -> f32 {
+        let deviation = (quaternion_core::norm(acc) / GRAVITY - 1.0).abs();
+        (1.0 - deviation / self.acc_tol).clamp(0.0, 1.0)
+    }
+
     fn update(&mut self, acc: [f32; 3], mut gyro_data: [f32; 3], dt: f32) {
         let imu_update_half_t = dt / 2.0;
 
         let acc_normed = quaternion_core::normalize(acc);
+        let weight = self.acc_weight(acc);
 
         let acc_rotate = quaternion_core::frame_rotation(self.q, [0.0, 0.0, 1.0]);
         let err = quaternion_core::cross(acc_normed, acc_rotate); // use the product of cross as the err
@@ -33,9 +71,10 @@ impl IMUUpdate {
         //self.scope.send_wave(&[gyro_data[0],gyro_data[1],gyro_data[2],gyro_data[2]]);
         for (index, err_item) in err.iter().enumerate() {
             if err_item.is_normal() {
-                self.err_i[index] += (*err_item) * dt * self.imu_update_ki;
+                self.err_i[index] += (*err_item) * dt * self.imu_update_ki * weight;
+                self.err_i[index] = self.err_i[index].clamp(-self.err_i_max, self.err_i_max);
 
-                gyro_data[index] +=  (*err_item) * self.imu_update_kp + self.err_i[index];
+                gyro_data[index] +=  (*err_item) * self.imu_update_kp * weight + self.err_i[index];
             }
         }
 
@@ -52,53 +91,79 @@ impl IMUUpdate {
 
         *q = normalize(*q);
     }
+
+    // the magnetometer only corrects the heading: the estimate is rotated around the world z axis.
+    fn update_mag(&mut self, mag: [f32; 3], dt: f32) {
+        if let Some(err) = mag_yaw_error(self.q, mag, self.mag_decl) {
+            let dq = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], err * self.mag_kp * dt);
+            self.q = normalize(quaternion_core::mul(dq, self.q));
+        }
+    }
 }
 
 fn imu_update_main(ptr: *mut c_void) -> *mut c_void {
     let sp = unsafe { Arc::from_raw(ptr as *const SchedulePthread) };
-    let mut gyro_rx = get_new_rx_of_message::<Vector3>("gyro").unwrap();
-    let mut acc_rx = get_new_rx_of_message::<Vector3>("acc").unwrap();
-    let mut imu_update = IMUUpdate {
-        q: (1.0, [0.0; 3]),
-        imu_update_ki: 0.2,
-        imu_update_kp: 2.0,
-        err_i:[0.0;3]
-    };
+    let mut gyro_rx = get_new_rx_of_message::<SensorGyroMsg>("gyro").unwrap();
+    let mut acc_rx = get_new_rx_of_message::<SensorAccelMsg>("acc").unwrap();
+    let mut mag_rx = get_new_rx_of_message::<SensorMagMsg>("mag").unwrap();
+    let mut imu_update = IMUUpdate::new();
+    imu_update.load_params();
+    let mut cnt: u32 = 0;
 
-    // let q_tx: Sender<Vector4> = get_new_tx_of_message("attitude").unwrap();
-    let mut q_rx_debug: Receiver<Vector4> = get_new_rx_of_message("attitude").unwrap();
+    let q_tx = Publisher::<Stamped<Vector4>>::new("attitude");
 
-    const IMU_UPDATE_T: f32 = 0.010;
-    const IMU_UPDATE_T_US: c_long = (IMU_UPDATE_T * 1000.0 * 1000.0) as c_long;
+    const IMU_UPDATE_PERIOD_US: c_long = 2000;
+    // samples older than this are treated as a gap, don't integrate across it.
+    const IMU_MAX_DT_US: u64 = 100_000;
 
     let mut acc_data: [f32; 3] = [0.0; 3];
-    let mut gyro_data: [f32; 3] = [0.0; 3];
+    let mut last_gyro_timestamp: u64 = 0;
+    let mut last_mag_timestamp: u64 = 0;
 
     loop {
+        if let Some(mag_msg) = mag_rx.try_read() {
+            let timestamp = mag_msg.header.timestamp;
+            if last_mag_timestamp != 0 && timestamp > last_mag_timestamp && timestamp - last_mag_timestamp < IMU_MAX_DT_US * 5 {
+                let dt = (timestamp - last_mag_timestamp) as f32 / 1000_000.0;
+                imu_update.update_mag([mag_msg.mag.x, mag_msg.mag.y, mag_msg.mag.z], dt);
+            }
+            last_mag_timestamp = timestamp;
+        }
         if let Some(acc_msg) = acc_rx.try_read() {
-            acc_data = [acc_msg.x, acc_msg.y, acc_msg.z];
+            acc_data = [acc_msg.acc.x, acc_msg.acc.y, acc_msg.acc.z];
         }
         if let Some(gyro_msg) = gyro_rx.try_read() {
-            gyro_data = [gyro_msg.x, gyro_msg.y, gyro_msg.z];
-        }
-        imu_update.update(acc_data, gyro_data, IMU_UPDATE_T);
-        let mut x = Vector4 {
-            w: 0.0,
-            x: 0.0,
-            y: 0.0,
-            z: 0.0,
-        };
-        if let Some(msg) = q_rx_debug.try_read() {
-            x = msg;
+            let gyro_data = [gyro_msg.rate.x, gyro_msg.rate.y, gyro_msg.rate.z];
+            let timestamp = gyro_msg.header.timestamp;
+            if last_gyro_timestamp != 0
+                && timestamp > last_gyro_timestamp
+                && timestamp - last_gyro_timestamp < IMU_MAX_DT_US
+            {
+                let dt = (timestamp - last_gyro_timestamp) as f32 / 1000_000.0;
+                cnt += 1;
+                if cnt % 100 == 0 {
+                    imu_update.load_params();
+                }
+                imu_update.update(acc_data, gyro_data, dt);
+                // est_type 0: complementary filter, 1: ekf_att
+                if param::get_param("est_type").map_or(0, |x| x.as_i32()) == 0 {
+                    let q = imu_update.q;
+                    q_tx.send(Stamped::new(Vector4 {
+                        w: q.0,
+                        x: q.1[0],
+                        y: q.1[1],
+                        z: q.1[2],
+                    }));
+                }
+            }
+            last_gyro_timestamp = timestamp;
         }
 
-        let gazebo_q = (x.w,[x.x,x.y,x.z]);
+        // compare with "attitude_groundtruth" in simulation:
         // let euler_cal = get_euler_degree(imu_update.q);
-        // let euler_gz = get_euler_degree(gazebo_q);
-
         //imu_update.scope.send_wave(&[euler_cal[0],euler_cal[1],euler_gz[0],euler_gz[1]]);
 
-        sp.schedule_until(IMU_UPDATE_T_US);
+        sp.schedule_until(IMU_UPDATE_PERIOD_US);
     }
     #[allow(unreachable_code)]
     null_mut()
@@ -110,6 +175,15 @@ pub fn init_imu_update(_argc: u32, _argv: *const &str) {
 
 #[rpos::ctor::ctor]
 fn register() {
+    let default = IMUUpdate::new();
+    param::add_param("cf_kp", ParameterData::Float(default.imu_update_kp));
+    param::add_param("cf_ki", ParameterData::Float(default.imu_update_ki));
+    param::add_param("cf_acc_tol", ParameterData::Float(default.acc_tol));
+    param::add_param("cf_i_max", ParameterData::Float(default.err_i_max));
+    param::add_param("cf_mag_kp", ParameterData::Float(default.mag_kp));
+    // magnetic declination, degree, positive to the east
+    param::add_param("mag_decl", ParameterData::Float(0.0));
+    param::add_param("est_type", ParameterData::Int(0));
     rpos::module::Module::register("imu_update", init_imu_update);
 }
 
@@ -117,16 +191,22 @@ fn register() {
 mod tests {
     use std::f32::consts::PI;
 
-    use super::IMUUpdate;
-    use quaternion_core::RotationSequence::*;
+    use super::{IMUUpdate, GRAVITY};
+    use quaternion_core::{frame_rotation, point_rotation, RotationSequence::*};
+
+    // angle between the estimated and the real z axis of body, unit:degree
+    fn tilt_err_deg(q: quaternion_core::Quaternion<f32>, q_true: quaternion_core::Quaternion<f32>) -> f32 {
+        let z = point_rotation(q, [0.0, 0.0, 1.0]);
+        let z_true = point_rotation(q_true, [0.0, 0.0, 1.0]);
+        quaternion_core::dot(z, z_true).clamp(-1.0, 1.0).acos() / PI * 180.0
+    }
 
     #[test]
     fn test_imu_update() {
         let mut imu_update = IMUUpdate {
-            q: (1.0, [0.0, 0.0, 0.0]),
             imu_update_ki: 0.0,
             imu_update_kp: 0.0,
-            err_i: [0.0;3],
+            ..IMUUpdate::new()
         };
 
         let target_rad = 90.0 / 180.0 * PI;
@@ -144,4 +224,91 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_linear_acc_rejection() {
+        // level vehicle accelerating forward at 0.5g, |acc| = 1.118g
+        let mut imu_update = IMUUpdate::new();
+        let acc = [0.0, 0.5 * GRAVITY, GRAVITY];
+        for _ in 0..(5.0 / 0.002) as usize {
+            imu_update.update(acc, [0.0; 3], 0.002);
+        }
+        let err = tilt_err_deg(imu_update.q, (1.0, [0.0; 3]));
+        assert!(err < 1.0, "tilt err:{}", err);
+
+        // without rejection, the estimate is pulled to atan(0.5) = 26.6 degree
+        let mut imu_update = IMUUpdate {
+            acc_tol: f32::MAX,
+            ..IMUUpdate::new()
+        };
+        for _ in 0..(5.0 / 0.002) as usize {
+            imu_update.update(acc, [0.0; 3], 0.002);
+        }
+        assert!(tilt_err_deg(imu_update.q, (1.0, [0.0; 3])) > 15.0);
+    }
+
+    #[test]
+    fn test_coordinated_turn() {
+        // banked 35 degree and yawing in a coordinated turn,
+        // the specific force is along body z and larger than 1g
+        let bank = 35.0 / 180.0 * PI;
+        let yaw_rate = 0.5;
+        let q_bank = quaternion_core::from_axis_angle([0.0, 1.0, 0.0], bank);
+        let gyro = frame_rotation(q_bank, [0.0, 0.0, yaw_rate]);
+        let acc = [0.0, 0.0, GRAVITY / bank.cos()];
+
+        let mut imu_update = IMUUpdate::new();
+        imu_update.q = q_bank;
+        let dt = 0.002;
+        let steps = (10.0 / dt) as usize;
+        for i in 1..=steps {
+            imu_update.update(acc, gyro, dt);
+            let q_yaw = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], yaw_rate * dt * i as f32);
+            let q_true = quaternion_core::mul(q_yaw, q_bank);
+            let err = tilt_err_deg(imu_update.q, q_true);
+            assert!(err < 1.0, "step:{} tilt err:{}", i, err);
+        }
+    }
+
+    #[test]
+    fn test_err_i_clamp() {
+        let mut imu_update = IMUUpdate {
+            imu_update_ki: 100.0,
+            ..IMUUpdate::new()
+        };
+        let tilted = frame_rotation(quaternion_core::from_axis_angle([1.0, 0.0, 0.0], 0.5), [0.0, 0.0, GRAVITY]);
+        for _ in 0..100 {
+            imu_update.update(tilted, [0.0; 3], 0.002);
+        }
+        assert!(imu_update.err_i.iter().all(|x| x.abs() <= imu_update.err_i_max));
+    }
+
+    #[test]
+    fn test_mag_heading() {
+        // the estimate starts 30 degree off the real heading, the gyro has a z bias, declination 10 degree
+        let decl = 10.0f32.to_radians();
+        let field = [0.2 * decl.sin(), 0.2 * decl.cos(), -0.4];
+        let yaw_true = 0.5f32;
+        let q_true = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], yaw_true);
+        let mag = frame_rotation(q_true, field);
+
+        let mut imu_update = IMUUpdate {
+            q: quaternion_core::from_axis_angle([0.0, 0.0, 1.0], yaw_true + 30.0f32.to_radians()),
+            mag_decl: decl,
+            ..IMUUpdate::new()
+        };
+        let dt = 0.002;
+        for i in 0..(20.0 / dt) as usize {
+            imu_update.update([0.0, 0.0, GRAVITY], [0.0, 0.0, 0.005], dt);
+            if i % 10 == 0 {
+                imu_update.update_mag(mag, dt * 10.0);
+            }
+        }
+        let y = point_rotation(imu_update.q, [0.0, 1.0, 0.0]);
+        let y_true = point_rotation(q_true, [0.0, 1.0, 0.0]);
+        let yaw_err = (y[0] * y_true[1] - y[1] * y_true[0]).asin().abs() / PI * 180.0;
+        assert!(yaw_err < 2.0, "yaw err:{}", yaw_err);
+        // the heading correction doesn't touch the tilt
+        assert!(tilt_err_deg(imu_update.q, q_true) < 0.1);
+    }
 }
@@ -0,0 +1,310 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    os::unix::fs::OpenOptionsExt,
+};
+
+use clap::Parser;
+use rpos::{libc, pthread_scheduler::SchedulePthread, thread_logln};
+
+use crate::{
+    msg_define::{MsgHeader, Publisher, RcInputMsg, RcSource, RC_INPUT_MAX_CHANNELS},
+    param::{self, CachedParams, ParameterData},
+};
+
+/*
+    usb gamepad or rc transmitter in joystick mode, read from /dev/input/event*.
+    each rc channel takes an evdev axis(scaled from min~max to 1000~2000us) or a button(1000/2000us).
+    the default mapping is a mode 2 gamepad: right stick roll/pitch, left stick throttle/yaw.
+*/
+#[derive(Parser)]
+#[command(name = "joystick_input", about = None, long_about = None)]
+struct Cli {
+    // a file of recorded events is replayed
+    dev_name: String,
+}
+
+pub const JS_CHANNELS: usize = 8;
+const PUBLISH_PERIOD_US: libc::c_long = 20_000;
+const REOPEN_PERIOD_US: u64 = 1_000_000;
+
+// struct input_event: timeval, type(u16), code(u16), value(i32), 24 bytes on 64-bit and 16 on 32-bit
+pub const EVENT_SIZE: usize = std::mem::size_of::<libc::input_event>();
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_ABS: u16 = 0x03;
+pub const SYN_REPORT: u16 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    pub fn from_bytes(x: &[u8]) -> Self {
+        assert!(x.len() >= EVENT_SIZE);
+        // the bytes from read() have no alignment
+        let event = unsafe { std::ptr::read_unaligned(x.as_ptr() as *const libc::input_event) };
+        InputEvent { event_type: event.type_, code: event.code, value: event.value }
+    }
+}
+
+fn channel_param(ch: usize, name: &str) -> String {
+    format!("js{}_{}", ch + 1, name)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JoystickChannel {
+    pub axis: i32,   // evdev ABS_* code, -1 if not used
+    pub button: i32, // evdev BTN_* code, -1 if not used
+    pub min: i32,    // raw range of the axis
+    pub max: i32,
+    pub rev: bool,
+}
+
+impl Default for JoystickChannel {
+    fn default() -> Self {
+        JoystickChannel { axis: -1, button: -1, min: -32768, max: 32767, rev: false }
+    }
+}
+
+impl JoystickChannel {
+    fn from_params(ch: usize) -> Option<Self> {
+        let get = |name: &str| param::get_param(&channel_param(ch, name));
+        Some(JoystickChannel {
+            axis: get("axis")?.as_i32(),
+            button: get("btn")?.as_i32(),
+            min: get("min")?.as_i32(),
+            max: get("max")?.as_i32(),
+            rev: get("rev")?.as_bool(),
+        })
+    }
+
+    fn axis_to_us(&self, value: i32) -> i16 {
+        let t = ((value - self.min) as f32 / (self.max - self.min).max(1) as f32).clamp(0.0, 1.0);
+        let t = if self.rev { 1.0 - t } else { t };
+        (1000.0 + t * 1000.0).round() as i16
+    }
+
+    fn button_to_us(&self, pressed: bool) -> i16 {
+        if pressed != self.rev {
+            2000
+        } else {
+            1000
+        }
+    }
+}
+
+// ABS_RX, ABS_RY, ABS_Y, ABS_X; up is negative on gamepads
+fn default_channels() -> [JoystickChannel; JS_CHANNELS] {
+    let mut channels = [JoystickChannel::default(); JS_CHANNELS];
+    for (ch, (axis, rev)) in [(0x03, false), (0x04, true), (0x01, true), (0x00, false)].into_iter().enumerate() {
+        channels[ch].axis = axis;
+        channels[ch].rev = rev;
+    }
+    channels
+}
+
+// None if a param of a channel can't be read
+fn channels_from_params() -> Option<[JoystickChannel; JS_CHANNELS]> {
+    let mut channels = [JoystickChannel::default(); JS_CHANNELS];
+    for (ch, channel) in channels.iter_mut().enumerate() {
+        *channel = JoystickChannel::from_params(ch)?;
+    }
+    Some(channels)
+}
+
+pub struct Joystick {
+    pub channels: [JoystickChannel; JS_CHANNELS],
+    channel_vals: [i16; JS_CHANNELS],
+    // a part of an event from the last read
+    pending: Vec<u8>,
+}
+
+impl Joystick {
+    pub fn new(channels: [JoystickChannel; JS_CHANNELS]) -> Self {
+        // the throttle starts at the bottom until the axis moves
+        let channel_vals = std::array::from_fn(|ch| if ch == 2 { 1000 } else { 1500 });
+        Joystick { channels, channel_vals, pending: Vec::new() }
+    }
+
+    pub fn apply(&mut self, event: &InputEvent) {
+        for (ch, calib) in self.channels.iter().enumerate() {
+            let code = event.code as i32;
+            if event.event_type == EV_ABS && calib.axis == code {
+                self.channel_vals[ch] = calib.axis_to_us(event.value);
+            } else if event.event_type == EV_KEY && calib.button == code {
+                // 0 release, 1 press, 2 repeat
+                self.channel_vals[ch] = calib.button_to_us(event.value != 0);
+            }
+        }
+    }
+
+    // returns the number of SYN_REPORT, the device groups the changes by them
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> usize {
+        self.pending.extend_from_slice(bytes);
+        let mut reports = 0;
+        let len = self.pending.len() / EVENT_SIZE * EVENT_SIZE;
+        let events: Vec<InputEvent> = self.pending[..len].chunks(EVENT_SIZE).map(InputEvent::from_bytes).collect();
+        self.pending.drain(..len);
+        for event in &events {
+            if event.event_type == EV_SYN && event.code == SYN_REPORT {
+                reports += 1;
+            } else {
+                self.apply(event);
+            }
+        }
+        reports
+    }
+
+    // reads all the events available, Ok(0) at the end of a recorded file
+    pub fn read_from(&mut self, dev: &mut impl Read) -> io::Result<usize> {
+        let mut buf = [0; EVENT_SIZE * 64];
+        let mut total = 0;
+        loop {
+            match dev.read(&mut buf) {
+                Ok(0) => return Ok(total),
+                Ok(len) => {
+                    total += len;
+                    self.push_bytes(&buf[..len]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(total),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn to_msg(&self, failsafe: bool) -> RcInputMsg {
+        let mut channel_vals = [1500; RC_INPUT_MAX_CHANNELS];
+        channel_vals[..JS_CHANNELS].copy_from_slice(&self.channel_vals);
+        RcInputMsg {
+            header: MsgHeader::default(),
+            source: RcSource::Joystick,
+            channel_count: JS_CHANNELS as u8,
+            failsafe,
+            frame_lost: false,
+            channel_vals,
+        }
+    }
+}
+
+fn open_event_device(dev_name: &str) -> io::Result<File> {
+    OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(dev_name)
+}
+
+pub fn joystick_input_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        let dev_name = args.dev_name.clone();
+        SchedulePthread::new_simple(Box::new(move |s| {
+            let tx = Publisher::<RcInputMsg>::new("rc_input");
+            // the mapping is read again only after a param change, the old one is kept while a param is written
+            let mut channels = CachedParams::new(channels_from_params, default_channels());
+            let mut joystick = Joystick::new(*channels.value());
+            let mut dev: Option<File> = None;
+            let mut next_open_us = 0;
+            loop {
+                let now = crate::basic::hrt_now_us();
+                if dev.is_none() && now >= next_open_us {
+                    match open_event_device(&dev_name) {
+                        Ok(file) => {
+                            thread_logln!("joystick opened: {}", dev_name);
+                            dev = Some(file);
+                        }
+                        Err(e) => {
+                            thread_logln!("open {} failed: {}", dev_name, e);
+                            next_open_us = now + REOPEN_PERIOD_US;
+                        }
+                    }
+                }
+                // evdev reports the changes only, so the state is published at a fixed rate
+                if let Some(file) = dev.as_mut() {
+                    if let Err(e) = joystick.read_from(file) {
+                        thread_logln!("joystick error: {}", e);
+                        dev = None;
+                        next_open_us = now + REOPEN_PERIOD_US;
+                    }
+                }
+                tx.send(joystick.to_msg(dev.is_none()));
+
+                if channels.update() {
+                    joystick.channels = *channels.value();
+                }
+                s.schedule_until(PUBLISH_PERIOD_US);
+            }
+        }));
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    for (ch, default) in default_channels().iter().enumerate() {
+        param::add_param(&channel_param(ch, "axis"), ParameterData::Int(default.axis));
+        param::add_param(&channel_param(ch, "btn"), ParameterData::Int(default.button));
+        param::add_param(&channel_param(ch, "min"), ParameterData::Int(default.min));
+        param::add_param(&channel_param(ch, "max"), ParameterData::Int(default.max));
+        param::add_param(&channel_param(ch, "rev"), ParameterData::Bool(default.rev));
+    }
+    rpos::module::Module::register("joystick_input", joystick_input_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write};
+
+    use super::*;
+
+    fn event_bytes(event_type: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut event: libc::input_event = unsafe { std::mem::zeroed() };
+        event.type_ = event_type;
+        event.code = code;
+        event.value = value;
+        let x = unsafe { std::slice::from_raw_parts(&event as *const libc::input_event as *const u8, EVENT_SIZE) };
+        x.to_vec()
+    }
+
+    #[test]
+    fn test_joystick_replay() {
+        let mut channels = default_channels();
+        // mode switch on BTN_SOUTH
+        channels[4].button = 0x130;
+        let mut recorded = Vec::new();
+        recorded.extend(event_bytes(EV_ABS, 0x03, 32767));
+        recorded.extend(event_bytes(EV_ABS, 0x04, -32768));
+        recorded.extend(event_bytes(EV_SYN, SYN_REPORT, 0));
+        recorded.extend(event_bytes(EV_ABS, 0x01, 0));
+        recorded.extend(event_bytes(EV_KEY, 0x130, 1));
+        // not mapped
+        recorded.extend(event_bytes(EV_ABS, 0x10, -1));
+        recorded.extend(event_bytes(EV_SYN, SYN_REPORT, 0));
+
+        // an event split by the reads
+        let mut joystick = Joystick::new(channels);
+        assert_eq!(joystick.push_bytes(&recorded[..EVENT_SIZE + 6]), 0);
+        assert_eq!(joystick.push_bytes(&recorded[EVENT_SIZE + 6..EVENT_SIZE * 3]), 1);
+        assert_eq!(joystick.to_msg(false).channel_vals[..4], [2000, 2000, 1000, 1500]);
+
+        let path = std::env::temp_dir().join("joystick_test");
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        file.write_all(&recorded).unwrap();
+        drop(file);
+        let mut joystick = Joystick::new(channels);
+        let mut file = open_event_device(path.to_str().unwrap()).unwrap();
+        assert_eq!(joystick.read_from(&mut file).unwrap(), recorded.len());
+        std::fs::remove_file(&path).unwrap();
+        let msg = joystick.to_msg(false);
+        assert_eq!(msg.channel_vals[..6], [2000, 2000, 1500, 1500, 2000, 1500]);
+        assert_eq!((msg.source, msg.channel_count), (RcSource::Joystick, 8));
+
+        let mut joystick = Joystick::new(channels);
+        joystick.push_bytes(&event_bytes(EV_KEY, 0x130, 0));
+        joystick.channels[1].rev = false;
+        joystick.push_bytes(&event_bytes(EV_ABS, 0x04, 0));
+        assert_eq!(joystick.to_msg(true).channel_vals[..5], [1500, 1500, 1000, 1500, 1000]);
+
+        // the params are registered with the default mapping
+        assert_eq!(JoystickChannel::from_params(0), Some(default_channels()[0]));
+        assert!(channels_from_params().is_some());
+    }
+}
@@ -3,7 +3,8 @@
 
 mod msg_define;
 mod param;
-//mod mode;
+mod mode;
+mod arming;
 
 #[cfg(feature = "gzsim")]
 mod gazebo_sim;
@@ -14,7 +15,26 @@ mod fake_linux_input;
 mod att_control;
 mod mixer;
 mod imu_update;
+mod ekf_att;
 mod elrs;
+mod crsf_frame;
+mod rc_input;
+mod sbus;
+mod ibus;
+mod srxl2;
+mod joystick_input;
+mod spi_imu;
+mod iio_mag;
+mod iio_battery;
+mod bmp280;
+mod alt_estimator;
+mod gps;
+mod alt_control;
+mod pos_control;
+mod offboard;
+mod rate_control;
+mod rc_update;
+mod sensor_calib;
 //mod fpga_spi_pwm;
 mod manual_ctrl;
 mod msg_echo;
@@ -1,9 +1,18 @@
+use std::cell::{Cell, RefCell};
+
 use clap::Parser;
-use rpos::msg::{get_new_rx_of_message, get_new_tx_of_message};
+use quaternion_core::Quaternion as Q;
+use rpos::msg::get_new_rx_of_message;
 
 use crate::{
-    msg_define::RcInputMsg,
-    msg_define::{AttitudeSetPointMsg, EulerVector3, TorqueThrustMsg, Vector3, Vector4},
+    alt_control::apply_deadband,
+    basic::rotation::get_yaw,
+    mode::FlightMode,
+    msg_define::{
+        AttitudeSetPointMsg, EulerVector3, MsgHeader, Publisher, RateSetPointMsg, RcChannelsMsg, RcFunction, Stamped,
+        TorqueThrustMsg, Vector3, Vector4,
+    },
+    param::{self, CachedParams, ParameterData},
 };
 
 #[derive(Parser, Clone)]
@@ -16,17 +25,183 @@ struct ManualCtrl {
     directly_out: bool,
 }
 
+// [roll, pitch, throttle, yaw] in [-1,1], positive: right, forward, up, right.
+// the sticks not mapped stay centred, the throttle at the bottom.
+// in failsafe all of them are centred, altitude and position modes hold.
+pub fn get_sticks(rc: &RcChannelsMsg) -> [f32; 4] {
+    if rc.failsafe {
+        return [0.0; 4];
+    }
+    [
+        rc.get(RcFunction::Roll).unwrap_or(0.0),
+        rc.get(RcFunction::Pitch).unwrap_or(0.0),
+        rc.get(RcFunction::Throttle).unwrap_or(-1.0),
+        rc.get(RcFunction::Yaw).unwrap_or(0.0),
+    ]
+}
+
+// cubic expo curve, expo:[0,1], 0 is linear
+pub fn apply_expo(x: f32, expo: f32) -> f32 {
+    (1.0 - expo) * x + expo * x * x * x
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ManualParams {
+    pub tilt_max: f32, // rad, tilt at full roll/pitch stick
+    pub yaw_rate: f32, // rad/s, yaw rate at full stick
+    pub expo: f32,
+    pub deadband: f32,
+}
+
+impl Default for ManualParams {
+    fn default() -> Self {
+        ManualParams {
+            tilt_max: 35.0f32.to_radians(),
+            yaw_rate: 150.0f32.to_radians(),
+            expo: 0.3,
+            deadband: 0.05,
+        }
+    }
+}
+
+impl ManualParams {
+    // None if a param is being written
+    pub fn from_params() -> Option<Self> {
+        let get = |name: &str| param::get_param(name).map(|x| x.as_f32());
+        Some(ManualParams {
+            tilt_max: get("man_tilt_max")?.to_radians(),
+            yaw_rate: get("man_yaw_rate")?.to_radians(),
+            expo: get("man_expo")?,
+            deadband: get("man_deadband")?,
+        })
+    }
+}
+
+// betaflight style rates, the max rate is 200 * rc_rate / (1 - super_rate) deg/s
+#[derive(Debug, Clone, Copy)]
+pub struct AcroRates {
+    pub rc_rate: f32,
+    pub super_rate: f32,
+    pub expo: f32,
+}
+
+impl AcroRates {
+    const MAX_RATE: f32 = 1998.0;
+
+    // stick [-1,1] -> deg/s
+    pub fn rate(&self, x: f32) -> f32 {
+        let x = x.clamp(-1.0, 1.0);
+        let x = x * x.abs().powi(3) * self.expo + x * (1.0 - self.expo);
+        let rc_rate = if self.rc_rate > 2.0 { self.rc_rate + 14.54 * (self.rc_rate - 2.0) } else { self.rc_rate };
+        let rate = 200.0 * rc_rate * x / (1.0 - x.abs() * self.super_rate).clamp(0.01, 1.0);
+        rate.clamp(-Self::MAX_RATE, Self::MAX_RATE)
+    }
+}
+
+pub struct AcroParams {
+    pub roll_pitch: AcroRates,
+    pub yaw: AcroRates,
+    pub thr_idle: f32, // the lowest throttle, keeps the motors spinning
+}
+
+impl Default for AcroParams {
+    fn default() -> Self {
+        AcroParams {
+            roll_pitch: AcroRates { rc_rate: 1.0, super_rate: 0.7, expo: 0.0 },
+            yaw: AcroRates { rc_rate: 1.0, super_rate: 0.7, expo: 0.0 },
+            thr_idle: 0.05,
+        }
+    }
+}
+
+impl AcroParams {
+    // None if a param is being written
+    fn from_params() -> Option<Self> {
+        let get = |name: &str| param::get_param(name).map(|x| x.as_f32());
+        Some(AcroParams {
+            roll_pitch: AcroRates { rc_rate: get("acro_rc_rate")?, super_rate: get("acro_super")?, expo: get("acro_expo")? },
+            yaw: AcroRates { rc_rate: get("acro_y_rc_rate")?, super_rate: get("acro_y_super")?, expo: get("acro_y_expo")? },
+            thr_idle: get("acro_thr_idle")?,
+        })
+    }
+
+    // sticks [roll, pitch, throttle, yaw] -> body rates(rad/s) and the throttle
+    pub fn get_rate_setpoint(&self, sticks: [f32; 4]) -> (EulerVector3, f32) {
+        let [roll, pitch, throttle, yaw] = sticks;
+        let rates = EulerVector3 {
+            // pitch forward is nose down, yaw right is clockwise
+            pitch: -self.roll_pitch.rate(pitch).to_radians(),
+            roll: self.roll_pitch.rate(roll).to_radians(),
+            yaw: -self.yaw.rate(yaw).to_radians(),
+        };
+        let throttle = (throttle.clamp(-1.0, 1.0) + 1.0) / 2.0;
+        (rates, self.thr_idle + (1.0 - self.thr_idle) * throttle)
+    }
+}
+
+/*
+    roll/pitch sticks to a tilt in the heading frame, the yaw stick is integrated into the heading setpoint.
+    the time step is taken from the rc message headers.
+*/
+pub struct StickAttitude {
+    pub params: ManualParams,
+    pub yaw_sp: f32,
+    last_timestamp: u64,
+}
+
+impl StickAttitude {
+    // the longest step of the yaw integration, the rc link may drop for a while
+    const MAX_DT: f32 = 0.1;
+
+    pub fn new(params: ManualParams) -> Self {
+        StickAttitude { params, yaw_sp: 0.0, last_timestamp: 0 }
+    }
+
+    pub fn reset(&mut self, yaw: f32) {
+        self.yaw_sp = yaw;
+        self.last_timestamp = 0;
+    }
+
+    pub fn update(&mut self, sticks: [f32; 4], timestamp: u64) -> Q<f32> {
+        let p = &self.params;
+        let [roll, pitch, _, yaw] = sticks.map(|x| apply_expo(apply_deadband(x, p.deadband), p.expo));
+
+        let dt = if self.last_timestamp != 0 && timestamp > self.last_timestamp {
+            ((timestamp - self.last_timestamp) as f32 / 1000_000.0).min(Self::MAX_DT)
+        } else {
+            0.0
+        };
+        self.last_timestamp = timestamp;
+        // yaw stick to the right turns clockwise
+        self.yaw_sp -= yaw * p.yaw_rate * dt;
+        self.yaw_sp = (self.yaw_sp + std::f32::consts::PI).rem_euclid(2.0 * std::f32::consts::PI) - std::f32::consts::PI;
+
+        // pitch forward is nose down(negative around x), roll right is positive around y, limited in a circle
+        let norm = (roll * roll + pitch * pitch).sqrt();
+        let k = if norm > 1.0 { 1.0 / norm } else { 1.0 };
+        let tilt = quaternion_core::from_rotation_vector([-pitch * k * p.tilt_max, roll * k * p.tilt_max, 0.0]);
+        let q_yaw = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], self.yaw_sp);
+        quaternion_core::mul(q_yaw, tilt)
+    }
+}
+
+// the other modes have their own controllers publishing the setpoints
+fn manual_mode() -> bool {
+    matches!(FlightMode::current(), FlightMode::Manual | FlightMode::Stabilize)
+}
+
 pub fn init_manual_ctrl(argc: u32, argv: *const &str) {
     if let Some(args) = crate::basic::client_process_args::<ManualCtrl>(argc, argv) {
-        let rx = get_new_rx_of_message::<RcInputMsg>("rc_input").unwrap();
+        let rx = get_new_rx_of_message::<RcChannelsMsg>("rc_channels").unwrap();
         if args.directly_out {
-            let ctrl_msg_tx =
-                get_new_tx_of_message::<TorqueThrustMsg>("toreque_thrust_setpoint").unwrap();
+            let ctrl_msg_tx = Publisher::<TorqueThrustMsg>::new("toreque_thrust_setpoint");
             rx.register_callback("manual_ctrl_rx", move |rc_msg| {
-                let arr = rc_msg
-                    .channel_vals
-                    .map(|x| (x as f32).clamp(-1000.0, 1000.0));
+                if !manual_mode() || rc_msg.failsafe {
+                    return;
+                }
+                let arr = get_sticks(rc_msg).map(|x| x * 1000.0);
                 ctrl_msg_tx.send(TorqueThrustMsg {
+                    header: MsgHeader::default(),
                     torques: EulerVector3 {
                         pitch: arr[1],
                         roll: arr[0],
@@ -40,21 +215,55 @@ pub fn init_manual_ctrl(argc: u32, argv: *const &str) {
                 });
             });
         } else {
-            let att_target_tx = get_new_tx_of_message::<AttitudeSetPointMsg>("att_target").unwrap();
+            let att_target_tx = Publisher::<AttitudeSetPointMsg>::new("att_target");
+            let rate_target_tx = Publisher::<RateSetPointMsg>::new("rate_target");
+            let att_rx = RefCell::new(get_new_rx_of_message::<Stamped<Vector4>>("attitude").unwrap());
+            let manual = RefCell::new(CachedParams::new(ManualParams::from_params, ManualParams::default()));
+            let stick_att = RefCell::new(StickAttitude::new(*manual.borrow().value()));
+            let acro = RefCell::new(CachedParams::new(AcroParams::from_params, AcroParams::default()));
+            let q: Cell<Q<f32>> = Cell::new((1.0, [0.0; 3]));
+            let active = Cell::new(false);
 
             rx.register_callback("manual_ctrl_rx", move |rc_msg| {
+                // in failsafe the sticks are centred: zero rates in acro, level with the heading held in stabilize,
+                // both at the middle throttle. the last setpoint is not kept.
+                if let Some(att) = att_rx.borrow_mut().try_read() {
+                    q.set((att.w, [att.x, att.y, att.z]));
+                }
+                let sticks = get_sticks(rc_msg);
+                if FlightMode::current() == FlightMode::Acro {
+                    let (angle_rate, thrust) = acro.borrow_mut().get().get_rate_setpoint(sticks);
+                    rate_target_tx.send(RateSetPointMsg {
+                        header: MsgHeader::default(),
+                        angle_rate,
+                        thrusts: Vector3 { x: 0.0, y: 0.0, z: thrust },
+                    });
+                }
+                if !manual_mode() {
+                    active.set(false);
+                    return;
+                }
+                let mut stick_att = stick_att.borrow_mut();
+                // follow the current heading when switched in or landed(throttle at the bottom)
+                if !active.get() || sticks[2] <= -0.95 {
+                    stick_att.params = *manual.borrow_mut().get();
+                    stick_att.reset(get_yaw(q.get()));
+                    active.set(true);
+                }
+                let attitude = stick_att.update(sticks, rc_msg.header.timestamp);
                 att_target_tx.send(AttitudeSetPointMsg {
-                    attitude: Vector4{
-                        w: 1.0,
-                        x: 0.0,
-                        y: 0.0,
-                        z: 0.0,
+                    header: MsgHeader::default(),
+                    attitude: Vector4 {
+                        w: attitude.0,
+                        x: attitude.1[0],
+                        y: attitude.1[1],
+                        z: attitude.1[2],
                     },
                     body_thrusts: Vector3 {
                         x: 0.0,
                         y: 0.0,
-                        z: (rc_msg.channel_vals[2] + 1000) as f32 / 2000.0,
-                    }, // maping -1000~1000 to 0~1 }
+                        z: (sticks[2] + 1.0) / 2.0,
+                    }, // maping -1~1 to 0~1
                 });
             });
         }
@@ -63,5 +272,127 @@ pub fn init_manual_ctrl(argc: u32, argv: *const &str) {
 
 #[rpos::ctor::ctor]
 fn register() {
+    let default = ManualParams::default();
+    param::add_param("man_tilt_max", ParameterData::Float(default.tilt_max.to_degrees()));
+    param::add_param("man_yaw_rate", ParameterData::Float(default.yaw_rate.to_degrees()));
+    param::add_param("man_expo", ParameterData::Float(default.expo));
+    param::add_param("man_deadband", ParameterData::Float(default.deadband));
+    let acro = AcroParams::default();
+    param::add_param("acro_rc_rate", ParameterData::Float(acro.roll_pitch.rc_rate));
+    param::add_param("acro_super", ParameterData::Float(acro.roll_pitch.super_rate));
+    param::add_param("acro_expo", ParameterData::Float(acro.roll_pitch.expo));
+    param::add_param("acro_y_rc_rate", ParameterData::Float(acro.yaw.rc_rate));
+    param::add_param("acro_y_super", ParameterData::Float(acro.yaw.super_rate));
+    param::add_param("acro_y_expo", ParameterData::Float(acro.yaw.expo));
+    param::add_param("acro_thr_idle", ParameterData::Float(acro.thr_idle));
     rpos::module::Module::register("manual_ctrl", init_manual_ctrl);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg_define::{RcSource, RC_INPUT_MAX_CHANNELS};
+    use quaternion_core::point_rotation;
+
+    #[test]
+    fn test_get_sticks() {
+        let mut functions = [f32::NAN; RcFunction::COUNT];
+        functions[RcFunction::Roll as usize] = 0.1;
+        functions[RcFunction::Yaw as usize] = -0.4;
+        let mut rc = RcChannelsMsg {
+            header: MsgHeader::default(),
+            source: RcSource::Crsf,
+            failsafe: false,
+            channels: [0.0; RC_INPUT_MAX_CHANNELS],
+            functions,
+        };
+        assert_eq!(get_sticks(&rc), [0.1, 0.0, -1.0, -0.4]);
+        rc.failsafe = true;
+        assert_eq!(get_sticks(&rc), [0.0; 4]);
+    }
+
+    #[test]
+    fn test_expo() {
+        assert_eq!(apply_expo(1.0, 0.3), 1.0);
+        assert_eq!(apply_expo(-1.0, 0.3), -1.0);
+        assert_eq!(apply_expo(0.5, 0.0), 0.5);
+        assert!(apply_expo(0.5, 0.3) < 0.5);
+    }
+
+    #[test]
+    fn test_stick_attitude() {
+        let mut stick_att = StickAttitude::new(ManualParams { expo: 0.0, ..Default::default() });
+        let tilt_max = stick_att.params.tilt_max;
+
+        // centred
+        let q = stick_att.update([0.03, -0.03, 0.0, 0.0], 1000);
+        assert_eq!(q, (1.0, [0.0; 3]));
+
+        // full pitch forward, nose down
+        let q = stick_att.update([0.0, 1.0, 0.0, 0.0], 2000);
+        let front = point_rotation(q, [0.0, 1.0, 0.0]);
+        assert!((front[2] + tilt_max.sin()).abs() < 1e-5);
+
+        // full roll right, right side down
+        let q = stick_att.update([1.0, 0.0, 0.0, 0.0], 3000);
+        let right = point_rotation(q, [1.0, 0.0, 0.0]);
+        assert!((right[2] + tilt_max.sin()).abs() < 1e-5);
+
+        // diagonal is limited to the max tilt too
+        let q = stick_att.update([1.0, 1.0, 0.0, 0.0], 4000);
+        let z = point_rotation(q, [0.0, 0.0, 1.0]);
+        assert!((z[2] - tilt_max.cos()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_stick_yaw() {
+        let mut stick_att = StickAttitude::new(ManualParams { expo: 0.0, deadband: 0.0, ..Default::default() });
+        stick_att.reset(0.2);
+        let yaw_rate = stick_att.params.yaw_rate;
+
+        // half yaw stick to the right for 1 second with 50Hz rc
+        let mut q = (1.0, [0.0; 3]);
+        for i in 0..=50 {
+            q = stick_att.update([0.0, 0.0, 0.0, 0.5], 1000_000 + i * 20_000);
+        }
+        assert!((stick_att.yaw_sp - (0.2 - 0.5 * yaw_rate)).abs() < 1e-4);
+        assert!((get_yaw(q) - stick_att.yaw_sp).abs() < 1e-4);
+
+        // a long gap of the rc link does not jump the heading
+        let yaw = stick_att.yaw_sp;
+        stick_att.update([0.0, 0.0, 0.0, 1.0], 5000_000);
+        assert!((yaw - stick_att.yaw_sp - StickAttitude::MAX_DT * yaw_rate).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_acro_rates() {
+        let rates = AcroRates { rc_rate: 1.0, super_rate: 0.7, expo: 0.0 };
+        assert_eq!(rates.rate(0.0), 0.0);
+        assert!((rates.rate(1.0) - 200.0 / 0.3).abs() < 0.01);
+        assert!((rates.rate(0.5) - 100.0 / 0.65).abs() < 0.01);
+        assert_eq!(rates.rate(-0.5), -rates.rate(0.5));
+
+        // expo softens the centre, keeps the end
+        let expo = AcroRates { expo: 0.5, ..rates };
+        assert!(expo.rate(0.3) < rates.rate(0.3));
+        assert!((expo.rate(1.0) - rates.rate(1.0)).abs() < 0.01);
+
+        // rc rate over 2 goes faster
+        let fast = AcroRates { rc_rate: 2.2, super_rate: 0.0, expo: 0.0 };
+        assert!((fast.rate(1.0) - 200.0 * (2.2 + 14.54 * 0.2)).abs() < 0.01);
+        assert_eq!(AcroRates { rc_rate: 2.5, super_rate: 0.99, expo: 0.0 }.rate(1.0), AcroRates::MAX_RATE);
+    }
+
+    #[test]
+    fn test_acro_setpoint() {
+        let acro = AcroParams::default();
+        let (rates, thrust) = acro.get_rate_setpoint([1.0, 1.0, -1.0, 1.0]);
+        let max = (200.0f32 / 0.3).to_radians();
+        assert!((rates.roll - max).abs() < 1e-3);
+        assert!((rates.pitch + max).abs() < 1e-3);
+        assert!((rates.yaw + max).abs() < 1e-3);
+        // idle at the bottom
+        assert_eq!(thrust, acro.thr_idle);
+        assert_eq!(acro.get_rate_setpoint([0.0, 0.0, 1.0, 0.0]).1, 1.0);
+    }
+}
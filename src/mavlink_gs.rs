@@ -1,9 +1,19 @@
 use clap::Parser;
-use rpos::{thread_logln, msg::get_new_tx_of_message};
-use std::{sync::Arc};
+use rpos::{msg::get_new_rx_of_message, thread_logln};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    param::{self, ParameterData}, msg_define::RcInputMsg,
+    basic::rotation::{ned_q_to_enu, ned_to_enu},
+    mode::FlightMode,
+    param::{self, ParameterData},
+    msg_define::{
+        AttitudeSetPointMsg, EulerVector3, MsgHeader, OffboardSetpoint, OffboardSetpointMsg, PositionSetpointMsg,
+        Publisher, RcInputMsg, RcSource, Vector4, RC_INPUT_MAX_CHANNELS,
+    },
+    sensor_calib::{self, CalibType},
 };
 use mavlink::{
     common::{self, MavMessage},
@@ -50,6 +60,60 @@ fn get_paramdata_in_mav(p: &common::MavParamType, value: f32) -> Option<param::P
     }
 }
 
+// offboard setpoints are in NED/FRD, convert them to the frames of docs/axis.md
+fn get_offboard_setpoint(msg: &MavMessage) -> Option<OffboardSetpoint> {
+    match msg {
+        MavMessage::SET_ATTITUDE_TARGET(data) => {
+            use common::AttitudeTargetTypemask as Mask;
+            if data.type_mask.contains(Mask::ATTITUDE_TARGET_TYPEMASK_ATTITUDE_IGNORE) {
+                return None;
+            }
+            let q = ned_q_to_enu((data.q[0], [data.q[1], data.q[2], data.q[3]]));
+            Some(OffboardSetpoint::Attitude {
+                attitude: Vector4 { w: q.0, x: q.1[0], y: q.1[1], z: q.1[2] },
+                thrust: data.thrust,
+            })
+        }
+        MavMessage::SET_POSITION_TARGET_LOCAL_NED(data) => {
+            use common::PositionTargetTypemask as Mask;
+            if data.coordinate_frame != common::MavFrame::MAV_FRAME_LOCAL_NED {
+                return None;
+            }
+            let pos_mask = Mask::POSITION_TARGET_TYPEMASK_X_IGNORE
+                | Mask::POSITION_TARGET_TYPEMASK_Y_IGNORE
+                | Mask::POSITION_TARGET_TYPEMASK_Z_IGNORE;
+            let vel_mask = Mask::POSITION_TARGET_TYPEMASK_VX_IGNORE
+                | Mask::POSITION_TARGET_TYPEMASK_VY_IGNORE
+                | Mask::POSITION_TARGET_TYPEMASK_VZ_IGNORE;
+            // the local position is NED too
+            let pos = (!data.type_mask.intersects(pos_mask)).then_some([data.x, data.y, data.z]);
+            let vel = (!data.type_mask.intersects(vel_mask)).then_some([data.vx, data.vy, data.vz]);
+            let yaw = (!data.type_mask.contains(Mask::POSITION_TARGET_TYPEMASK_YAW_IGNORE)).then_some(data.yaw);
+            if pos.is_none() && vel.is_none() {
+                return None;
+            }
+            Some(OffboardSetpoint::Position { pos, vel, yaw })
+        }
+        MavMessage::SET_ACTUATOR_CONTROL_TARGET(data) if data.group_mlx == 0 => {
+            // group 0: roll, pitch, yaw in FRD, throttle
+            let torques = ned_to_enu([data.controls[0], data.controls[1], data.controls[2]]);
+            Some(OffboardSetpoint::Torque {
+                torques: EulerVector3 { pitch: torques[0], roll: torques[1], yaw: torques[2] },
+                thrust: data.controls[3],
+            })
+        }
+        _ => None,
+    }
+}
+
+// the text is cut to the 50 bytes of STATUSTEXT
+fn status_text(severity: common::MavSeverity, s: &str) -> MavMessage {
+    let mut text = [0u8; 50];
+    let len = s.len().min(text.len());
+    text[..len].copy_from_slice(&s.as_bytes()[..len]);
+    MavMessage::STATUSTEXT(common::STATUSTEXT_DATA { severity, text })
+}
+
 pub unsafe fn init_mavlink_gs(argc: u32, argv: *const &str) {
     let args = crate::basic::client_process_args::<Cli>(argc, argv).unwrap();
 
@@ -87,6 +151,61 @@ pub unsafe fn init_mavlink_gs(argc: u32, argv: *const &str) {
         }
     });
 
+    // feedback of the offboard targets
+    std::thread::spawn({
+        let mavconn = mavconn.clone();
+        move || {
+            let mut att_target_rx = get_new_rx_of_message::<AttitudeSetPointMsg>("att_target").unwrap();
+            let mut pos_target_rx = get_new_rx_of_message::<PositionSetpointMsg>("position_target").unwrap();
+            loop {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                if FlightMode::current() != FlightMode::Offboard {
+                    continue;
+                }
+                let time_boot_ms = (crate::basic::hrt_now_us() / 1000) as u32;
+                if let Some(sp) = att_target_rx.try_read() {
+                    use common::AttitudeTargetTypemask as Mask;
+                    let q = ned_q_to_enu((sp.attitude.w, [sp.attitude.x, sp.attitude.y, sp.attitude.z]));
+                    let msg = MavMessage::ATTITUDE_TARGET(common::ATTITUDE_TARGET_DATA {
+                        time_boot_ms,
+                        q: [q.0, q.1[0], q.1[1], q.1[2]],
+                        body_roll_rate: 0.0,
+                        body_pitch_rate: 0.0,
+                        body_yaw_rate: 0.0,
+                        thrust: sp.body_thrusts.z,
+                        type_mask: Mask::ATTITUDE_TARGET_TYPEMASK_BODY_ROLL_RATE_IGNORE
+                            | Mask::ATTITUDE_TARGET_TYPEMASK_BODY_PITCH_RATE_IGNORE
+                            | Mask::ATTITUDE_TARGET_TYPEMASK_BODY_YAW_RATE_IGNORE,
+                    });
+                    let _ = mavconn.send(&header, &msg);
+                }
+                if let Some(sp) = pos_target_rx.try_read() {
+                    use common::PositionTargetTypemask as Mask;
+                    let msg = MavMessage::POSITION_TARGET_LOCAL_NED(common::POSITION_TARGET_LOCAL_NED_DATA {
+                        time_boot_ms,
+                        x: sp.x,
+                        y: sp.y,
+                        z: sp.z,
+                        vx: sp.vx,
+                        vy: sp.vy,
+                        vz: sp.vz,
+                        afx: 0.0,
+                        afy: 0.0,
+                        afz: 0.0,
+                        yaw: sp.yaw,
+                        yaw_rate: 0.0,
+                        type_mask: Mask::POSITION_TARGET_TYPEMASK_AX_IGNORE
+                            | Mask::POSITION_TARGET_TYPEMASK_AY_IGNORE
+                            | Mask::POSITION_TARGET_TYPEMASK_AZ_IGNORE
+                            | Mask::POSITION_TARGET_TYPEMASK_YAW_RATE_IGNORE,
+                        coordinate_frame: common::MavFrame::MAV_FRAME_LOCAL_NED,
+                    });
+                    let _ = mavconn.send(&header, &msg);
+                }
+            }
+        }
+    });
+
     let autopilot_version = MavMessage::AUTOPILOT_VERSION(common::AUTOPILOT_VERSION_DATA {
         capabilities: common::MavProtocolCapability::MAV_PROTOCOL_CAPABILITY_MAVLINK2 |
             common::MavProtocolCapability::MAV_PROTOCOL_CAPABILITY_PARAM_ENCODE_BYTEWISE |
@@ -112,18 +231,67 @@ pub unsafe fn init_mavlink_gs(argc: u32, argv: *const &str) {
         library_version_hash: [0; 8],
     });
 
+    let offboard_tx = Publisher::<OffboardSetpointMsg>::new("offboard_setpoint");
+
     let rc_input_tx;
 
     if args.joystick{
-        rc_input_tx = Some(get_new_tx_of_message::<RcInputMsg>("rc_input").unwrap());
+        rc_input_tx = Some(Publisher::<RcInputMsg>::new("rc_input"));
     }else{
         rc_input_tx = None;
     }
 
+    // a stream of setpoints, logged once a second at most
+    let mut unsupported_setpoints = 0u32;
+    let mut last_unsupported_log: Option<Instant> = None;
+
     loop {
         match mavconn.recv() {
             Ok((_header, msg)) => {
                 match msg {
+                    MavMessage::COMMAND_LONG(ref data)
+                        if data.command == common::MavCmd::MAV_CMD_PREFLIGHT_CALIBRATION =>
+                    {
+                        // param1: gyro, param2: mag, param4: rc, param5: 1 accel, 2 level
+                        let calib_type = if data.param1 == 1.0 {
+                            Some(CalibType::Gyro)
+                        } else if data.param2 == 1.0 {
+                            Some(CalibType::Mag)
+                        } else if data.param4 == 1.0 {
+                            Some(CalibType::Rc)
+                        } else if data.param5 == 1.0 {
+                            Some(CalibType::Accel)
+                        } else if data.param5 == 2.0 {
+                            Some(CalibType::Level)
+                        } else {
+                            None
+                        };
+                        let result = if let Some(calib_type) = calib_type {
+                            // the progress goes to the ground station too
+                            let mavconn = mavconn.clone();
+                            std::thread::spawn(move || {
+                                let log = |severity, s: &str| {
+                                    println!("{}", s);
+                                    let _ = mavconn.send(&header, &status_text(severity, s));
+                                };
+                                let ret = sensor_calib::run_calibration(calib_type, &|s| log(common::MavSeverity::MAV_SEVERITY_INFO, s));
+                                let severity = if ret.is_ok() {
+                                    common::MavSeverity::MAV_SEVERITY_INFO
+                                } else {
+                                    common::MavSeverity::MAV_SEVERITY_ERROR
+                                };
+                                log(severity, &format!("{:?} calibration result:{:?}", calib_type, ret));
+                            });
+                            common::MavResult::MAV_RESULT_ACCEPTED
+                        } else {
+                            common::MavResult::MAV_RESULT_UNSUPPORTED
+                        };
+                        let ack = MavMessage::COMMAND_ACK(common::COMMAND_ACK_DATA {
+                            command: common::MavCmd::MAV_CMD_PREFLIGHT_CALIBRATION,
+                            result,
+                        });
+                        let _ = mavconn.send(&header, &ack);
+                    }
                     MavMessage::COMMAND_LONG(ref data) => {
                         let req_message_id = data.param1 as u32;
                         if data.command != common::MavCmd::MAV_CMD_REQUEST_MESSAGE {
@@ -202,12 +370,37 @@ pub unsafe fn init_mavlink_gs(argc: u32, argv: *const &str) {
                         let _ = mavconn.send(&header, &msg);
                     }
 
+                    MavMessage::SET_ATTITUDE_TARGET(_)
+                    | MavMessage::SET_POSITION_TARGET_LOCAL_NED(_)
+                    | MavMessage::SET_ACTUATOR_CONTROL_TARGET(_) => {
+                        if let Some(setpoint) = get_offboard_setpoint(&msg) {
+                            offboard_tx.send(OffboardSetpointMsg { header: MsgHeader::default(), setpoint });
+                        } else {
+                            unsupported_setpoints += 1;
+                            if last_unsupported_log.is_none_or(|t| t.elapsed() >= Duration::from_secs(1)) {
+                                println!("unsupport offboard setpoint({unsupported_setpoints} in total): {msg:?}");
+                                last_unsupported_log = Some(Instant::now());
+                            }
+                        }
+                    }
+
                     MavMessage::HEARTBEAT(_) => {}
                     MavMessage::MANUAL_CONTROL(data) => {
                         if let Some(ref tx) = rc_input_tx{
-                            let mut vals = [0;8];
-                            vals[2] = (data.z - 500) * 2;   // map 0-1000 to -1000 to 1000
-                            tx.send(RcInputMsg { channel_vals: vals })
+                            // AETR in us, x,y,r: -1000~1000, z: 0~1000
+                            let mut vals = [1500;RC_INPUT_MAX_CHANNELS];
+                            vals[0] = 1500 + data.y / 2;
+                            vals[1] = 1500 + data.x / 2;
+                            vals[2] = 1000 + data.z;
+                            vals[3] = 1500 + data.r / 2;
+                            tx.send(RcInputMsg {
+                                header: MsgHeader::default(),
+                                source: RcSource::Mavlink,
+                                channel_count: 4,
+                                failsafe: false,
+                                frame_lost: false,
+                                channel_vals: vals,
+                            })
                         }
                         //println!("received: {msg:?}");
                     }
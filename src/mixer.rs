@@ -1,13 +1,12 @@
 #![allow(dead_code)]
 use crate::basic::scaler::Scaler;
-use rpos::{
-    channel::Sender,
-    msg::{get_new_rx_of_message, get_new_tx_of_message},
-};
+use rpos::msg::get_new_rx_of_message;
 use serde::{Deserialize, Serialize};
-use std::{io::Read, path::Path };
+use std::{cell::Cell, io::Read, path::Path};
 
-use crate::msg_define::{TorqueThrustMsg, MixerOutputMsg};
+use crate::msg_define::{EulerVector3, TorqueThrustMsg, MixerOutputMsg, MsgHeader, Publisher};
+use crate::arming;
+use crate::param::{self, ParameterData};
 
 // Mixer Output
 
@@ -29,21 +28,62 @@ struct Mixer {
     #[serde(skip)]
     controller_outputs: Vec<TorqueThrustMsg>,
     mixers: Vec<SumMixer>,
+    // the motors of a multicopter, mixed together to handle the saturation
+    #[serde(default)]
+    multicopter: Option<MulticopterMixer>,
+    #[serde(skip)]
+    tx: Publisher<MixerOutputMsg>,
+    // mc_airmode and the param version it was read at
     #[serde(skip)]
-    tx: Sender<MixerOutputMsg>,
+    airmode: Cell<Option<(u32, bool)>>,
 }
 
 impl Mixer {
-    #[inline(always)]
-    fn update_ctrl_outputs(&self, msg: &TorqueThrustMsg) {
+    fn outputs(&self, msg: &TorqueThrustMsg, airmode: bool) -> [f32; 8] {
         let mut publish: [f32; 8] = [0.0; 8];
+        if let Some(mc) = &self.multicopter {
+            for (m, x) in mc.motors.iter().zip(mc.mix(msg, airmode)) {
+                publish[m.output_channel_idx as usize] = x;
+            }
+        }
         for i in &self.mixers {
             if i.bind_ctrl_group_id == 0 {
                 // TODO: remove this
-                publish[i.output_channel_idx as usize] = i.calcuate(&msg)
+                publish[i.output_channel_idx as usize] = i.calcuate(msg)
+            }
+        }
+        publish
+    }
+
+    // read again only when a param is changed, the old value is kept if it is being written
+    fn airmode(&self) -> bool {
+        let version = param::version();
+        let cached = self.airmode.get();
+        match cached {
+            Some((v, airmode)) if v == version => airmode,
+            _ => match param::get_param("mc_airmode") {
+                Some(x) => {
+                    self.airmode.set(Some((version, x.as_bool())));
+                    x.as_bool()
+                }
+                None => cached.is_some_and(|(_, airmode)| airmode),
+            },
+        }
+    }
+
+    #[inline(always)]
+    fn update_ctrl_outputs(&self, msg: &TorqueThrustMsg) {
+        let mut publish = self.outputs(msg, self.airmode());
+        // disarmed or killed, only the motors are stopped
+        if !arming::motors_enabled() {
+            if let Some(mc) = &self.multicopter {
+                for m in &mc.motors {
+                    publish[m.output_channel_idx as usize] = 0.0;
+                }
             }
         }
         self.tx.send(MixerOutputMsg {
+            header: MsgHeader::default(),
             output: publish,
             control_group_id: 0,
         });
@@ -58,6 +98,7 @@ impl Mixer {
             file.read_to_string(&mut s).unwrap();
             if let Ok(temp) = serde_json::from_str::<Mixer>(&s) {
                 self.mixers = temp.mixers;
+                self.multicopter = temp.multicopter;
             } else {
                 return Err(());
             }
@@ -69,89 +110,201 @@ impl Mixer {
         Ok(())
     }
 
-    fn init_x_quadcopter_mixers(&mut self) {
-        /*
-            gazebo x3 quadcopter motor index
-               2     0
-                  x
-               1     3
-        */
-        let motor_0 = SumMixer {
-            list: vec![
-                MixerChannel {
-                    scaler: Scaler::default(),
-                    ctrl_group_id: 0,
-                    ctrl_channel: ControlChannel::Pitch,
-                },
-                MixerChannel {
-                    scaler: -Scaler::default(),
-                    ctrl_group_id: 0,
-                    ctrl_channel: ControlChannel::Roll,
-                },
-            ],
-            bind_ctrl_group_id: 0,
-            output_channel_idx: 0,
-            mode: OutputMode::Speed,
-        };
+    fn init_geometry_mixers(&mut self, geometry: Geometry) {
+        let rotors = geometry.rotors();
+        // the farthest arm gets ±1, so the torque ratios of the frame are kept
+        let k = rotors.iter().fold(0.0f32, |k, r| k.max(r.x().abs()).max(r.y().abs()));
+        // values of sin/cos near 0
+        let round = |x: f32| if x.abs() > 1e-6 { x } else { 0.0 };
+        let motors = rotors
+            .iter()
+            .enumerate()
+            .map(|(i, r)| MotorMix {
+                output_channel_idx: i as u8,
+                pitch: round(r.y() / k),
+                roll: round(-r.x() / k),
+                yaw: r.yaw,
+                thrust: THRUST_SCALE,
+            })
+            .collect();
+        self.multicopter = Some(MulticopterMixer { motors, max: THRUST_SCALE, torque_max: TORQUE_LIMIT });
+        if geometry == Geometry::Tri {
+            // tail servo after the motors, as the car mixer
+            let mut yaw = MixerChannel::new(ControlChannel::Yaw, 0.5, 1000.0, 2000.0);
+            yaw.scaler.offset = 1500.0;
+            self.mixers.push(SumMixer {
+                list: vec![yaw],
+                bind_ctrl_group_id: 0,
+                output_channel_idx: rotors.len() as u8,
+                mode: OutputMode::PluseWidth,
+            });
+        }
+    }
+}
 
-        let motor_1 = SumMixer {
-            list: vec![
-                MixerChannel {
-                    scaler: -Scaler::default(),
-                    ctrl_group_id: 0,
-                    ctrl_channel: ControlChannel::Pitch,
-                },
-                MixerChannel {
-                    scaler: Scaler::default(),
-                    ctrl_group_id: 0,
-                    ctrl_channel: ControlChannel::Roll,
-                },
-            ],
-            bind_ctrl_group_id: 0,
-            output_channel_idx: 1,
-            mode: OutputMode::Speed,
-        };
+// same as mixers/gz_mixer.json: thrust 0~1 to the motor speed of gazebo
+const THRUST_SCALE: f32 = 1000.0;
+// the controllers saturate here, as the ±100 limit of each channel of the old sum mixers
+const TORQUE_LIMIT: f32 = 100.0;
+// yaw torque of a rotor, the reaction of a ccw prop turns the frame cw
+const CW: f32 = 1.0;
+const CCW: f32 = -1.0;
 
-        let motor_2 = SumMixer {
-            list: vec![
-                MixerChannel {
-                    scaler: Scaler::default(),
-                    ctrl_group_id: 0,
-                    ctrl_channel: ControlChannel::Pitch,
-                },
-                MixerChannel {
-                    scaler: Scaler::default(),
-                    ctrl_group_id: 0,
-                    ctrl_channel: ControlChannel::Roll,
-                },
-            ],
-            bind_ctrl_group_id: 0,
-            output_channel_idx: 2,
-            mode: OutputMode::Speed,
-        };
+#[derive(Debug, Clone, Copy)]
+struct Rotor {
+    angle: f32, // degrees from the front, clockwise seen from above
+    yaw: f32,   // CW, CCW, or 0 if the yaw is done by a servo
+}
 
-        let motor_3 = SumMixer {
-            list: vec![
-                MixerChannel {
-                    scaler: -Scaler::default(),
-                    ctrl_group_id: 0,
-                    ctrl_channel: ControlChannel::Pitch,
-                },
-                MixerChannel {
-                    scaler: -Scaler::default(),
-                    ctrl_group_id: 0,
-                    ctrl_channel: ControlChannel::Roll,
-                },
-            ],
-            bind_ctrl_group_id: 0,
-            output_channel_idx: 3,
-            mode: OutputMode::Speed,
-        };
+impl Rotor {
+    // body frame of docs/axis.md, x right, y front, unit arm
+    fn x(&self) -> f32 {
+        self.angle.to_radians().sin()
+    }
 
-        self.mixers.push(motor_0);
-        self.mixers.push(motor_1);
-        self.mixers.push(motor_2);
-        self.mixers.push(motor_3);
+    fn y(&self) -> f32 {
+        self.angle.to_radians().cos()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Geometry {
+    QuadX,
+    QuadPlus,
+    HexX,
+    HexPlus,
+    OctoX,
+    OctoPlus,
+    Tri,
+    Y6,
+}
+
+impl Geometry {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "quad_x" => Some(Geometry::QuadX),
+            "quad_plus" => Some(Geometry::QuadPlus),
+            "hex_x" => Some(Geometry::HexX),
+            "hex_plus" => Some(Geometry::HexPlus),
+            "octo_x" => Some(Geometry::OctoX),
+            "octo_plus" => Some(Geometry::OctoPlus),
+            "tri" => Some(Geometry::Tri),
+            "y6" => Some(Geometry::Y6),
+            _ => None,
+        }
+    }
+
+    // n rotors evenly around, clockwise from the first one with alternate spin
+    fn ring(n: usize, first: f32) -> Vec<Rotor> {
+        (0..n)
+            .map(|i| Rotor {
+                angle: first + 360.0 / n as f32 * i as f32,
+                yaw: if i % 2 == 0 { CCW } else { CW },
+            })
+            .collect()
+    }
+
+    fn rotors(&self) -> Vec<Rotor> {
+        let r = |angle, yaw| Rotor { angle, yaw };
+        match self {
+            /*
+                gazebo x3 quadcopter motor index
+                   2     0
+                      x
+                   1     3
+            */
+            Geometry::QuadX => vec![r(45.0, CCW), r(225.0, CCW), r(315.0, CW), r(135.0, CW)],
+            Geometry::QuadPlus => Self::ring(4, 0.0),
+            Geometry::HexX => Self::ring(6, 30.0),
+            Geometry::HexPlus => Self::ring(6, 0.0),
+            Geometry::OctoX => Self::ring(8, 22.5),
+            Geometry::OctoPlus => Self::ring(8, 0.0),
+            // front right, front left, rear
+            Geometry::Tri => vec![r(60.0, 0.0), r(300.0, 0.0), r(180.0, 0.0)],
+            // top and bottom props of an arm spin in the opposite directions
+            Geometry::Y6 => [60.0, 180.0, 300.0].iter().flat_map(|a| [r(*a, CCW), r(*a, CW)]).collect(),
+        }
+    }
+}
+
+// a motor gets pitch * torques.pitch + roll * torques.roll + yaw * torques.yaw + thrust * thrusts.z
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct MotorMix {
+    output_channel_idx: u8,
+    pitch: f32,
+    roll: f32,
+    yaw: f32,
+    thrust: f32,
+}
+
+/*
+    the outputs are kept in 0~max without losing the direction of the torque:
+    1. roll and pitch are scaled down together if the differences of the motors are out of the range
+    2. the collective is shifted to fit them, it is only lowered without airmode,
+       roll and pitch are reduced instead at low throttle(no torque at zero throttle)
+    3. the collective is shifted again for yaw in the same way, then yaw is scaled to the room left
+    the torques are limited to ±torque_max before, roll and pitch together to keep the direction
+*/
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct MulticopterMixer {
+    motors: Vec<MotorMix>,
+    max: f32,
+    #[serde(default = "default_torque_max")]
+    torque_max: f32,
+}
+
+fn default_torque_max() -> f32 {
+    TORQUE_LIMIT
+}
+
+impl MulticopterMixer {
+    fn limit_torques(&self, t: &EulerVector3) -> EulerVector3 {
+        let k = (self.torque_max / t.pitch.abs().max(t.roll.abs())).min(1.0);
+        EulerVector3 { pitch: t.pitch * k, roll: t.roll * k, yaw: t.yaw.clamp(-self.torque_max, self.torque_max) }
+    }
+
+    fn mix(&self, ctrl: &TorqueThrustMsg, airmode: bool) -> Vec<f32> {
+        let t = &self.limit_torques(&ctrl.torques);
+        let base: Vec<f32> = self.motors.iter().map(|m| (m.thrust * ctrl.thrusts.z).clamp(0.0, self.max)).collect();
+        let mut rp: Vec<f32> = self.motors.iter().map(|m| m.pitch * t.pitch + m.roll * t.roll).collect();
+        let yaw: Vec<f32> = self.motors.iter().map(|m| m.yaw * t.yaw).collect();
+
+        let (rp_min, rp_max) = rp.iter().fold((0.0f32, 0.0f32), |(lo, hi), x| (lo.min(*x), hi.max(*x)));
+        if rp_max - rp_min > self.max {
+            let k = self.max / (rp_max - rp_min);
+            rp.iter_mut().for_each(|x| *x *= k);
+        }
+
+        // the shift of the collective keeping all motors in range
+        let lo = base.iter().zip(&rp).fold(f32::MIN, |lo, (b, x)| lo.max(-b - x));
+        let hi = base.iter().zip(&rp).fold(f32::MAX, |hi, (b, x)| hi.min(self.max - b - x));
+        let shift = if airmode { 0.0f32.max(lo).min(hi) } else { 0.0f32.min(hi) };
+        if lo > shift {
+            // the motors below zero limit roll and pitch
+            let k = base.iter().zip(&rp).fold(1.0f32, |k, (b, x)| if b + shift + x < 0.0 { k.min((b + shift) / -x) } else { k });
+            rp.iter_mut().for_each(|x| *x *= k.max(0.0));
+        }
+        let mut out: Vec<f32> = base.iter().zip(&rp).map(|(b, x)| b + shift + x).collect();
+
+        // the collective moves for yaw too, as long as roll and pitch still fit
+        let lo = out.iter().zip(&yaw).fold(f32::MIN, |lo, (u, y)| lo.max(-u - y));
+        let hi = out.iter().zip(&yaw).fold(f32::MAX, |hi, (u, y)| hi.min(self.max - u - y));
+        let shift = if lo <= hi { 0.0f32.max(lo).min(hi) } else { (lo + hi) / 2.0 };
+        let shift = if airmode { shift } else { shift.min(0.0) };
+        let (u_min, u_max) = out.iter().fold((f32::MAX, f32::MIN), |(lo, hi), u| (lo.min(*u), hi.max(*u)));
+        let shift = shift.min(self.max - u_max).max(-u_min);
+        out.iter_mut().for_each(|u| *u += shift);
+
+        let k = out.iter().zip(&yaw).fold(1.0f32, |k, (u, y)| {
+            if *y > 0.0 {
+                k.min((self.max - u) / y)
+            } else if *y < 0.0 {
+                k.min(u / -y)
+            } else {
+                k
+            }
+        });
+        out.iter().zip(&yaw).map(|(u, y)| (u + k.max(0.0) * y).clamp(0.0, self.max)).collect()
     }
 }
 
@@ -162,6 +315,16 @@ struct MixerChannel {
     ctrl_channel: ControlChannel,
 }
 
+impl MixerChannel {
+    fn new(ctrl_channel: ControlChannel, scale: f32, min: f32, max: f32) -> Self {
+        MixerChannel {
+            scaler: Scaler { scale_p: scale, scale_n: scale, offset: 0.0, min, max },
+            ctrl_group_id: 0,
+            ctrl_channel,
+        }
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 struct SumMixer {
     list: Vec<MixerChannel>,
@@ -211,16 +374,24 @@ pub unsafe fn init_mixer(argc: u32, argv: *const &str) {
     let mut mixer = Mixer {
         controller_outputs: Vec::new(),
         mixers: Vec::new(),
-        tx: get_new_tx_of_message("mixer_output").unwrap(),
+        multicopter: None,
+        tx: Publisher::new("mixer_output"),
+        airmode: Cell::new(None),
     };
 
+    // mixer [quad_x|quad_plus|hex_x|hex_plus|octo_x|octo_plus|tri|y6|<mixer json file>]
     if argc == 2 {
         let path = std::slice::from_raw_parts(argv, argc as usize);
-        println!("read mixer from {}.", path[1]);
-        mixer.read_mixers_info_from_file(path[1]).unwrap();
+        if let Some(geometry) = Geometry::from_name(path[1]) {
+            println!("use {:?} mixer!", geometry);
+            mixer.init_geometry_mixers(geometry);
+        } else {
+            println!("read mixer from {}.", path[1]);
+            mixer.read_mixers_info_from_file(path[1]).unwrap();
+        }
     } else if argc == 1 {
         println!("use default x quadcopter mixer!");
-        mixer.init_x_quadcopter_mixers();
+        mixer.init_geometry_mixers(Geometry::QuadX);
     } else {
         panic!("error arg num of mixer!");
     }
@@ -233,6 +404,8 @@ pub unsafe fn init_mixer(argc: u32, argv: *const &str) {
 
 #[rpos::ctor::ctor]
 fn register() {
+    // keep roll and pitch authority at zero throttle, the collective is raised for them
+    param::add_param("mc_airmode", ParameterData::Bool(false));
     rpos::module::Module::register("mixer", |a, b| unsafe { init_mixer(a, b) });
 }
 
@@ -243,6 +416,7 @@ mod tests {
     use crate::{mixer, msg_define::{EulerVector3, Vector3}};
 
     use super::*;
+    use rpos::msg::get_new_tx_of_message;
 
     #[test]
     fn test_init_mixer() {
@@ -252,6 +426,7 @@ mod tests {
             init_mixer(1, null_mut());
             assert!(rx.try_read().is_none());
             tx.send(TorqueThrustMsg {
+                header: MsgHeader::default(),
                 torques: EulerVector3{
                     pitch: 1.0,
                     roll: 0.0,
@@ -267,6 +442,171 @@ mod tests {
         }
     }
 
+    fn torque_thrust(pitch: f32, roll: f32, yaw: f32, thrust: f32) -> TorqueThrustMsg {
+        TorqueThrustMsg {
+            header: MsgHeader::default(),
+            torques: EulerVector3 { pitch, roll, yaw },
+            thrusts: Vector3 { x: 0.0, y: 0.0, z: thrust },
+        }
+    }
+
+    // [pitch, roll, yaw, thrust] on the frame from the motor outputs
+    fn geometry_mixer(geometry: Geometry) -> Mixer {
+        let mut mixer = Mixer {
+            controller_outputs: Vec::new(),
+            mixers: Vec::new(),
+            multicopter: None,
+            tx: Publisher::new("mixer_output"),
+            airmode: Cell::new(None),
+        };
+        mixer.init_geometry_mixers(geometry);
+        mixer
+    }
+
+    // without the torque limit, the demands beyond the motor range are tested
+    fn unlimited_mixer(geometry: Geometry) -> Mixer {
+        let mut mixer = geometry_mixer(geometry);
+        mixer.multicopter.as_mut().unwrap().torque_max = f32::INFINITY;
+        mixer
+    }
+
+    fn frame_torques(geometry: Geometry, msg: &TorqueThrustMsg, airmode: bool) -> [f32; 4] {
+        mixer_torques(&geometry_mixer(geometry), geometry, msg, airmode)
+    }
+
+    fn mixer_torques(mixer: &Mixer, geometry: Geometry, msg: &TorqueThrustMsg, airmode: bool) -> [f32; 4] {
+        let outputs = mixer.outputs(msg, airmode);
+        let mut sum = [0.0; 4];
+        for (r, out) in geometry.rotors().iter().zip(outputs) {
+            sum[0] += r.y() * out;
+            sum[1] -= r.x() * out;
+            sum[2] += r.yaw * out;
+            sum[3] += out;
+        }
+        sum
+    }
+
+    #[test]
+    fn test_geometry_mixers() {
+        let geometries = ["quad_x", "quad_plus", "hex_x", "hex_plus", "octo_x", "octo_plus", "tri", "y6"];
+        for geometry in geometries.map(|x| Geometry::from_name(x).unwrap()) {
+            // each axis moves the frame about that axis only
+            let cases = [
+                torque_thrust(10.0, 0.0, 0.0, 0.5),
+                torque_thrust(0.0, 10.0, 0.0, 0.5),
+                torque_thrust(0.0, 0.0, 10.0, 0.5),
+                torque_thrust(0.0, 0.0, 0.0, 0.5),
+            ];
+            let hover = frame_torques(geometry, &cases[3], false);
+            for (axis, msg) in cases.iter().enumerate() {
+                let sum = frame_torques(geometry, msg, false);
+                for i in 0..3 {
+                    let torque = sum[i] - hover[i];
+                    // the tail servo of tri does the yaw
+                    if i == axis && !(geometry == Geometry::Tri && i == 2) {
+                        assert!(torque > 1.0, "{:?} axis {}: {:?}", geometry, axis, sum);
+                    } else {
+                        assert!(torque.abs() < 1e-3, "{:?} axis {}: {:?}", geometry, axis, sum);
+                    }
+                }
+                // the torques don't change the collective
+                assert!((sum[3] - hover[3]).abs() < 1e-2, "{:?} axis {}: {:?}", geometry, axis, sum);
+            }
+        }
+        assert!(Geometry::from_name("gz_mixer.json").is_none());
+
+        // gazebo x3: motor 0 is front right and ccw
+        let outputs = geometry_mixer(Geometry::QuadX).outputs(&torque_thrust(1.0, 2.0, 4.0, 0.5), false);
+        assert_eq!(outputs[..4], [500.0 + 1.0 - 2.0 - 4.0, 500.0 - 1.0 + 2.0 - 4.0, 500.0 + 1.0 + 2.0 + 4.0, 500.0 - 1.0 - 2.0 + 4.0]);
+
+        let mixer = geometry_mixer(Geometry::Tri);
+        assert_eq!(mixer.mixers.len(), 1);
+        assert_eq!(mixer.outputs(&torque_thrust(0.0, 0.0, 1000.0, 0.5), false)[3], 2000.0);
+    }
+
+    #[test]
+    fn test_mixer_desaturation() {
+        for geometry in [Geometry::QuadX, Geometry::HexX, Geometry::OctoPlus, Geometry::Y6] {
+            // full throttle, too much for the range, and a demand out of the range itself
+            for msg in [torque_thrust(200.0, -100.0, 0.0, 1.0), torque_thrust(300.0, 150.0, 0.0, 0.95), torque_thrust(-3000.0, 1500.0, 0.0, 0.5)] {
+                let mixer = unlimited_mixer(geometry);
+                let outputs = mixer.outputs(&msg, false);
+                assert!(outputs.iter().all(|x| (0.0..=THRUST_SCALE).contains(x)), "{:?} {:?}", geometry, outputs);
+                let sum = mixer_torques(&mixer, geometry, &msg, false);
+                // the direction of the torque is kept
+                let ratio = msg.torques.pitch / msg.torques.roll;
+                assert!((sum[0] / sum[1] - ratio).abs() < 1e-3, "{:?} {:?}", geometry, sum);
+                assert!(sum[0] * msg.torques.pitch > 0.0);
+                // the collective goes down for it at full throttle
+                if msg.thrusts.z == 1.0 {
+                    assert!(sum[3] < THRUST_SCALE * geometry.rotors().len() as f32 - 1.0);
+                }
+            }
+
+            // roll and pitch first, then yaw
+            let mixer = unlimited_mixer(geometry);
+            let msg = torque_thrust(300.0, 0.0, 300.0, 0.8);
+            let sum = mixer_torques(&mixer, geometry, &msg, false);
+            let pitch_only = mixer_torques(&mixer, geometry, &torque_thrust(300.0, 0.0, 0.0, 0.8), false);
+            assert!((sum[0] - pitch_only[0]).abs() < 1e-2, "{:?} {:?} {:?}", geometry, sum, pitch_only);
+            assert!(sum[1].abs() < 1e-2);
+            assert!(sum[2] > 0.0 && sum[2] < 300.0 * geometry.rotors().len() as f32);
+        }
+    }
+
+    #[test]
+    fn test_torque_limit() {
+        // roll and pitch are scaled together, yaw is clamped
+        let sum = frame_torques(Geometry::QuadX, &torque_thrust(200.0, -100.0, 300.0, 0.5), false);
+        let limited = frame_torques(Geometry::QuadX, &torque_thrust(100.0, -50.0, 100.0, 0.5), false);
+        for i in 0..4 {
+            assert!((sum[i] - limited[i]).abs() < 1e-3, "{:?} {:?}", sum, limited);
+        }
+        let small = torque_thrust(30.0, -20.0, -10.0, 0.5);
+        assert_eq!(geometry_mixer(Geometry::QuadX).outputs(&small, false), unlimited_mixer(Geometry::QuadX).outputs(&small, false));
+    }
+
+    #[test]
+    fn test_gz_mixer_file() {
+        // the same outputs as the built-in quad_x
+        let mut mixer = geometry_mixer(Geometry::QuadX);
+        mixer.multicopter = None;
+        mixer.read_mixers_info_from_file("mixers/gz_mixer.json").unwrap();
+        for msg in [torque_thrust(1.0, 2.0, 4.0, 0.5), torque_thrust(300.0, -150.0, 80.0, 0.9)] {
+            let expected = geometry_mixer(Geometry::QuadX).outputs(&msg, false);
+            for (x, y) in mixer.outputs(&msg, false).iter().zip(expected) {
+                assert!((x - y).abs() < 1e-3, "{:?}", expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mixer_airmode() {
+        let msg = torque_thrust(0.0, 100.0, 50.0, 0.0);
+        // no torque at zero throttle without airmode
+        let outputs = geometry_mixer(Geometry::QuadX).outputs(&msg, false);
+        assert_eq!(outputs, [0.0; 8]);
+
+        // the param is read again after it is changed
+        let mixer = geometry_mixer(Geometry::QuadX);
+        assert!(!mixer.airmode());
+        param::set_param("mc_airmode", ParameterData::Bool(true)).unwrap();
+        assert!(mixer.airmode());
+        param::set_param("mc_airmode", ParameterData::Bool(false)).unwrap();
+        assert!(!mixer.airmode());
+
+        let hover = frame_torques(Geometry::QuadX, &torque_thrust(0.0, 100.0, 50.0, 0.5), false);
+        let sum = frame_torques(Geometry::QuadX, &msg, true);
+        assert!((sum[1] - hover[1]).abs() < 1e-2, "{:?} {:?}", sum, hover);
+        assert!((sum[2] - hover[2]).abs() < 1e-2);
+        assert!(sum[3] > 0.0 && sum[3] < hover[3]);
+
+        // low throttle without airmode, reduced but kept in the direction
+        let sum = frame_torques(Geometry::QuadX, &torque_thrust(40.0, 100.0, 0.0, 0.05), false);
+        assert!((sum[0] / sum[1] - 0.4).abs() < 1e-3);
+        assert!(sum[1] > 0.0 && sum[1] < hover[1]);
+    }
+
     // #[test]
     // fn test_mixer2toml() {
     //     unsafe {
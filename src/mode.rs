@@ -1,9 +1,69 @@
+use std::sync::atomic::{AtomicI32, Ordering};
 
+use clap::{Parser, ValueEnum};
+use rpos::thread_logln;
 
+// read by the control loops every cycle, never blocked by a writer
+static MODE: AtomicI32 = AtomicI32::new(FlightMode::Stabilize as i32);
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
 pub enum FlightMode{
     Manual,
     Stabilize,
     Altitude,
-    Position
+    Position,
+    Offboard,
+    Acro
+}
+
+impl FlightMode{
+    const ALL:[FlightMode;6] = [FlightMode::Manual, FlightMode::Stabilize, FlightMode::Altitude, FlightMode::Position, FlightMode::Offboard, FlightMode::Acro];
+
+    pub fn from_i32(index:i32)->Option<FlightMode>{
+        Self::ALL.get(usize::try_from(index).ok()?).copied()
+    }
+
+    pub fn current()->FlightMode{
+        // only set() stores it, always a valid index
+        Self::from_i32(MODE.load(Ordering::Acquire)).unwrap()
+    }
+
+    pub fn set(self){
+        MODE.store(self as i32, Ordering::Release);
+    }
+}
+
+#[derive(Parser, Clone)]
+#[command(name = "mode", about = "show or switch the flight mode")]
+struct Cli{
+    #[arg(value_enum)]
+    mode:Option<FlightMode>
+}
+
+fn mode_main(argc: u32, argv: *const &str){
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv){
+        if let Some(mode) = args.mode{
+            mode.set();
+        }
+        thread_logln!("flight mode:{:?}", FlightMode::current());
+    }
 }
 
+#[rpos::ctor::ctor]
+fn register(){
+    rpos::module::Module::register("mode", mode_main);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn test_flight_mode(){
+        for (index,mode) in FlightMode::ALL.iter().enumerate(){
+            assert_eq!(FlightMode::from_i32(index as i32), Some(*mode));
+        }
+        assert_eq!(FlightMode::from_i32(-1), None);
+        assert_eq!(FlightMode::from_i32(FlightMode::ALL.len() as i32), None);
+    }
+}
@@ -1,10 +1,99 @@
 #![allow(dead_code)]
-use std::ops::Index;
+use std::ops::{Deref, DerefMut, Index};
+use std::sync::atomic::{AtomicU32, Ordering};
 
-use rpos::msg::add_message;
+use rpos::{channel::Sender, msg::{add_message, get_new_tx_of_message}};
+
+// Common header of every bus message, filled in by Publisher::send
+#[derive(Debug,Clone,Copy,Default)]
+pub struct MsgHeader{
+    pub timestamp:u64, // hrt time, unit:us
+    pub seq:u32
+}
+
+pub trait StampedMsg{
+    fn header(&self)->&MsgHeader;
+    fn header_mut(&mut self)->&mut MsgHeader;
+}
+
+macro_rules! impl_stamped_msg {
+    ($($t:ty),*) => {
+        $(impl StampedMsg for $t{
+            fn header(&self)->&MsgHeader{
+                &self.header
+            }
+            fn header_mut(&mut self)->&mut MsgHeader{
+                &mut self.header
+            }
+        })*
+    };
+}
+
+// wrap a bare data type(Vector3, Vector4...) so that it could be published with a header
+#[derive(Debug,Clone,Copy,Default)]
+pub struct Stamped<T>{
+    pub header:MsgHeader,
+    pub data:T
+}
+
+impl<T> Stamped<T>{
+    pub fn new(data:T)->Self{
+        Stamped { header: MsgHeader::default(), data }
+    }
+}
+
+impl<T> Deref for Stamped<T>{
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl<T> DerefMut for Stamped<T>{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+impl<T> StampedMsg for Stamped<T>{
+    fn header(&self)->&MsgHeader{
+        &self.header
+    }
+    fn header_mut(&mut self)->&mut MsgHeader{
+        &mut self.header
+    }
+}
+
+// a Sender wrapper which stamps the hrt time and sequence number on each message
+pub struct Publisher<T>{
+    tx:Sender<T>,
+    seq:AtomicU32
+}
+
+impl<T:StampedMsg + Clone + 'static> Publisher<T>{
+    pub fn new(topic:&str)->Self{
+        Publisher {
+            tx: get_new_tx_of_message(topic).unwrap(),
+            seq: AtomicU32::new(0)
+        }
+    }
+
+    pub fn send(&self,mut msg:T){
+        let header = msg.header_mut();
+        header.timestamp = crate::basic::hrt_now_us();
+        header.seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(msg);
+    }
+}
+
+impl<T> Default for Publisher<T> where Sender<T>:Default{
+    fn default() -> Self {
+        Publisher { tx: Sender::default(), seq: AtomicU32::new(0) }
+    }
+}
 
 
-// Gyro/Acc message data, unit:rad/s
+// general 3-axis vector, used by sensor messages and setpoints
 #[derive(Debug,Clone,Copy,Default)]
 pub struct Vector3{
     pub x:f32,
@@ -20,8 +109,110 @@ pub struct Vector4{
     pub z:f32
 }
 
+// device id of the sensors which are not attached to a real bus(simulation, replay...)
+pub const SENSOR_DEVICE_ID_SIM:u32 = 1;
+
+#[derive(Debug,Clone,Copy,Default)]
+pub struct SensorGyroMsg{
+    pub header:MsgHeader,
+    pub device_id:u32,
+    pub temperature:f32, // unit:degC, NAN if the sensor does not provide it
+    pub error_count:u32,
+    pub samples:u8, // raw samples averaged into this message
+    pub rate:Vector3, // unit:rad/s
+    pub delta_angle:Vector3, // unit:rad, integrated over delta_dt
+    pub delta_dt:u32 // unit:us
+}
+
+#[derive(Debug,Clone,Copy,Default)]
+pub struct SensorAccelMsg{
+    pub header:MsgHeader,
+    pub device_id:u32,
+    pub temperature:f32, // unit:degC, NAN if the sensor does not provide it
+    pub error_count:u32,
+    pub samples:u8,
+    pub clip_count:[u8;3], // samples hitting the range limit on each axis
+    pub acc:Vector3, // unit:m/s^2
+    pub delta_velocity:Vector3, // unit:m/s, integrated over delta_dt
+    pub delta_dt:u32 // unit:us
+}
+
+#[derive(Debug,Clone,Copy,Default)]
+pub struct SensorMagMsg{
+    pub header:MsgHeader,
+    pub device_id:u32,
+    pub temperature:f32, // unit:degC, NAN if the sensor does not provide it
+    pub error_count:u32,
+    pub mag:Vector3 // unit:gauss, calibrated, in body frame
+}
+
+#[derive(Debug,Clone,Copy,Default)]
+pub struct SensorBaroMsg{
+    pub header:MsgHeader,
+    pub device_id:u32,
+    pub temperature:f32, // unit:degC, NAN if the sensor does not provide it
+    pub error_count:u32,
+    pub pressure:f32 // unit:Pa
+}
+
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum GpsFixType{
+    #[default]
+    NoFix,
+    Fix2D,
+    Fix3D,
+    Dgps,
+    RtkFloat,
+    RtkFixed
+}
+
+#[derive(Debug,Clone,Copy,Default)]
+pub struct GpsMsg{
+    pub header:MsgHeader,
+    pub device_id:u32,
+    pub error_count:u32,
+    pub time_utc_us:u64, // unix time, 0 if unknown
+    pub fix_type:GpsFixType,
+    pub lat:f64, // unit:degree
+    pub lon:f64, // unit:degree
+    pub alt:f32, // unit:m, above mean sea level
+    pub vel_n:f32, // unit:m/s
+    pub vel_e:f32,
+    pub vel_d:f32,
+    pub h_acc:f32, // unit:m
+    pub v_acc:f32, // unit:m
+    pub s_acc:f32, // unit:m/s
+    pub satellites:u8
+}
+
+// vertical state estimated from baro and accelerometer
+#[derive(Debug,Clone,Copy,Default)]
+pub struct VehicleAltitudeMsg{
+    pub header:MsgHeader,
+    pub alt:f32, // unit:m, above the home(where the estimator starts)
+    pub vz:f32, // unit:m/s, positive up
+    pub baro_alt:f32, // unit:m, raw baro altitude above home
+    pub acc_bias:f32 // unit:m/s^2, estimated bias of the vertical acceleration
+}
+
+// local position in NED(north, east, down) frame, the origin is the home position.
+// note: the attitude is in the ENU world frame(docs/axis.md), the controllers do the conversion.
+#[derive(Debug,Clone,Copy,Default)]
+pub struct LocalPositionMsg{
+    pub header:MsgHeader,
+    pub x:f32, // unit:m, north
+    pub y:f32, // unit:m, east
+    pub z:f32, // unit:m, down
+    pub vx:f32, // unit:m/s
+    pub vy:f32,
+    pub vz:f32,
+    pub xy_valid:bool,
+    pub z_valid:bool
+}
+
 #[derive(Debug,Clone,Copy)]
 pub struct AttitudeSetPointMsg{
+    pub header:MsgHeader,
     pub attitude:Vector4, // quaternion 
     pub body_thrusts:Vector3 // [-1,1]
 }
@@ -60,29 +251,141 @@ impl Index<usize> for Vector3{
 
 #[derive(Debug,Clone,Copy)]
 pub struct TorqueThrustMsg{
+    pub header:MsgHeader,
     pub torques:EulerVector3,
     pub thrusts:Vector3
 }
 
-// will used in rate controller
+// setpoint of an external computer(mavlink offboard), converted to the frames of docs/axis.md
+#[derive(Debug,Clone,Copy)]
+pub enum OffboardSetpoint{
+    Attitude{attitude:Vector4, thrust:f32}, // thrust:[0,1]
+    Position{pos:Option<[f32;3]>, vel:Option<[f32;3]>, yaw:Option<f32>}, // NED like LocalPositionMsg, yaw:rad, positive to the east
+    Torque{torques:EulerVector3, thrust:f32} // [-1,1], directly to the mixer
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct OffboardSetpointMsg{
+    pub header:MsgHeader,
+    pub setpoint:OffboardSetpoint
+}
+
+// the position target being tracked, NED
+#[derive(Debug,Clone,Copy,Default)]
+pub struct PositionSetpointMsg{
+    pub header:MsgHeader,
+    pub x:f32, // unit:m
+    pub y:f32,
+    pub z:f32,
+    pub vx:f32, // unit:m/s
+    pub vy:f32,
+    pub vz:f32,
+    pub yaw:f32 // unit:rad, 0 to the north, positive to the east(NED)
+}
+
+// target of the rate controller
+#[derive(Debug,Clone,Copy)]
 pub struct RateSetPointMsg{
-    pub angle_rate:EulerVector3,
-    pub thrusts:Vector3
+    pub header:MsgHeader,
+    pub angle_rate:EulerVector3, // unit:rad/s, around the body axes
+    pub thrusts:Vector3 // [0,1]
 }
 
 // Manual Control Input
 #[allow(dead_code)]
 #[derive(Debug,Clone)]
 pub struct ManualControlMsg{
+    pub header:MsgHeader,
     pub pitch:u32,
     pub roll:u32,
     pub thrust:u32,
     pub direction:u32
 }
 
+pub const RC_INPUT_MAX_CHANNELS:usize = 16;
+
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub enum RcSource{
+    #[default]
+    Unknown,
+    Crsf,
+    Mavlink,
+    Sbus,
+    Ibus,
+    Srxl2,
+    Keyboard,
+    Joystick
+}
+
+// raw values from the receiver, rc_update calibrates them into RcChannelsMsg
 #[derive(Debug,Clone)]
 pub struct RcInputMsg{
-    pub channel_vals:[i16;8]   // -1000~1000
+    pub header:MsgHeader,
+    pub source:RcSource,
+    pub channel_count:u8, // the channels after it are not provided by the receiver
+    pub failsafe:bool, // the receiver lost the link, the values are not from the pilot
+    pub frame_lost:bool, // the receiver missed some frames from the transmitter, the link is weak
+    pub channel_vals:[i16;RC_INPUT_MAX_CHANNELS]   // unit:us, 1000~2000 nominal
+}
+
+// the functions of the rc channels, index of RcChannelsMsg::functions
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum RcFunction{
+    Roll,
+    Pitch,
+    Throttle,
+    Yaw,
+    Mode,
+    Arm,
+    Kill
+}
+
+impl RcFunction{
+    pub const COUNT:usize = 7;
+    pub const ALL:[RcFunction;Self::COUNT] = [RcFunction::Roll, RcFunction::Pitch, RcFunction::Throttle, RcFunction::Yaw,
+        RcFunction::Mode, RcFunction::Arm, RcFunction::Kill];
+}
+
+// link state of the rc receiver, the failsafe could use it
+#[derive(Debug,Clone,Copy,Default)]
+pub struct RcLinkQualityMsg{
+    pub header:MsgHeader,
+    pub source:RcSource,
+    pub rssi:i16, // unit:dBm, the active antenna
+    pub link_quality:u8, // unit:%, 0 if the link is lost
+    pub snr:i8, // unit:dB
+    pub downlink_link_quality:u8 // unit:%, the telemetry link
+}
+
+// state of the rc receiver connection, published by the rc input drivers
+#[derive(Debug,Clone,Copy,Default)]
+pub struct RcHealthMsg{
+    pub header:MsgHeader,
+    pub source:RcSource,
+    pub connected:bool, // the serial port is open
+    pub signal:bool, // frames are received in the rc timeout
+    pub failsafe:bool, // the receiver or the timeout reports failsafe
+    pub frame_count:u32,
+    pub crc_errors:u32,
+    pub discarded_bytes:u32, // the bytes not in any valid frame
+    pub reconnects:u32
+}
+
+// calibrated rc channels
+#[derive(Debug,Clone,Copy)]
+pub struct RcChannelsMsg{
+    pub header:MsgHeader,
+    pub source:RcSource,
+    pub failsafe:bool,
+    pub channels:[f32;RC_INPUT_MAX_CHANNELS], // [-1,1], NAN if not provided
+    pub functions:[f32;RcFunction::COUNT] // [-1,1], NAN if the function is not mapped or not provided
+}
+
+impl RcChannelsMsg{
+    pub fn get(&self,function:RcFunction)->Option<f32>{
+        let x = self.functions[function as usize];
+        if x.is_nan(){ None } else { Some(x) }
+    }
 }
 
 #[allow(dead_code)]
@@ -90,25 +393,71 @@ impl ManualControlMsg{
     pub const MAX:u32 = 10000;
 }
 
+#[derive(Debug,Clone,Copy,Default)]
+pub struct BatteryStatusMsg{
+    pub header:MsgHeader,
+    pub voltage:f32, // unit:V
+    pub current:f32, // unit:A
+    pub used_mah:f32, // unit:mAh
+    pub remaining:f32 // [0,1], negative if unknown
+}
+
 #[derive(Debug,Clone)]
 pub struct MixerOutputMsg{
+    pub header:MsgHeader,
     pub control_group_id:u8,
     pub output:[f32;8],
 }
 
+impl_stamped_msg!(
+    SensorGyroMsg,
+    SensorAccelMsg,
+    SensorMagMsg,
+    SensorBaroMsg,
+    VehicleAltitudeMsg,
+    GpsMsg,
+    LocalPositionMsg,
+    AttitudeSetPointMsg,
+    OffboardSetpointMsg,
+    PositionSetpointMsg,
+    TorqueThrustMsg,
+    RateSetPointMsg,
+    ManualControlMsg,
+    RcInputMsg,
+    RcChannelsMsg,
+    RcLinkQualityMsg,
+    RcHealthMsg,
+    BatteryStatusMsg,
+    MixerOutputMsg
+);
 
 #[rpos::ctor::ctor]
 fn register_msgs(){
-    add_message::<Vector3>("gyro");
-    add_message::<Vector3>("acc");
-    add_message::<Vector4>("attitude");
+    add_message::<SensorGyroMsg>("gyro");
+    add_message::<SensorAccelMsg>("acc");
+    add_message::<SensorMagMsg>("mag");
+    add_message::<SensorBaroMsg>("baro");
+    add_message::<VehicleAltitudeMsg>("vehicle_altitude");
+    add_message::<VehicleAltitudeMsg>("vehicle_altitude_groundtruth");
+    add_message::<GpsMsg>("gps");
+    add_message::<LocalPositionMsg>("vehicle_local_position");
+    add_message::<LocalPositionMsg>("vehicle_local_position_groundtruth");
+    add_message::<Stamped<Vector4>>("attitude");
+    add_message::<Stamped<Vector4>>("attitude_groundtruth");
     //add_message::<EulerVector3>("att_target_euler");
     add_message::<AttitudeSetPointMsg>("att_target");
+    add_message::<RateSetPointMsg>("rate_target");
     add_message::<TorqueThrustMsg>("toreque_thrust_setpoint");
+    add_message::<OffboardSetpointMsg>("offboard_setpoint");
+    add_message::<PositionSetpointMsg>("position_target");
     //add_message::<ControllerOutputGroupMsg>("controller_output0");
     //add_message::<ControllerOutputGroupMsg>("controller_output1");
     add_message:: <MixerOutputMsg>("mixer_output");
     add_message::<ManualControlMsg>("manual_control");
     add_message::<RcInputMsg>("rc_input");
+    add_message::<RcChannelsMsg>("rc_channels");
+    add_message::<RcLinkQualityMsg>("rc_link_quality");
+    add_message::<RcHealthMsg>("rc_health");
+    add_message::<BatteryStatusMsg>("battery_status");
 }
 
@@ -22,6 +22,15 @@ fn msg_echo_main(argc:u32, argv:*const &str){
             let mut rx = get_new_rx_of_message::<RcInputMsg>(&args.topic).unwrap();
             func = Box::new(move ||{thread_logln!("{:?}",rx.read());});
             
+        }else if args.topic == "rc_channels"{
+            let mut rx = get_new_rx_of_message::<RcChannelsMsg>(&args.topic).unwrap();
+            func = Box::new(move ||{thread_logln!("{:?}",rx.read());});
+        }else if args.topic == "rc_link_quality"{
+            let mut rx = get_new_rx_of_message::<RcLinkQualityMsg>(&args.topic).unwrap();
+            func = Box::new(move ||{thread_logln!("{:?}",rx.read());});
+        }else if args.topic == "rc_health"{
+            let mut rx = get_new_rx_of_message::<RcHealthMsg>(&args.topic).unwrap();
+            func = Box::new(move ||{thread_logln!("{:?}",rx.read());});
         }else if args.topic == "mixer_output"{
             let mut rx = Box::new(get_new_rx_of_message::<MixerOutputMsg>(&args.topic).unwrap()); 
             func = Box::new(move ||{thread_logln!("{:?}",rx.read());});
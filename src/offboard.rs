@@ -0,0 +1,288 @@
+use std::{
+    ffi::c_void,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use quaternion_core::{point_rotation, Quaternion as Q};
+use rpos::{libc::c_long, msg::get_new_rx_of_message, pthread_scheduler::SchedulePthread, thread_logln};
+
+use crate::{
+    alt_control::AltCtrlParams,
+    mode::FlightMode,
+    msg_define::{
+        AttitudeSetPointMsg, EulerVector3, LocalPositionMsg, MsgHeader, OffboardSetpoint, OffboardSetpointMsg,
+        PositionSetpointMsg, Publisher, SensorAccelMsg, Stamped, TorqueThrustMsg, Vector3, Vector4,
+    },
+    param::{self, CachedParams, ParameterData},
+    pos_control::{PosController, PosCtrlParams, PosState},
+};
+
+const GRAVITY: f32 = 9.80665;
+// default of obd_timeout
+const OFFBOARD_TIMEOUT_US: u64 = 500_000;
+
+// set when the offboard torques go to the mixer directly, att_control stays quiet
+static TORQUE_DIRECT: AtomicBool = AtomicBool::new(false);
+
+pub fn torque_direct() -> bool {
+    TORQUE_DIRECT.load(Ordering::Relaxed)
+}
+
+pub enum OffboardOutput {
+    Attitude { attitude: Q<f32>, thrust: f32 },
+    Torque { torques: EulerVector3, thrust: f32 },
+}
+
+/*
+    follows the setpoint stream of an external computer, the stream is lost when no setpoint
+    is received in the timeout, then update() returns None and the caller switches to the failsafe mode.
+*/
+pub struct Offboard {
+    pub ctrl: PosController,
+    pub timeout_us: u64,
+    setpoint: Option<OffboardSetpointMsg>,
+    tracking_position: bool,
+}
+
+impl Offboard {
+    pub fn new(ctrl: PosController, timeout_us: u64) -> Self {
+        Offboard { ctrl, timeout_us, setpoint: None, tracking_position: false }
+    }
+
+    // called when the mode is switched in
+    pub fn reset(&mut self) {
+        self.tracking_position = false;
+    }
+
+    pub fn set_setpoint(&mut self, msg: OffboardSetpointMsg) {
+        self.setpoint = Some(msg);
+    }
+
+    // state is None if the local position is not valid
+    pub fn update(&mut self, now: u64, state: Option<&PosState>, dt: f32) -> Option<OffboardOutput> {
+        let msg = self.setpoint.as_ref()?;
+        if now.saturating_sub(msg.header.timestamp) > self.timeout_us {
+            return None;
+        }
+        match msg.setpoint {
+            OffboardSetpoint::Attitude { attitude, thrust } => {
+                self.tracking_position = false;
+                Some(OffboardOutput::Attitude { attitude: (attitude.w, [attitude.x, attitude.y, attitude.z]), thrust })
+            }
+            OffboardSetpoint::Torque { torques, thrust } => {
+                self.tracking_position = false;
+                Some(OffboardOutput::Torque { torques, thrust })
+            }
+            OffboardSetpoint::Position { pos, vel, yaw } => {
+                let state = state?;
+                if !self.tracking_position {
+                    self.ctrl.reset(state);
+                    self.tracking_position = true;
+                }
+                let sp = self.ctrl.update_target(pos, vel, yaw, state, dt);
+                Some(OffboardOutput::Attitude { attitude: sp.attitude, thrust: sp.thrust })
+            }
+        }
+    }
+
+    // the position target being tracked, NED
+    pub fn position_target(&self) -> Option<PositionSetpointMsg> {
+        if !self.tracking_position {
+            return None;
+        }
+        let vel = match self.setpoint?.setpoint {
+            OffboardSetpoint::Position { vel, .. } => vel.unwrap_or([0.0; 3]),
+            _ => [0.0; 3],
+        };
+        Some(PositionSetpointMsg {
+            header: MsgHeader::default(),
+            x: self.ctrl.pos_sp[0],
+            y: self.ctrl.pos_sp[1],
+            z: -self.ctrl.alt.alt_sp,
+            vx: vel[0],
+            vy: vel[1],
+            vz: vel[2],
+            yaw: -self.ctrl.yaw_sp,
+        })
+    }
+}
+
+fn offboard_main(ptr: *mut c_void) -> *mut c_void {
+    let sp = unsafe { Arc::from_raw(ptr as *const SchedulePthread) };
+    let mut setpoint_rx = get_new_rx_of_message::<OffboardSetpointMsg>("offboard_setpoint").unwrap();
+    let mut pos_rx = get_new_rx_of_message::<LocalPositionMsg>("vehicle_local_position").unwrap();
+    let mut pos_gt_rx = get_new_rx_of_message::<LocalPositionMsg>("vehicle_local_position_groundtruth").unwrap();
+    let mut acc_rx = get_new_rx_of_message::<SensorAccelMsg>("acc").unwrap();
+    let mut att_rx = get_new_rx_of_message::<Stamped<Vector4>>("attitude").unwrap();
+    let att_target_tx = Publisher::<AttitudeSetPointMsg>::new("att_target");
+    let torque_tx = Publisher::<TorqueThrustMsg>::new("toreque_thrust_setpoint");
+    let pos_target_tx = Publisher::<PositionSetpointMsg>::new("position_target");
+
+    const OFFBOARD_PERIOD_US: c_long = 4000;
+    let dt = OFFBOARD_PERIOD_US as f32 / 1000_000.0;
+
+    // the last value is kept while the parameter is being written
+    let timeout_us = |last: u64| param::get_param("obd_timeout").map_or(last, |x| (x.as_f32() * 1000_000.0) as u64);
+    let mut params = CachedParams::new(PosCtrlParams::from_params, PosCtrlParams::default());
+    let mut alt_params = CachedParams::new(AltCtrlParams::from_params, AltCtrlParams::default());
+    let mut offboard = Offboard::new(
+        PosController::new(*params.value(), *alt_params.value()),
+        timeout_us(OFFBOARD_TIMEOUT_US),
+    );
+    let mut state = PosState { pos: [0.0; 3], vel: [0.0; 3], q: (1.0, [0.0; 3]), acc_up: 0.0 };
+    let mut valid = false;
+    let mut active = false;
+    let mut cnt: u32 = 0;
+
+    loop {
+        if let Some(msg) = setpoint_rx.try_read() {
+            offboard.set_setpoint(msg);
+        }
+        if let Some(att) = att_rx.try_read() {
+            state.q = (att.w, [att.x, att.y, att.z]);
+        }
+        if let Some(acc) = acc_rx.try_read() {
+            state.acc_up = point_rotation(state.q, [acc.acc.x, acc.acc.y, acc.acc.z])[2] - GRAVITY;
+        }
+        let pos_msg = if param::get_param("pos_use_gt").is_some_and(|x| x.as_bool()) {
+            pos_gt_rx.try_read()
+        } else {
+            pos_rx.try_read()
+        };
+        if let Some(pos) = pos_msg {
+            state.pos = [pos.x, pos.y, pos.z];
+            state.vel = [pos.vx, pos.vy, pos.vz];
+            valid = pos.xy_valid && pos.z_valid;
+        }
+
+        cnt += 1;
+        if cnt % 250 == 0 {
+            if params.update() | alt_params.update() {
+                offboard.ctrl.set_params(*params.value(), *alt_params.value());
+            }
+            offboard.timeout_us = timeout_us(offboard.timeout_us);
+        }
+
+        let mut torque_direct = false;
+        if FlightMode::current() == FlightMode::Offboard {
+            if !active {
+                offboard.reset();
+                active = true;
+            }
+            match offboard.update(crate::basic::hrt_now_us(), valid.then_some(&state), dt) {
+                Some(OffboardOutput::Attitude { attitude, thrust }) => {
+                    att_target_tx.send(AttitudeSetPointMsg {
+                        header: MsgHeader::default(),
+                        attitude: Vector4 { w: attitude.0, x: attitude.1[0], y: attitude.1[1], z: attitude.1[2] },
+                        body_thrusts: Vector3 { x: 0.0, y: 0.0, z: thrust },
+                    });
+                }
+                Some(OffboardOutput::Torque { torques, thrust }) => {
+                    torque_direct = true;
+                    torque_tx.send(TorqueThrustMsg {
+                        header: MsgHeader::default(),
+                        torques,
+                        thrusts: Vector3 { x: 0.0, y: 0.0, z: thrust },
+                    });
+                }
+                None => {
+                    let mode = param::get_param("obd_fs_mode")
+                        .and_then(|x| FlightMode::from_i32(x.as_i32()))
+                        .filter(|x| *x != FlightMode::Offboard)
+                        .unwrap_or(FlightMode::Altitude);
+                    thread_logln!("offboard setpoint lost, switch to {:?}", mode);
+                    mode.set();
+                }
+            }
+            if let Some(target) = offboard.position_target() {
+                pos_target_tx.send(target);
+            }
+        } else {
+            active = false;
+        }
+        TORQUE_DIRECT.store(torque_direct, Ordering::Relaxed);
+        sp.schedule_until(OFFBOARD_PERIOD_US);
+    }
+    #[allow(unreachable_code)]
+    null_mut()
+}
+
+pub fn init_offboard(_argc: u32, _argv: *const &str) {
+    SchedulePthread::new(1024 * 1024, 96, offboard_main, null_mut(), false);
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    param::add_param("obd_timeout", ParameterData::Float(OFFBOARD_TIMEOUT_US as f32 / 1000_000.0));
+    param::add_param("obd_fs_mode", ParameterData::Int(FlightMode::Altitude as i32));
+    rpos::module::Module::register("offboard", init_offboard);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_offboard() -> Offboard {
+        Offboard::new(PosController::new(PosCtrlParams::default(), AltCtrlParams::default()), 500_000)
+    }
+
+    fn setpoint_at(timestamp: u64, setpoint: OffboardSetpoint) -> OffboardSetpointMsg {
+        OffboardSetpointMsg { header: MsgHeader { timestamp, seq: 0 }, setpoint }
+    }
+
+    #[test]
+    fn test_offboard_timeout() {
+        let mut offboard = new_offboard();
+        // no stream yet
+        assert!(offboard.update(0, None, 0.004).is_none());
+
+        let attitude = Vector4 { w: 0.5f32.sqrt(), x: 0.0, y: 0.0, z: 0.5f32.sqrt() };
+        offboard.set_setpoint(setpoint_at(1_000_000, OffboardSetpoint::Attitude { attitude, thrust: 0.6 }));
+        match offboard.update(1_400_000, None, 0.004) {
+            Some(OffboardOutput::Attitude { attitude, thrust }) => {
+                assert_eq!(thrust, 0.6);
+                assert!((attitude.1[2] - 0.5f32.sqrt()).abs() < 1e-6);
+            }
+            _ => panic!("expect an attitude output"),
+        }
+        assert!(offboard.update(1_500_001, None, 0.004).is_none());
+
+        // the stream comes back
+        let torques = EulerVector3 { pitch: 0.1, roll: -0.2, yaw: 0.3 };
+        offboard.set_setpoint(setpoint_at(1_600_000, OffboardSetpoint::Torque { torques, thrust: 0.4 }));
+        match offboard.update(1_600_000, None, 0.004) {
+            Some(OffboardOutput::Torque { torques, thrust }) => {
+                assert_eq!((torques.pitch, torques.roll, torques.yaw, thrust), (0.1, -0.2, 0.3, 0.4));
+            }
+            _ => panic!("expect a torque output"),
+        }
+        assert!(offboard.position_target().is_none());
+    }
+
+    #[test]
+    fn test_offboard_position() {
+        let mut offboard = new_offboard();
+        let state = PosState { pos: [1.0, 2.0, -5.0], vel: [0.0; 3], q: (1.0, [0.0; 3]), acc_up: 0.0 };
+        let setpoint = OffboardSetpoint::Position { pos: Some([1.0, 7.0, -5.0]), vel: None, yaw: Some(0.5) };
+        offboard.set_setpoint(setpoint_at(0, setpoint));
+
+        // no valid local position
+        assert!(offboard.update(0, None, 0.004).is_none());
+
+        match offboard.update(0, Some(&state), 0.004) {
+            // accelerate to the east: the body z tilts to the east
+            Some(OffboardOutput::Attitude { attitude, .. }) => {
+                let z = point_rotation(attitude, [0.0, 0.0, 1.0]);
+                assert!(z[0] > 0.0 && z[1].abs() < 1e-6);
+            }
+            _ => panic!("expect an attitude output"),
+        }
+        let target = offboard.position_target().unwrap();
+        assert_eq!((target.x, target.y, target.z), (1.0, 7.0, -5.0));
+        assert!((target.yaw - 0.5).abs() < 1e-6);
+    }
+}
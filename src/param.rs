@@ -1,6 +1,9 @@
 #![allow(dead_code)]
 use core::panic;
-use std::sync::LazyLock;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    LazyLock,
+};
 
 use clap::Args;
 use dashmap::DashMap;
@@ -76,10 +79,62 @@ struct Cli {
 
 static PARAMS: LazyLock<DashMap<String, Parameter>> = LazyLock::new(|| DashMap::new());
 
+// counts the changes, the users cache the values and read them again when it moves
+static VERSION: AtomicU32 = AtomicU32::new(0);
+
 pub fn get_params_map() -> &'static DashMap<String, Parameter> {
     &PARAMS
 }
 
+pub fn version() -> u32 {
+    VERSION.load(Ordering::Acquire)
+}
+
+/*
+    the params of a module read together, eg. the gains of a controller.
+    read again only after a param is changed, the old values are kept while one is being written.
+*/
+pub struct CachedParams<T> {
+    read: fn() -> Option<T>,
+    version: u32,
+    value: T,
+}
+
+impl<T> CachedParams<T> {
+    // the default is used until the params can be read
+    pub fn new(read: fn() -> Option<T>, default: T) -> Self {
+        let mut params = CachedParams { read, version: version().wrapping_sub(1), value: default };
+        params.update();
+        params
+    }
+
+    // true if the values are read again
+    pub fn update(&mut self) -> bool {
+        let current = version();
+        if current == self.version {
+            return false;
+        }
+        match (self.read)() {
+            Some(value) => {
+                self.value = value;
+                self.version = current;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&mut self) -> &T {
+        self.update();
+        &self.value
+    }
+
+    // without checking the version
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+}
+
 pub fn get_param(name: &str) -> Option<ParameterData> {
     if let Some(parameter) = PARAMS.try_get(name).try_unwrap() {
         Some(parameter.get_data())
@@ -91,6 +146,9 @@ pub fn get_param(name: &str) -> Option<ParameterData> {
 pub fn reset_param(name: &str) -> Result<(), ()> {
     if let Some(mut x) = PARAMS.try_get_mut(name).try_unwrap() {
         x.data = None;
+        // after the entry is unlocked, so the new value is read
+        drop(x);
+        VERSION.fetch_add(1, Ordering::AcqRel);
         Ok(())
     } else {
         Err(())
@@ -100,6 +158,8 @@ pub fn reset_param(name: &str) -> Result<(), ()> {
 pub fn set_param(name: &str, val: ParameterData) -> Result<(), ()> {
     if let Some(mut x) = PARAMS.try_get_mut(name).try_unwrap() {
         x.data = Some(val);
+        drop(x);
+        VERSION.fetch_add(1, Ordering::AcqRel);
         Ok(())
     } else {
         Err(())
@@ -115,6 +175,7 @@ pub fn add_param(name: &str, default: ParameterData) {
             default,
         },
     );
+    VERSION.fetch_add(1, Ordering::AcqRel);
 }
 
 fn param_main(_argc: u32, _argv: *const &str) {
@@ -166,6 +227,8 @@ fn register() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::AtomicBool;
+
     #[test]
     fn test_option_ptr() {
         let mut x: Option<i32> = Some(0);
@@ -200,6 +263,31 @@ mod tests {
         assert!(val.as_f32() < 1.0001);
     }
 
+    #[test]
+    fn test_cached_params() {
+        // a writer holding the entry
+        static LOCKED: AtomicBool = AtomicBool::new(false);
+        add_param("cached_test", ParameterData::Float(1.0));
+        let read = || get_param("cached_test").filter(|_| !LOCKED.load(Ordering::Acquire)).map(|x| x.as_f32());
+        let mut params = CachedParams::new(read, 0.0);
+        assert_eq!(*params.get(), 1.0);
+
+        set_param("cached_test", ParameterData::Float(2.0)).unwrap();
+        assert!(params.update());
+        assert_eq!(*params.value(), 2.0);
+
+        // the old value is kept while it is being written
+        LOCKED.store(true, Ordering::Release);
+        set_param("cached_test", ParameterData::Float(3.0)).unwrap();
+        assert!(!params.update());
+        assert_eq!(*params.get(), 2.0);
+        LOCKED.store(false, Ordering::Release);
+        assert_eq!(*params.get(), 3.0);
+
+        // absent, the default
+        assert_eq!(*CachedParams::new(|| get_param("undefined").map(|x| x.as_f32()), 4.0).get(), 4.0);
+    }
+
     #[test]
     fn test_key_from_c() {
         add_param("ctest", ParameterData::Bool(true));
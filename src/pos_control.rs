@@ -0,0 +1,494 @@
+use std::{ffi::c_void, ptr::null_mut, sync::Arc};
+
+use quaternion_core::{point_rotation, Quaternion as Q};
+use rpos::{libc::c_long, msg::get_new_rx_of_message, pthread_scheduler::SchedulePthread, thread_logln};
+
+use crate::{
+    alt_control::{apply_deadband, AltController, AltCtrlParams, AltState},
+    basic::{pid::PIDController, rotation::get_yaw},
+    manual_ctrl::{get_sticks, ManualParams, StickAttitude},
+    mode::FlightMode,
+    msg_define::{
+        AttitudeSetPointMsg, LocalPositionMsg, MsgHeader, Publisher, RcChannelsMsg, SensorAccelMsg, Stamped, Vector3,
+        Vector4,
+    },
+    param::{self, CachedParams, ParameterData},
+};
+
+const GRAVITY: f32 = 9.80665;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PosCtrlParams {
+    pub vel_max: f32,  // m/s, horizontal speed at full stick
+    pub deadband: f32, // [0,1)
+    pub pos_kp: f32,   // position error to velocity, 1/s
+    pub vel_kp: f32,   // velocity error to acceleration
+    pub vel_ki: f32,
+    pub vel_kd: f32,
+    pub tilt_max: f32, // rad
+    pub yaw_rate: f32, // rad/s at full stick
+}
+
+impl Default for PosCtrlParams {
+    fn default() -> Self {
+        PosCtrlParams {
+            vel_max: 5.0,
+            deadband: 0.1,
+            pos_kp: 1.0,
+            vel_kp: 2.0,
+            vel_ki: 0.5,
+            vel_kd: 0.0,
+            tilt_max: 35.0f32.to_radians(),
+            yaw_rate: 90.0f32.to_radians(),
+        }
+    }
+}
+
+impl PosCtrlParams {
+    // None if a param is being written
+    pub fn from_params() -> Option<Self> {
+        let get = |name: &str| param::get_param(name).map(|x| x.as_f32());
+        Some(PosCtrlParams {
+            vel_max: get("pos_vel_max")?,
+            deadband: get("pos_deadband")?,
+            pos_kp: get("pos_kp")?,
+            vel_kp: get("pos_vel_kp")?,
+            vel_ki: get("pos_vel_ki")?,
+            vel_kd: get("pos_vel_kd")?,
+            tilt_max: get("pos_tilt_max")?.to_radians(),
+            yaw_rate: get("pos_yaw_rate")?.to_radians(),
+        })
+    }
+}
+
+// the attitude whose body z points along the thrust direction(ENU world), with the given yaw.
+pub fn thrust_dir_to_attitude(thrust_dir: [f32; 3], yaw: f32) -> Q<f32> {
+    let q_yaw = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], yaw);
+    let z = quaternion_core::normalize(thrust_dir);
+    let axis = quaternion_core::cross([0.0, 0.0, 1.0], z);
+    if quaternion_core::norm(axis) < 1e-6 {
+        return q_yaw;
+    }
+    let q_tilt = quaternion_core::from_axis_angle(quaternion_core::normalize(axis), z[2].clamp(-1.0, 1.0).acos());
+    quaternion_core::mul(q_tilt, q_yaw)
+}
+
+pub struct PosState {
+    pub pos: [f32; 3], // NED, m
+    pub vel: [f32; 3], // NED, m/s
+    pub q: Q<f32>,     // attitude
+    pub acc_up: f32,   // m/s^2, vertical acceleration without gravity
+}
+
+pub struct PosSetpoint {
+    pub attitude: Q<f32>,
+    pub thrust: f32,
+}
+
+/*
+    horizontal: position -> velocity -> acceleration -> tilt, the sticks command the velocity
+    in the heading frame and the position is held when they are centred.
+    vertical: the same as the altitude mode.
+*/
+pub struct PosController {
+    pub params: PosCtrlParams,
+    pub pos_sp: [f32; 2], // north, east
+    pub yaw_sp: f32,
+    pub alt: AltController,
+    vel_pid: [PIDController; 2],
+    // flown without a valid position, as altitude or stabilize mode
+    stick_att: StickAttitude,
+}
+
+impl PosController {
+    pub fn new(params: PosCtrlParams, alt_params: AltCtrlParams) -> Self {
+        let vel_pid = [0, 1].map(|_| {
+            let mut pid = PIDController::new(params.vel_kp, params.vel_ki, params.vel_kd);
+            pid.set_i_limit(2.0);
+            pid
+        });
+        PosController {
+            params,
+            pos_sp: [0.0; 2],
+            yaw_sp: 0.0,
+            alt: AltController::new(alt_params),
+            vel_pid,
+            stick_att: StickAttitude::new(ManualParams::default()),
+        }
+    }
+
+    pub fn set_params(&mut self, params: PosCtrlParams, alt_params: AltCtrlParams) {
+        for pid in self.vel_pid.iter_mut() {
+            pid.set_gains(params.vel_kp, params.vel_ki, params.vel_kd);
+        }
+        self.params = params;
+        self.alt.set_params(alt_params);
+    }
+
+    // called when the mode is switched in, hold the current position and heading
+    pub fn reset(&mut self, state: &PosState) {
+        self.pos_sp = [state.pos[0], state.pos[1]];
+        self.yaw_sp = get_yaw(state.q);
+        self.alt.reset(-state.pos[2]);
+        self.vel_pid.iter_mut().for_each(|x| x.reset());
+    }
+
+    // called when the position is lost, or the mode is switched in without a position
+    pub fn reset_fallback(&mut self, state: &PosState, params: ManualParams) {
+        self.stick_att.params = params;
+        self.stick_att.reset(get_yaw(state.q));
+        self.alt.reset(-state.pos[2]);
+    }
+
+    // no valid position: the sticks command the attitude, the altitude is held if it is valid,
+    // or the throttle stick is the thrust. timestamp: of the rc message
+    pub fn update_fallback(&mut self, sticks: [f32; 4], state: &PosState, alt_valid: bool, timestamp: u64, dt: f32) -> PosSetpoint {
+        let attitude = self.stick_att.update(sticks, timestamp);
+        let thrust = if alt_valid {
+            self.alt.update(sticks[2], &alt_state(state), dt)
+        } else {
+            self.alt.reset(-state.pos[2]);
+            (sticks[2].clamp(-1.0, 1.0) + 1.0) / 2.0
+        };
+        PosSetpoint { attitude, thrust }
+    }
+
+    // sticks: [roll, pitch, throttle, yaw] in [-1,1]
+    pub fn update(&mut self, sticks: [f32; 4], state: &PosState, dt: f32) -> PosSetpoint {
+        let p = &self.params;
+        let [roll, pitch, throttle, yaw] = sticks.map(|x| apply_deadband(x.clamp(-1.0, 1.0), p.deadband));
+
+        // yaw stick to the right turns clockwise
+        self.yaw_sp -= yaw * p.yaw_rate * dt;
+        self.yaw_sp = (self.yaw_sp + std::f32::consts::PI).rem_euclid(2.0 * std::f32::consts::PI) - std::f32::consts::PI;
+
+        // the front and the right of the heading in NE
+        let (sin, cos) = self.yaw_sp.sin_cos();
+        let front = [cos, -sin];
+        let right = [sin, cos];
+
+        let vel_sp = if roll != 0.0 || pitch != 0.0 {
+            self.pos_sp = [state.pos[0], state.pos[1]];
+            [0, 1].map(|i| (front[i] * pitch + right[i] * roll) * p.vel_max)
+        } else {
+            [0, 1].map(|i| (self.pos_sp[i] - state.pos[i]) * p.pos_kp)
+        };
+        let attitude = self.update_vel(vel_sp, state, dt);
+        let thrust = self.alt.update(throttle, &alt_state(state), dt);
+        PosSetpoint { attitude, thrust }
+    }
+
+    // track an external target(offboard), NED. the position is held on the axes without a target.
+    pub fn update_target(
+        &mut self,
+        pos: Option<[f32; 3]>,
+        vel: Option<[f32; 3]>,
+        yaw: Option<f32>,
+        state: &PosState,
+        dt: f32,
+    ) -> PosSetpoint {
+        if let Some(yaw) = yaw {
+            // NED yaw is positive to the east
+            self.yaw_sp = -yaw;
+        }
+        let vel_ff = vel.map_or([0.0; 2], |v| [v[0], v[1]]);
+        let vel_sp = match (pos, vel) {
+            (Some(pos), _) => {
+                self.pos_sp = [pos[0], pos[1]];
+                [0, 1].map(|i| (self.pos_sp[i] - state.pos[i]) * self.params.pos_kp + vel_ff[i])
+            }
+            (None, Some(_)) => {
+                self.pos_sp = [state.pos[0], state.pos[1]];
+                vel_ff
+            }
+            (None, None) => [0, 1].map(|i| (self.pos_sp[i] - state.pos[i]) * self.params.pos_kp),
+        };
+        let attitude = self.update_vel(vel_sp, state, dt);
+        let thrust = self.alt.update_target(pos.map(|p| -p[2]), vel.map(|v| -v[2]), &alt_state(state), dt);
+        PosSetpoint { attitude, thrust }
+    }
+
+    // horizontal velocity(NE) -> acceleration -> attitude
+    fn update_vel(&mut self, vel_sp: [f32; 2], state: &PosState, dt: f32) -> Q<f32> {
+        let p = &self.params;
+        let norm = (vel_sp[0] * vel_sp[0] + vel_sp[1] * vel_sp[1]).sqrt();
+        let k = if norm > p.vel_max { p.vel_max / norm } else { 1.0 };
+        let vel_sp = vel_sp.map(|x| x * k);
+
+        let mut acc_sp = [0, 1].map(|i| self.vel_pid[i].calcuate(vel_sp[i] - state.vel[i], dt));
+        let acc_max = GRAVITY * p.tilt_max.tan();
+        let norm = (acc_sp[0] * acc_sp[0] + acc_sp[1] * acc_sp[1]).sqrt();
+        if norm > acc_max {
+            acc_sp = acc_sp.map(|x| x * acc_max / norm);
+        }
+
+        // NE -> ENU, tilt the thrust to the acceleration
+        thrust_dir_to_attitude([acc_sp[1], acc_sp[0], GRAVITY], self.yaw_sp)
+    }
+}
+
+fn alt_state(state: &PosState) -> AltState {
+    AltState {
+        alt: -state.pos[2],
+        vz: -state.vel[2],
+        acc_up: state.acc_up,
+        tilt_cos: point_rotation(state.q, [0.0, 0.0, 1.0])[2],
+    }
+}
+
+fn pos_control_main(ptr: *mut c_void) -> *mut c_void {
+    let sp = unsafe { Arc::from_raw(ptr as *const SchedulePthread) };
+    let mut rc_rx = get_new_rx_of_message::<RcChannelsMsg>("rc_channels").unwrap();
+    let mut pos_rx = get_new_rx_of_message::<LocalPositionMsg>("vehicle_local_position").unwrap();
+    let mut pos_gt_rx = get_new_rx_of_message::<LocalPositionMsg>("vehicle_local_position_groundtruth").unwrap();
+    let mut acc_rx = get_new_rx_of_message::<SensorAccelMsg>("acc").unwrap();
+    let mut att_rx = get_new_rx_of_message::<Stamped<Vector4>>("attitude").unwrap();
+    let att_target_tx = Publisher::<AttitudeSetPointMsg>::new("att_target");
+
+    const POS_CTRL_PERIOD_US: c_long = 4000;
+    let dt = POS_CTRL_PERIOD_US as f32 / 1000_000.0;
+
+    let mut alt_params = CachedParams::new(AltCtrlParams::from_params, AltCtrlParams::default());
+    let mut params = CachedParams::new(PosCtrlParams::from_params, PosCtrlParams::default());
+    let mut ctrl = PosController::new(*params.value(), *alt_params.value());
+    let mut state = PosState { pos: [0.0; 3], vel: [0.0; 3], q: (1.0, [0.0; 3]), acc_up: 0.0 };
+    let mut xy_valid = false;
+    let mut z_valid = false;
+    let mut sticks = [0.0, 0.0, -1.0, 0.0];
+    let mut rc_timestamp = 0;
+    // position or fallback, to reset the controller on a change
+    let mut active: Option<bool> = None;
+    let mut manual = CachedParams::new(ManualParams::from_params, ManualParams::default());
+
+    loop {
+        if let Some(rc) = rc_rx.try_read() {
+            sticks = get_sticks(&rc);
+            rc_timestamp = rc.header.timestamp;
+        }
+        if let Some(att) = att_rx.try_read() {
+            state.q = (att.w, [att.x, att.y, att.z]);
+        }
+        if let Some(acc) = acc_rx.try_read() {
+            state.acc_up = point_rotation(state.q, [acc.acc.x, acc.acc.y, acc.acc.z])[2] - GRAVITY;
+        }
+        // fly on the groundtruth pose of the simulator, until a position estimator exists
+        let pos_msg = if param::get_param("pos_use_gt").is_some_and(|x| x.as_bool()) {
+            pos_gt_rx.try_read()
+        } else {
+            pos_rx.try_read()
+        };
+        if let Some(pos) = pos_msg {
+            state.pos = [pos.x, pos.y, pos.z];
+            state.vel = [pos.vx, pos.vy, pos.vz];
+            (xy_valid, z_valid) = (pos.xy_valid, pos.z_valid);
+        }
+
+        // both are checked
+        if params.update() | alt_params.update() {
+            ctrl.set_params(*params.value(), *alt_params.value());
+        }
+
+        if FlightMode::current() == FlightMode::Position {
+            let valid = xy_valid && z_valid;
+            if active != Some(valid) {
+                if valid {
+                    ctrl.reset(&state);
+                } else {
+                    thread_logln!("pos_control: no valid position, fly as {}", if z_valid { "altitude" } else { "stabilize" });
+                    ctrl.reset_fallback(&state, *manual.get());
+                }
+                active = Some(valid);
+            }
+            let setpoint = if valid {
+                ctrl.update(sticks, &state, dt)
+            } else {
+                ctrl.update_fallback(sticks, &state, z_valid, rc_timestamp, dt)
+            };
+            att_target_tx.send(AttitudeSetPointMsg {
+                header: MsgHeader::default(),
+                attitude: Vector4 {
+                    w: setpoint.attitude.0,
+                    x: setpoint.attitude.1[0],
+                    y: setpoint.attitude.1[1],
+                    z: setpoint.attitude.1[2],
+                },
+                body_thrusts: Vector3 { x: 0.0, y: 0.0, z: setpoint.thrust },
+            });
+        } else {
+            active = None;
+        }
+        sp.schedule_until(POS_CTRL_PERIOD_US);
+    }
+    #[allow(unreachable_code)]
+    null_mut()
+}
+
+pub fn init_pos_control(_argc: u32, _argv: *const &str) {
+    SchedulePthread::new(1024 * 1024, 96, pos_control_main, null_mut(), false);
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    let default = PosCtrlParams::default();
+    param::add_param("pos_vel_max", ParameterData::Float(default.vel_max));
+    param::add_param("pos_deadband", ParameterData::Float(default.deadband));
+    param::add_param("pos_kp", ParameterData::Float(default.pos_kp));
+    param::add_param("pos_vel_kp", ParameterData::Float(default.vel_kp));
+    param::add_param("pos_vel_ki", ParameterData::Float(default.vel_ki));
+    param::add_param("pos_vel_kd", ParameterData::Float(default.vel_kd));
+    param::add_param("pos_tilt_max", ParameterData::Float(default.tilt_max.to_degrees()));
+    param::add_param("pos_yaw_rate", ParameterData::Float(default.yaw_rate.to_degrees()));
+    param::add_param("pos_use_gt", ParameterData::Bool(false));
+    rpos::module::Module::register("pos_control", init_pos_control);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.004;
+    const HOVER: f32 = 0.5;
+
+    // the attitude follows the setpoint immediately
+    struct Vehicle {
+        pos: [f32; 3],
+        vel: [f32; 3],
+        q: Q<f32>,
+        acc_up: f32,
+    }
+
+    impl Vehicle {
+        fn new() -> Self {
+            Vehicle { pos: [0.0, 0.0, -10.0], vel: [0.0; 3], q: (1.0, [0.0; 3]), acc_up: 0.0 }
+        }
+
+        fn state(&self) -> PosState {
+            PosState { pos: self.pos, vel: self.vel, q: self.q, acc_up: self.acc_up }
+        }
+
+        fn step(&mut self, setpoint: &PosSetpoint) {
+            self.q = setpoint.attitude;
+            let f = point_rotation(self.q, [0.0, 0.0, setpoint.thrust / HOVER * GRAVITY]);
+            // ENU -> NED
+            let acc = [f[1], f[0], GRAVITY - f[2]];
+            self.acc_up = -acc[2];
+            for i in 0..3 {
+                self.vel[i] += acc[i] * DT;
+                self.pos[i] += self.vel[i] * DT;
+            }
+        }
+    }
+
+    fn tilt(q: Q<f32>) -> f32 {
+        point_rotation(q, [0.0, 0.0, 1.0])[2].clamp(-1.0, 1.0).acos()
+    }
+
+    fn run(ctrl: &mut PosController, vehicle: &mut Vehicle, sticks: [f32; 4], time: f32) {
+        for _ in 0..(time / DT) as usize {
+            let setpoint = ctrl.update(sticks, &vehicle.state(), DT);
+            assert!(tilt(setpoint.attitude) <= ctrl.params.tilt_max + 1e-3);
+            vehicle.step(&setpoint);
+        }
+    }
+
+    #[test]
+    fn test_attitude_from_thrust() {
+        // accelerate to the east: the body z tilts to the east, right side down
+        let q = thrust_dir_to_attitude([1.0, 0.0, 1.0], 0.0);
+        let z = point_rotation(q, [0.0, 0.0, 1.0]);
+        assert!((z[0] - 0.5f32.sqrt()).abs() < 1e-5 && z[1].abs() < 1e-5);
+        assert!(get_yaw(q).abs() < 1e-5);
+
+        let q = thrust_dir_to_attitude([0.0, 0.0, 1.0], 1.0);
+        assert!((get_yaw(q) - 1.0).abs() < 1e-5);
+        // yaw 90 degree to the left, the front points to the west
+        let q = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], std::f32::consts::FRAC_PI_2);
+        let front = point_rotation(q, [0.0, 1.0, 0.0]);
+        assert!((front[0] + 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_position_hold() {
+        let mut vehicle = Vehicle::new();
+        let mut ctrl = PosController::new(PosCtrlParams::default(), AltCtrlParams::default());
+        ctrl.reset(&vehicle.state());
+        // pushed by a gust
+        vehicle.vel = [2.0, -3.0, 0.0];
+        run(&mut ctrl, &mut vehicle, [0.0; 4], 15.0);
+        assert!(vehicle.pos[0].abs() < 0.1 && vehicle.pos[1].abs() < 0.1, "{:?}", vehicle.pos);
+        assert!((vehicle.pos[2] + 10.0).abs() < 0.1);
+        assert!(tilt(vehicle.q) < 0.01);
+    }
+
+    #[test]
+    fn test_stick_velocity() {
+        let mut vehicle = Vehicle::new();
+        let mut ctrl = PosController::new(PosCtrlParams::default(), AltCtrlParams::default());
+        // heading to the west
+        vehicle.q = quaternion_core::from_axis_angle([0.0, 0.0, 1.0], std::f32::consts::FRAC_PI_2);
+        ctrl.reset(&vehicle.state());
+
+        // full pitch stick forward: fly to the west with the max speed
+        run(&mut ctrl, &mut vehicle, [0.0, 1.0, 0.0, 0.0], 10.0);
+        assert!((vehicle.vel[1] + 5.0).abs() < 0.1 && vehicle.vel[0].abs() < 0.1, "{:?}", vehicle.vel);
+        assert!((get_yaw(vehicle.q) - std::f32::consts::FRAC_PI_2).abs() < 1e-3);
+
+        // release, stop and hold
+        run(&mut ctrl, &mut vehicle, [0.0; 4], 15.0);
+        assert!(vehicle.vel[1].abs() < 0.05);
+        assert!((vehicle.pos[1] - ctrl.pos_sp[1]).abs() < 0.1);
+
+        // yaw stick to the right turns the yaw setpoint to the right
+        let yaw = ctrl.yaw_sp;
+        run(&mut ctrl, &mut vehicle, [0.0, 0.0, 0.0, 1.0], 0.5);
+        assert!((yaw - ctrl.yaw_sp - 45.0f32.to_radians()).abs() < 1e-2);
+    }
+
+    #[test]
+    fn test_position_fallback() {
+        let mut vehicle = Vehicle::new();
+        let mut ctrl = PosController::new(PosCtrlParams::default(), AltCtrlParams::default());
+        ctrl.reset_fallback(&vehicle.state(), ManualParams { expo: 0.0, ..Default::default() });
+
+        // centred sticks: level, and the altitude is held
+        for i in 0..(10.0 / DT) as u64 {
+            let setpoint = ctrl.update_fallback([0.0; 4], &vehicle.state(), true, i * 4000, DT);
+            vehicle.step(&setpoint);
+        }
+        assert!(tilt(vehicle.q) < 1e-3);
+        assert!((vehicle.pos[2] + 10.0).abs() < 0.1, "{:?}", vehicle.pos);
+
+        // the sticks tilt the vehicle, no altitude: the throttle stick is the thrust
+        let setpoint = ctrl.update_fallback([1.0, 0.0, 0.2, 0.0], &vehicle.state(), false, 0, DT);
+        assert!((tilt(setpoint.attitude) - ManualParams::default().tilt_max).abs() < 1e-3);
+        assert!((setpoint.thrust - 0.6).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_target_tracking() {
+        let mut vehicle = Vehicle::new();
+        let mut ctrl = PosController::new(PosCtrlParams::default(), AltCtrlParams::default());
+        ctrl.reset(&vehicle.state());
+
+        let run_target = |ctrl: &mut PosController, vehicle: &mut Vehicle, pos, vel, time: f32| {
+            for _ in 0..(time / DT) as usize {
+                let setpoint = ctrl.update_target(pos, vel, Some(0.5), &vehicle.state(), DT);
+                vehicle.step(&setpoint);
+            }
+        };
+        run_target(&mut ctrl, &mut vehicle, Some([3.0, -2.0, -12.0]), None, 15.0);
+        assert!((vehicle.pos[0] - 3.0).abs() < 0.1 && (vehicle.pos[1] + 2.0).abs() < 0.1, "{:?}", vehicle.pos);
+        assert!((vehicle.pos[2] + 12.0).abs() < 0.1);
+        // NED yaw to the east is negative in ENU
+        assert!((get_yaw(vehicle.q) + 0.5).abs() < 1e-3);
+
+        // velocity only: fly to the north and climb, the position setpoint follows the vehicle
+        run_target(&mut ctrl, &mut vehicle, None, Some([1.0, 0.0, -0.5]), 5.0);
+        assert!((vehicle.vel[0] - 1.0).abs() < 0.05 && (vehicle.vel[2] + 0.5).abs() < 0.05, "{:?}", vehicle.vel);
+        assert!((ctrl.pos_sp[0] - vehicle.pos[0]).abs() < 0.1);
+
+        // no target, hold
+        run_target(&mut ctrl, &mut vehicle, None, None, 10.0);
+        assert!(vehicle.vel.iter().all(|x| x.abs() < 0.05), "{:?}", vehicle.vel);
+    }
+}
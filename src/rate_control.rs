@@ -0,0 +1,198 @@
+use std::{ffi::c_void, ptr::null_mut, sync::Arc};
+
+use rpos::{libc::c_long, msg::get_new_rx_of_message, pthread_scheduler::SchedulePthread};
+
+use crate::{
+    basic::pid::PIDController,
+    mode::FlightMode,
+    msg_define::{EulerVector3, MsgHeader, Publisher, RateSetPointMsg, SensorGyroMsg, TorqueThrustMsg, Vector3},
+    param::{self, CachedParams, ParameterData},
+};
+
+pub struct RateCtrlParams {
+    pub rp_kp: f32, // roll and pitch, rad/s error to torque
+    pub rp_ki: f32,
+    pub rp_kd: f32,
+    pub y_kp: f32, // yaw
+    pub y_ki: f32,
+    pub y_kd: f32,
+    pub i_limit: f32, // limit of the integrated error, rad
+}
+
+impl Default for RateCtrlParams {
+    fn default() -> Self {
+        RateCtrlParams {
+            rp_kp: 20.0,
+            rp_ki: 10.0,
+            rp_kd: 0.2,
+            y_kp: 30.0,
+            y_ki: 5.0,
+            y_kd: 0.0,
+            i_limit: 2.0,
+        }
+    }
+}
+
+impl RateCtrlParams {
+    // None if a param is being written
+    fn from_params() -> Option<Self> {
+        let get = |name: &str| param::get_param(name).map(|x| x.as_f32());
+        Some(RateCtrlParams {
+            rp_kp: get("rate_rp_kp")?,
+            rp_ki: get("rate_rp_ki")?,
+            rp_kd: get("rate_rp_kd")?,
+            y_kp: get("rate_y_kp")?,
+            y_ki: get("rate_y_ki")?,
+            y_kd: get("rate_y_kd")?,
+            i_limit: get("rate_i_limit")?,
+        })
+    }
+}
+
+// no rate setpoint in this time(the rc link or manual_ctrl is gone), the rates are zeroed at idle.
+// longer than the interval of the failsafe rc messages
+const RATE_SP_TIMEOUT_US: u64 = 500_000;
+
+// [pitch, roll, yaw] rates and the thrust to follow at now_us
+fn current_setpoint(setpoint: &RateSetPointMsg, now_us: u64, idle: f32) -> ([f32; 3], f32) {
+    if now_us.saturating_sub(setpoint.header.timestamp) > RATE_SP_TIMEOUT_US {
+        return ([0.0; 3], idle);
+    }
+    let rates = &setpoint.angle_rate;
+    ([rates.pitch, rates.roll, rates.yaw], setpoint.thrusts.z)
+}
+
+// body rates -> torques, the axes are [pitch(x), roll(y), yaw(z)] as the gyro
+pub struct RateController {
+    pids: [PIDController; 3],
+}
+
+impl RateController {
+    pub fn new(params: &RateCtrlParams) -> Self {
+        let mut ctrl = RateController { pids: [0, 1, 2].map(|_| PIDController::new(0.0, 0.0, 0.0)) };
+        ctrl.set_params(params);
+        ctrl
+    }
+
+    pub fn set_params(&mut self, p: &RateCtrlParams) {
+        self.pids[0].set_gains(p.rp_kp, p.rp_ki, p.rp_kd);
+        self.pids[1].set_gains(p.rp_kp, p.rp_ki, p.rp_kd);
+        self.pids[2].set_gains(p.y_kp, p.y_ki, p.y_kd);
+        self.pids.iter_mut().for_each(|x| x.set_i_limit(p.i_limit));
+    }
+
+    pub fn reset(&mut self) {
+        self.pids.iter_mut().for_each(|x| x.reset());
+    }
+
+    pub fn update(&mut self, target: [f32; 3], rate: [f32; 3], dt: f32) -> [f32; 3] {
+        [0, 1, 2].map(|i| self.pids[i].calcuate(target[i] - rate[i], dt))
+    }
+}
+
+fn rate_control_main(ptr: *mut c_void) -> *mut c_void {
+    let sp = unsafe { Arc::from_raw(ptr as *const SchedulePthread) };
+    let mut rate_target_rx = get_new_rx_of_message::<RateSetPointMsg>("rate_target").unwrap();
+    let mut gyro_rx = get_new_rx_of_message::<SensorGyroMsg>("gyro").unwrap();
+    let torque_tx = Publisher::<TorqueThrustMsg>::new("toreque_thrust_setpoint");
+
+    const RATE_CTRL_PERIOD_US: c_long = 2500;
+    let dt = RATE_CTRL_PERIOD_US as f32 / 1000_000.0;
+
+    let mut params = CachedParams::new(RateCtrlParams::from_params, RateCtrlParams::default());
+    let mut ctrl = RateController::new(params.value());
+    // stale until the first one
+    let mut setpoint = RateSetPointMsg {
+        header: MsgHeader::default(),
+        angle_rate: EulerVector3 { pitch: 0.0, roll: 0.0, yaw: 0.0 },
+        thrusts: Vector3 { x: 0.0, y: 0.0, z: 0.0 },
+    };
+    let mut rate = [0.0; 3];
+
+    loop {
+        if let Some(x) = rate_target_rx.try_read() {
+            setpoint = x;
+        }
+        if let Some(gyro) = gyro_rx.try_read() {
+            rate = [gyro.rate.x, gyro.rate.y, gyro.rate.z];
+        }
+
+        if params.update() {
+            ctrl.set_params(params.value());
+        }
+
+        // no integration on the ground
+        let idle = param::get_param("acro_thr_idle").map_or(0.0, |x| x.as_f32());
+        let (target, thrust) = current_setpoint(&setpoint, crate::basic::hrt_now_us(), idle);
+        if FlightMode::current() == FlightMode::Acro {
+            if thrust <= idle + 0.01 {
+                ctrl.reset();
+            }
+            let torques = ctrl.update(target, rate, dt);
+            torque_tx.send(TorqueThrustMsg {
+                header: MsgHeader::default(),
+                torques: EulerVector3 { pitch: torques[0], roll: torques[1], yaw: torques[2] },
+                thrusts: Vector3 { x: 0.0, y: 0.0, z: thrust },
+            });
+        } else {
+            ctrl.reset();
+        }
+        sp.schedule_until(RATE_CTRL_PERIOD_US);
+    }
+    #[allow(unreachable_code)]
+    null_mut()
+}
+
+pub fn init_rate_control(_argc: u32, _argv: *const &str) {
+    SchedulePthread::new(16384, 98, rate_control_main, null_mut(), false);
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    let default = RateCtrlParams::default();
+    param::add_param("rate_rp_kp", ParameterData::Float(default.rp_kp));
+    param::add_param("rate_rp_ki", ParameterData::Float(default.rp_ki));
+    param::add_param("rate_rp_kd", ParameterData::Float(default.rp_kd));
+    param::add_param("rate_y_kp", ParameterData::Float(default.y_kp));
+    param::add_param("rate_y_ki", ParameterData::Float(default.y_ki));
+    param::add_param("rate_y_kd", ParameterData::Float(default.y_kd));
+    param::add_param("rate_i_limit", ParameterData::Float(default.i_limit));
+    rpos::module::Module::register("rate_control", init_rate_control);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setpoint_timeout() {
+        let setpoint = RateSetPointMsg {
+            header: MsgHeader { timestamp: 1_000_000, ..Default::default() },
+            angle_rate: EulerVector3 { pitch: 1.0, roll: -2.0, yaw: 3.0 },
+            thrusts: Vector3 { x: 0.0, y: 0.0, z: 0.6 },
+        };
+        assert_eq!(current_setpoint(&setpoint, 1_000_000 + RATE_SP_TIMEOUT_US, 0.05), ([1.0, -2.0, 3.0], 0.6));
+        assert_eq!(current_setpoint(&setpoint, 1_000_001 + RATE_SP_TIMEOUT_US, 0.05), ([0.0; 3], 0.05));
+    }
+
+    #[test]
+    fn test_rate_tracking() {
+        const DT: f32 = 0.0025;
+        // angular acceleration per unit torque, and a constant disturbance(unbalanced motors)
+        const GAIN: f32 = 2.0;
+        const DISTURBANCE: [f32; 3] = [3.0, -2.0, 1.0];
+
+        let mut ctrl = RateController::new(&RateCtrlParams::default());
+        let mut rate = [0.0f32; 3];
+        let target = [-4.0, 6.0, -2.0];
+        for _ in 0..(3.0 / DT) as usize {
+            let torques = ctrl.update(target, rate, DT);
+            for i in 0..3 {
+                rate[i] += (torques[i] * GAIN + DISTURBANCE[i]) * DT;
+            }
+        }
+        for i in 0..3 {
+            assert!((rate[i] - target[i]).abs() < 0.01, "{:?}", rate);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use rpos::{pthread_scheduler::SchedulePthread, thread_logln};
+use serialport::{DataBits, Parity, StopBits};
+
+use crate::msg_define::{MsgHeader, Publisher, RcHealthMsg, RcInputMsg, RcSource};
+
+// no frame in this time, the last values are published again with the failsafe flag
+pub const RC_TIMEOUT_US: u64 = 200_000;
+
+// crsf and sbus: 172~1811 is 988~2012us, rc_update does the calibration
+pub fn crsf_to_us(x: u16) -> i16 {
+    ((x as i32 - 992) * 5 / 8 + 1500) as i16
+}
+
+// the serial port of the receiver, some protocols send data back
+pub trait SerialDevice: Read + Write + Send {}
+impl<T: Read + Write + Send + ?Sized> SerialDevice for T {}
+
+// 16 x 11 bits, lsb first, used by crsf and sbus
+pub fn unpack_channels(data: &[u8]) -> [u16; 16] {
+    let mut channels = [0; 16];
+    let (mut bits, mut value, mut index) = (0, 0u32, 0);
+    for x in data.iter().take(22) {
+        value |= (*x as u32) << bits;
+        bits += 8;
+        if bits >= 11 {
+            channels[index] = (value & 0x7FF) as u16;
+            index += 1;
+            value >>= 11;
+            bits -= 11;
+        }
+    }
+    channels
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ParserStats {
+    pub crc_errors: u32,
+    pub discarded_bytes: u32, // not in any valid frame
+}
+
+/*
+    a receiver protocol, the bytes from the serial port are pushed in and the decoded frames popped out.
+    the frames carry the flags of the receiver, the header is filled by the publisher.
+*/
+pub trait RcProtocol: Send {
+    fn source(&self) -> RcSource;
+    fn push_bytes(&mut self, bytes: &[u8]);
+    fn next_frame(&mut self) -> Option<RcInputMsg>;
+    fn stats(&self) -> ParserStats;
+}
+
+type Opener = Box<dyn FnMut() -> io::Result<Box<dyn SerialDevice>> + Send>;
+
+/*
+    reads the receiver and publishes rc_input and rc_health.
+    the device is closed on a read error and opened again with a growing interval.
+*/
+pub struct RcReader<P: RcProtocol> {
+    pub protocol: P,
+    dev: Option<Box<dyn SerialDevice>>,
+    open: Opener,
+    tx: Publisher<RcInputMsg>,
+    health_tx: Publisher<RcHealthMsg>,
+    last: Option<RcInputMsg>,
+    last_us: u64,
+    last_frame_us: Option<u64>,
+    frame_count: u32,
+    opened: bool,
+    reconnects: u32,
+    backoff_us: u64,
+    next_open_us: u64,
+    next_health_us: u64,
+}
+
+impl<P: RcProtocol> RcReader<P> {
+    const HEALTH_PERIOD_US: u64 = 200_000;
+    const MIN_BACKOFF_US: u64 = 100_000;
+    const MAX_BACKOFF_US: u64 = 5_000_000;
+
+    // the device is never reopened, eg. a file of recorded bytes. published to `topic`
+    #[cfg(test)]
+    pub fn new(dev: Box<dyn SerialDevice>, protocol: P, topic: &str) -> Self {
+        let mut reader = Self::with_opener(
+            || Err(io::Error::new(io::ErrorKind::Unsupported, "the device can't be reopened")),
+            protocol,
+        );
+        reader.tx = Publisher::new(topic);
+        reader.dev = Some(dev);
+        reader.opened = true;
+        reader
+    }
+
+    pub fn with_opener(
+        open: impl FnMut() -> io::Result<Box<dyn SerialDevice>> + Send + 'static,
+        protocol: P,
+    ) -> Self {
+        RcReader {
+            protocol,
+            dev: None,
+            open: Box::new(open),
+            tx: Publisher::new("rc_input"),
+            health_tx: Publisher::new("rc_health"),
+            last: None,
+            last_us: 0,
+            last_frame_us: None,
+            frame_count: 0,
+            opened: false,
+            reconnects: 0,
+            backoff_us: Self::MIN_BACKOFF_US,
+            next_open_us: 0,
+            next_health_us: 0,
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        self.dev.is_some()
+    }
+
+    // the bytes to the receiver, eg. telemetry
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let dev = self.dev.as_mut().ok_or(io::Error::from(io::ErrorKind::NotConnected))?;
+        let ret = dev.write_all(bytes);
+        if let Err(e) = &ret {
+            self.close(e);
+        }
+        ret
+    }
+
+    fn close(&mut self, e: &io::Error) {
+        thread_logln!("{:?} receiver error: {}, reopen in {}ms", self.protocol.source(), e, self.backoff_us / 1000);
+        self.dev = None;
+    }
+
+    fn try_open(&mut self, now: u64) {
+        match (self.open)() {
+            Ok(dev) => {
+                if self.opened {
+                    self.reconnects += 1;
+                }
+                self.opened = true;
+                thread_logln!("{:?} receiver opened", self.protocol.source());
+                self.dev = Some(dev);
+                self.backoff_us = Self::MIN_BACKOFF_US;
+            }
+            Err(e) => {
+                if self.backoff_us == Self::MIN_BACKOFF_US {
+                    thread_logln!("{:?} receiver open failed: {}", self.protocol.source(), e);
+                }
+                self.next_open_us = now + self.backoff_us;
+                self.backoff_us = (self.backoff_us * 2).min(Self::MAX_BACKOFF_US);
+            }
+        }
+    }
+
+    pub fn process(&mut self) {
+        self.process_at(crate::basic::hrt_now_us());
+    }
+
+    pub fn process_at(&mut self, now: u64) {
+        if self.dev.is_none() && now >= self.next_open_us {
+            self.try_open(now);
+        }
+        if let Some(dev) = self.dev.as_mut() {
+            let mut buf = [0; 1024];
+            match dev.read(&mut buf) {
+                Ok(len) => self.protocol.push_bytes(&buf[..len]),
+                // no data in the timeout of the serial port
+                Err(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => {}
+                Err(e) => {
+                    self.close(&e);
+                    self.next_open_us = now + self.backoff_us;
+                }
+            }
+        }
+        let mut received = false;
+        while let Some(msg) = self.protocol.next_frame() {
+            self.last = Some(msg.clone());
+            self.last_us = now;
+            self.last_frame_us = Some(now);
+            self.frame_count += 1;
+            received = true;
+            self.tx.send(msg);
+        }
+        if !received && now.saturating_sub(self.last_us) > RC_TIMEOUT_US {
+            if let Some(last) = self.last.as_mut() {
+                last.failsafe = true;
+                self.tx.send(last.clone());
+                self.last_us = now;
+            }
+        }
+        if now >= self.next_health_us {
+            self.next_health_us = now + Self::HEALTH_PERIOD_US;
+            self.health_tx.send(self.health(now));
+        }
+    }
+
+    pub fn health(&self, now: u64) -> RcHealthMsg {
+        let stats = self.protocol.stats();
+        RcHealthMsg {
+            header: MsgHeader::default(),
+            source: self.protocol.source(),
+            connected: self.connected(),
+            signal: self.last_frame_us.is_some_and(|x| now.saturating_sub(x) <= RC_TIMEOUT_US),
+            failsafe: self.last.as_ref().is_some_and(|x| x.failsafe),
+            frame_count: self.frame_count,
+            crc_errors: stats.crc_errors,
+            discarded_bytes: stats.discarded_bytes,
+            reconnects: self.reconnects,
+        }
+    }
+}
+
+// a file(recorded bytes) is opened if the name is not under /dev
+pub fn open_device(
+    dev_name: &str,
+    baudrate: u32,
+    parity: Parity,
+    stop_bits: StopBits,
+) -> io::Result<Box<dyn SerialDevice>> {
+    if dev_name.contains("/dev/") {
+        let serial = serialport::new(dev_name, baudrate)
+            .data_bits(DataBits::Eight)
+            .parity(parity)
+            .stop_bits(stop_bits)
+            .timeout(Duration::from_millis(20));
+        Ok(Box::new(serial.open()?))
+    } else {
+        Ok(Box::new(OpenOptions::new().read(true).write(true).open(dev_name)?))
+    }
+}
+
+// the device is opened in the reader thread, a missing receiver is waited for
+pub fn spawn_reader<P: RcProtocol + 'static>(dev_name: &str, baudrate: u32, parity: Parity, stop_bits: StopBits, protocol: P) {
+    let dev_name = dev_name.to_string();
+    let mut reader = RcReader::with_opener(move || open_device(&dev_name, baudrate, parity, stop_bits), protocol);
+    SchedulePthread::new_simple(Box::new(move |s| loop {
+        reader.process();
+        s.schedule_until(2000);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use rpos::msg::get_new_rx_of_message;
+
+    use super::*;
+    use crate::msg_define::{MsgHeader, RcSource, RC_INPUT_MAX_CHANNELS};
+
+    // one frame for each byte
+    struct ByteFrames(Vec<u8>);
+
+    impl RcProtocol for ByteFrames {
+        fn source(&self) -> RcSource {
+            RcSource::Unknown
+        }
+
+        fn stats(&self) -> ParserStats {
+            ParserStats::default()
+        }
+
+        fn push_bytes(&mut self, bytes: &[u8]) {
+            self.0.extend_from_slice(bytes);
+        }
+
+        fn next_frame(&mut self) -> Option<RcInputMsg> {
+            let x = self.0.pop()?;
+            Some(RcInputMsg {
+                header: MsgHeader::default(),
+                source: RcSource::Unknown,
+                channel_count: 1,
+                failsafe: false,
+                frame_lost: false,
+                channel_vals: [1000 + x as i16; RC_INPUT_MAX_CHANNELS],
+            })
+        }
+    }
+
+    #[test]
+    fn test_reader_timeout() {
+        // other tests publish rc_input too, only the messages of this one are collected
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_cb = received.clone();
+        get_new_rx_of_message::<RcInputMsg>("rc_input").unwrap().register_callback("rc_input_test", move |msg| {
+            if msg.source == RcSource::Unknown {
+                received_cb.lock().unwrap().push((msg.channel_vals[0], msg.failsafe));
+            }
+        });
+
+        let mut reader = RcReader::new(Box::new(Cursor::new(vec![7u8])), ByteFrames(Vec::new()), "rc_input");
+        reader.process_at(1_000_000);
+        reader.process_at(1_000_000 + RC_TIMEOUT_US);
+        reader.process_at(1_000_001 + RC_TIMEOUT_US);
+        reader.process_at(1_000_001 + RC_TIMEOUT_US * 2);
+        reader.process_at(1_000_002 + RC_TIMEOUT_US * 2);
+        assert_eq!(*received.lock().unwrap(), [(1007, false), (1007, true), (1007, true)]);
+    }
+
+    // the receiver is unplugged
+    struct Unplugged;
+
+    impl Read for Unplugged {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    impl Write for Unplugged {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_reader_reopen() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let attempts_open = attempts.clone();
+        let open = move || -> io::Result<Box<dyn SerialDevice>> {
+            // missing at the first two tries
+            if attempts_open.fetch_add(1, Ordering::Relaxed) < 2 {
+                Err(io::ErrorKind::NotFound.into())
+            } else {
+                Ok(Box::new(Unplugged))
+            }
+        };
+        let mut reader = RcReader::with_opener(open, ByteFrames(Vec::new()));
+        let attempts_at = |reader: &mut RcReader<ByteFrames>, now: u64| {
+            reader.process_at(now);
+            attempts.load(Ordering::Relaxed)
+        };
+        assert_eq!(attempts_at(&mut reader, 0), 1);
+        assert_eq!(attempts_at(&mut reader, 50_000), 1);
+        assert_eq!(attempts_at(&mut reader, 100_000), 2);
+        // the interval is doubled
+        assert_eq!(attempts_at(&mut reader, 250_000), 2);
+        assert_eq!(attempts_at(&mut reader, 300_000), 3);
+        // opened, the read fails and the device is closed
+        assert!(!reader.connected());
+        assert_eq!(attempts_at(&mut reader, 400_000), 4);
+        let health = reader.health(400_000);
+        assert_eq!((health.connected, health.signal, health.reconnects), (false, false, 1));
+        assert_eq!(health.source, RcSource::Unknown);
+    }
+}
@@ -0,0 +1,417 @@
+use std::{
+    cell::{Cell, RefCell},
+    time::{Duration, Instant},
+};
+
+use rpos::{channel::Receiver, msg::get_new_rx_of_message, thread_logln};
+
+use crate::{
+    arming,
+    mode::FlightMode,
+    msg_define::{MsgHeader, Publisher, RcChannelsMsg, RcFunction, RcInputMsg, RC_INPUT_MAX_CHANNELS},
+    param::{self, ParameterData},
+    sensor_calib::{self, CalibError},
+};
+
+pub const RC_CHANNELS: usize = RC_INPUT_MAX_CHANNELS;
+
+// channel(1~) of each function, 0 if not used
+const MAP_PARAMS: [&str; RcFunction::COUNT] =
+    ["rc_map_roll", "rc_map_pitch", "rc_map_thr", "rc_map_yaw", "rc_map_mode", "rc_map_arm", "rc_map_kill"];
+// AETR, mode switch on channel 5
+const MAP_DEFAULT: [i32; RcFunction::COUNT] = [1, 2, 3, 4, 5, 0, 0];
+// flight mode of the low, middle and high position of the mode switch
+const MODE_PARAMS: [&str; 3] = ["rc_mode_low", "rc_mode_mid", "rc_mode_high"];
+const MODE_DEFAULT: [FlightMode; 3] = [FlightMode::Stabilize, FlightMode::Altitude, FlightMode::Position];
+
+fn channel_param(ch: usize, name: &str) -> String {
+    format!("rc{}_{}", ch + 1, name)
+}
+
+// endpoints of a channel, unit:us
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RcChannelCalib {
+    pub min: f32,
+    pub trim: f32,
+    pub max: f32,
+    pub rev: bool,
+    pub deadzone: f32, // around the trim
+}
+
+impl Default for RcChannelCalib {
+    fn default() -> Self {
+        RcChannelCalib { min: 1000.0, trim: 1500.0, max: 2000.0, rev: false, deadzone: 0.0 }
+    }
+}
+
+impl RcChannelCalib {
+    // None if a param is absent or being written
+    fn from_params(ch: usize) -> Option<Self> {
+        let get = |name: &str| param::get_param(&channel_param(ch, name));
+        Some(RcChannelCalib {
+            min: get("min")?.as_f32(),
+            trim: get("trim")?.as_f32(),
+            max: get("max")?.as_f32(),
+            rev: get("rev")?.as_bool(),
+            deadzone: get("dz")?.as_f32(),
+        })
+    }
+
+    fn save(&self, ch: usize) -> Result<(), CalibError> {
+        sensor_calib::set_f32(&channel_param(ch, "min"), self.min)?;
+        sensor_calib::set_f32(&channel_param(ch, "trim"), self.trim)?;
+        sensor_calib::set_f32(&channel_param(ch, "max"), self.max)
+    }
+
+    // us -> [-1,1], 0 at the trim. linear from min to max if `linear`(throttle).
+    pub fn normalize(&self, us: i16, linear: bool) -> f32 {
+        let us = us as f32;
+        let x = if linear {
+            (us - self.min) / (self.max - self.min).max(1.0) * 2.0 - 1.0
+        } else if us > self.trim + self.deadzone {
+            (us - self.trim - self.deadzone) / (self.max - self.trim - self.deadzone).max(1.0)
+        } else if us < self.trim - self.deadzone {
+            (us - self.trim + self.deadzone) / (self.trim - self.min - self.deadzone).max(1.0)
+        } else {
+            0.0
+        };
+        let x = x.clamp(-1.0, 1.0);
+        if self.rev {
+            -x
+        } else {
+            x
+        }
+    }
+}
+
+pub struct RcUpdate {
+    pub calib: [RcChannelCalib; RC_CHANNELS],
+    pub map: [i32; RcFunction::COUNT],
+}
+
+impl RcUpdate {
+    pub fn from_params() -> Option<Self> {
+        let mut calib = [RcChannelCalib::default(); RC_CHANNELS];
+        for (ch, x) in calib.iter_mut().enumerate() {
+            *x = RcChannelCalib::from_params(ch)?;
+        }
+        let mut map = MAP_DEFAULT;
+        for (x, name) in map.iter_mut().zip(MAP_PARAMS) {
+            *x = param::get_param(name)?.as_i32();
+        }
+        Some(RcUpdate { calib, map })
+    }
+
+    fn function_channel(&self, function: RcFunction) -> Option<usize> {
+        usize::try_from(self.map[function as usize] - 1).ok().filter(|x| *x < RC_CHANNELS)
+    }
+
+    pub fn update(&self, rc: &RcInputMsg) -> RcChannelsMsg {
+        let throttle = self.function_channel(RcFunction::Throttle);
+        let count = rc.channel_count as usize;
+        let channels: [f32; RC_CHANNELS] = std::array::from_fn(|i| {
+            if i < count {
+                self.calib[i].normalize(rc.channel_vals[i], throttle == Some(i))
+            } else {
+                f32::NAN
+            }
+        });
+        RcChannelsMsg {
+            header: MsgHeader::default(),
+            source: rc.source,
+            failsafe: rc.failsafe,
+            channels,
+            functions: RcFunction::ALL.map(|x| self.function_channel(x).map_or(f32::NAN, |ch| channels[ch])),
+        }
+    }
+}
+
+// 3 position switch: 0 low, 1 middle, 2 high
+pub fn switch_position(x: f32) -> usize {
+    if x < -0.5 {
+        0
+    } else if x > 0.5 {
+        2
+    } else {
+        1
+    }
+}
+
+/*
+    guided calibration: the trims are averaged with the sticks centred,
+    then the endpoints are recorded while the sticks and switches are moved to their extremes.
+*/
+pub struct RcCalibrator {
+    sum: [f64; RC_CHANNELS],
+    count: [u32; RC_CHANNELS],
+    min: [i16; RC_CHANNELS],
+    max: [i16; RC_CHANNELS],
+}
+
+impl RcCalibrator {
+    // a channel narrower than this is regarded as not moved
+    const MIN_RANGE: i16 = 300;
+
+    pub fn new() -> Self {
+        RcCalibrator {
+            sum: [0.0; RC_CHANNELS],
+            count: [0; RC_CHANNELS],
+            min: [i16::MAX; RC_CHANNELS],
+            max: [i16::MIN; RC_CHANNELS],
+        }
+    }
+
+    // raw: the channels provided by the receiver
+    pub fn push_center(&mut self, raw: &[i16]) {
+        for (i, x) in raw.iter().take(RC_CHANNELS).enumerate() {
+            self.sum[i] += *x as f64;
+            self.count[i] += 1;
+        }
+    }
+
+    pub fn push_extreme(&mut self, raw: &[i16]) {
+        for (i, x) in raw.iter().take(RC_CHANNELS).enumerate() {
+            self.min[i] = self.min[i].min(*x);
+            self.max[i] = self.max[i].max(*x);
+        }
+    }
+
+    // the channels not moved keep the old calibration, the required ones must be moved
+    pub fn result(
+        &self,
+        old: &[RcChannelCalib; RC_CHANNELS],
+        required: &[usize],
+    ) -> Result<[RcChannelCalib; RC_CHANNELS], CalibError> {
+        if self.count.iter().all(|x| *x == 0) {
+            return Err(CalibError::Timeout);
+        }
+        let mut calib = *old;
+        for (i, c) in calib.iter_mut().enumerate() {
+            let moved = self.count[i] > 0 && self.max[i] >= self.min[i] && self.max[i] - self.min[i] >= Self::MIN_RANGE;
+            if !moved {
+                if required.contains(&i) {
+                    return Err(CalibError::BadData);
+                }
+                continue;
+            }
+            let (min, max) = (self.min[i] as f32, self.max[i] as f32);
+            c.min = min;
+            c.max = max;
+            c.trim = ((self.sum[i] / self.count[i] as f64) as f32).clamp(min, max);
+        }
+        Ok(calib)
+    }
+}
+
+impl Default for RcCalibrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// feed the rc messages received in the duration
+fn collect_rc(rx: &mut Receiver<RcInputMsg>, duration: Duration, mut f: impl FnMut(&RcInputMsg)) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        if let Some(msg) = rx.try_read() {
+            f(&msg);
+        } else {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+pub fn run_rc_calib(log: &dyn Fn(&str)) -> Result<(), CalibError> {
+    let mut rx = get_new_rx_of_message::<RcInputMsg>("rc_input").unwrap();
+    let rc = RcUpdate::from_params().ok_or(CalibError::BadData)?;
+    let mut calibrator = RcCalibrator::new();
+
+    log("rc calibration: center the sticks, throttle at the bottom.");
+    collect_rc(&mut rx, Duration::from_secs(3), |_| {});
+    collect_rc(&mut rx, Duration::from_secs(1), |msg| calibrator.push_center(&msg.channel_vals[..msg.channel_count as usize]));
+
+    log("rc calibration: move all sticks and switches to their extremes.");
+    for remain in (0..3).rev() {
+        collect_rc(&mut rx, Duration::from_secs(5), |msg| calibrator.push_extreme(&msg.channel_vals[..msg.channel_count as usize]));
+        log(&format!("{}s left", remain * 5));
+    }
+
+    let required: Vec<usize> = [RcFunction::Roll, RcFunction::Pitch, RcFunction::Throttle, RcFunction::Yaw]
+        .iter()
+        .filter_map(|x| rc.function_channel(*x))
+        .collect();
+    let calib = calibrator.result(&rc.calib, &required)?;
+    for (ch, x) in calib.iter().enumerate() {
+        x.save(ch)?;
+        log(&format!("ch{} min:{} trim:{} max:{}", ch + 1, x.min, x.trim, x.max));
+    }
+    Ok(())
+}
+
+pub fn init_rc_update(_argc: u32, _argv: *const &str) {
+    let rx = get_new_rx_of_message::<RcInputMsg>("rc_input").unwrap();
+    let tx = Publisher::<RcChannelsMsg>::new("rc_channels");
+    let Some(init) = RcUpdate::from_params() else {
+        thread_logln!("rc_update: failed to read the rc params");
+        return;
+    };
+    // the motors wait for the arm switch if there is one
+    if init.function_channel(RcFunction::Arm).is_some() {
+        arming::set_armed(false);
+    }
+    let rc = RefCell::new(init);
+    let version = Cell::new(param::version());
+    let last_switch = Cell::new(None);
+
+    rx.register_callback("rc_update", move |msg| {
+        // reload only when a param is changed, the old one is kept if it is being written
+        let current = param::version();
+        if version.get() != current {
+            if let Some(x) = RcUpdate::from_params() {
+                version.set(current);
+                *rc.borrow_mut() = x;
+            }
+        }
+        let channels = rc.borrow().update(msg);
+
+        if let Some(x) = channels.get(RcFunction::Kill) {
+            let kill = switch_position(x) == 2;
+            if arming::killed() != kill {
+                arming::set_killed(kill);
+                thread_logln!("kill switch:{}", kill);
+            }
+        }
+        if let Some(x) = channels.get(RcFunction::Arm) {
+            let armed = arming::next_armed(arming::armed(), switch_position(x) == 2, channels.get(RcFunction::Throttle));
+            if arming::armed() != armed {
+                arming::set_armed(armed);
+                thread_logln!("armed:{}", armed);
+            }
+        }
+
+        // change the mode when the switch is moved, so that the ground station could change it too
+        if let Some(x) = channels.get(RcFunction::Mode) {
+            let position = switch_position(x);
+            if last_switch.replace(Some(position)) != Some(position) {
+                let mode = param::get_param(MODE_PARAMS[position]).and_then(|x| FlightMode::from_i32(x.as_i32()));
+                if let Some(mode) = mode {
+                    mode.set();
+                    thread_logln!("mode switch:{:?}", mode);
+                }
+            }
+        }
+        tx.send(channels);
+    });
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    let default = RcChannelCalib::default();
+    for ch in 0..RC_CHANNELS {
+        param::add_param(&channel_param(ch, "min"), ParameterData::Float(default.min));
+        param::add_param(&channel_param(ch, "trim"), ParameterData::Float(default.trim));
+        param::add_param(&channel_param(ch, "max"), ParameterData::Float(default.max));
+        param::add_param(&channel_param(ch, "rev"), ParameterData::Bool(default.rev));
+        param::add_param(&channel_param(ch, "dz"), ParameterData::Float(default.deadzone));
+    }
+    for (name, ch) in MAP_PARAMS.iter().zip(MAP_DEFAULT) {
+        param::add_param(name, ParameterData::Int(ch));
+    }
+    for (name, mode) in MODE_PARAMS.iter().zip(MODE_DEFAULT) {
+        param::add_param(name, ParameterData::Int(mode as i32));
+    }
+    rpos::module::Module::register("rc_update", init_rc_update);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg_define::RcSource;
+
+    fn rc_input(vals: &[i16]) -> RcInputMsg {
+        let mut channel_vals = [1500; RC_CHANNELS];
+        channel_vals[..vals.len()].copy_from_slice(vals);
+        RcInputMsg {
+            header: MsgHeader::default(),
+            source: RcSource::Crsf,
+            channel_count: vals.len() as u8,
+            failsafe: false,
+            frame_lost: false,
+            channel_vals,
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let calib = RcChannelCalib { min: 988.0, trim: 1520.0, max: 2012.0, rev: false, deadzone: 10.0 };
+        assert_eq!(calib.normalize(1520, false), 0.0);
+        assert_eq!(calib.normalize(1528, false), 0.0);
+        assert_eq!(calib.normalize(2012, false), 1.0);
+        assert_eq!(calib.normalize(988, false), -1.0);
+        assert_eq!(calib.normalize(2100, false), 1.0);
+        assert!((calib.normalize(1771, false) - 0.5).abs() < 1e-6);
+        assert!((calib.normalize(1249, false) + 0.5).abs() < 1e-6);
+        // throttle ignores the trim
+        assert_eq!(calib.normalize(1500, true), 0.0);
+
+        let rev = RcChannelCalib { rev: true, ..calib };
+        assert_eq!(rev.normalize(2012, false), -1.0);
+    }
+
+    #[test]
+    fn test_function_map() {
+        let mut rc = RcUpdate { calib: [RcChannelCalib::default(); RC_CHANNELS], map: MAP_DEFAULT };
+        let msg = rc.update(&rc_input(&[1600, 1700, 1000, 1400, 2000, 1500, 1500, 1500]));
+        assert!((msg.get(RcFunction::Roll).unwrap() - 0.2).abs() < 1e-6);
+        assert!((msg.get(RcFunction::Pitch).unwrap() - 0.4).abs() < 1e-6);
+        assert_eq!(msg.get(RcFunction::Throttle), Some(-1.0));
+        assert!((msg.get(RcFunction::Yaw).unwrap() + 0.2).abs() < 1e-6);
+        assert_eq!(switch_position(msg.get(RcFunction::Mode).unwrap()), 2);
+        assert_eq!(msg.get(RcFunction::Arm), None);
+
+        // TAER, kill switch on channel 16
+        rc.map = [2, 3, 1, 4, 0, 0, 16];
+        let mut vals = [1500; RC_CHANNELS];
+        vals[..4].copy_from_slice(&[1000, 1600, 1700, 1400]);
+        vals[15] = 2000;
+        let msg = rc.update(&rc_input(&vals));
+        assert!((msg.get(RcFunction::Roll).unwrap() - 0.2).abs() < 1e-6);
+        assert_eq!(msg.get(RcFunction::Throttle), Some(-1.0));
+        assert_eq!(msg.get(RcFunction::Mode), None);
+        assert_eq!(msg.get(RcFunction::Kill), Some(1.0));
+
+        // the receiver provides 8 channels only
+        let msg = rc.update(&rc_input(&vals[..8]));
+        assert_eq!(msg.get(RcFunction::Kill), None);
+        assert!(msg.channels[8].is_nan());
+        assert_eq!(msg.source, RcSource::Crsf);
+    }
+
+    #[test]
+    fn test_rc_calibrator() {
+        let old = [RcChannelCalib { rev: true, ..Default::default() }; RC_CHANNELS];
+        let mut calibrator = RcCalibrator::new();
+        assert_eq!(calibrator.result(&old, &[]), Err(CalibError::Timeout));
+
+        // sticks centred, throttle at the bottom
+        for i in 0..99 {
+            let noise = (i % 3) as i16 - 1;
+            calibrator.push_center(&[1510 + noise, 1490 + noise, 990, 1505, 1000, 1500, 1500, 1500]);
+        }
+        // sweep the sticks, channel 5 is a switch, channel 6~8 are not used
+        for i in 0..=100 {
+            let x = 980 + i * 10;
+            calibrator.push_extreme(&[x, 2010 - i * 10, x, x + 5, if i > 50 { 2000 } else { 1000 }, 1500, 1500, 1500]);
+        }
+        let calib = calibrator.result(&old, &[0, 1, 2, 3]).unwrap();
+        assert_eq!((calib[0].min, calib[0].max, calib[0].trim), (980.0, 1980.0, 1510.0));
+        assert_eq!((calib[1].min, calib[1].max), (1010.0, 2010.0));
+        assert_eq!(calib[2].trim, 990.0);
+        assert_eq!((calib[4].min, calib[4].max), (1000.0, 2000.0));
+        assert!(calib[0].rev);
+        assert_eq!(calib[5], old[5]);
+
+        // a stick channel is not moved
+        assert_eq!(calibrator.result(&old, &[0, 5]), Err(CalibError::BadData));
+    }
+}
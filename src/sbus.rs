@@ -0,0 +1,151 @@
+use clap::Parser;
+use rpos::thread_logln;
+use serialport::{Parity, StopBits};
+
+use crate::{
+    msg_define::{MsgHeader, RcInputMsg, RcSource},
+    rc_input::{crsf_to_us, spawn_reader, unpack_channels, ParserStats, RcProtocol},
+};
+
+/*
+    futaba sbus, 100000 baud 8E2. the signal is inverted, the uart needs an inverter
+    or the rx inversion of the soc.
+*/
+#[derive(Parser)]
+#[command(name = "sbus", about = None, long_about = None)]
+struct Cli {
+    #[arg(short, long, default_value_t = 100000)]
+    baudrate: u32,
+
+    dev_name: String,
+}
+
+const FRAME_LEN: usize = 25;
+const HEADER: u8 = 0x0F;
+const CHANNELS: usize = 16;
+const FLAG_FRAME_LOST: u8 = 1 << 2;
+const FLAG_FAILSAFE: u8 = 1 << 3;
+
+#[derive(Default)]
+pub struct Sbus {
+    buf: Vec<u8>,
+    stats: ParserStats,
+}
+
+impl Sbus {
+    // sbus2 uses 0x04, 0x14, 0x24, 0x34 to tell the telemetry slots
+    fn footer_valid(x: u8) -> bool {
+        x == 0x00 || x & 0x0F == 0x04
+    }
+
+    fn decode(frame: &[u8]) -> RcInputMsg {
+        let channel_vals = unpack_channels(&frame[1..23]).map(crsf_to_us);
+        let flags = frame[23];
+        RcInputMsg {
+            header: MsgHeader::default(),
+            source: RcSource::Sbus,
+            channel_count: CHANNELS as u8,
+            failsafe: flags & FLAG_FAILSAFE != 0,
+            frame_lost: flags & FLAG_FRAME_LOST != 0,
+            channel_vals,
+        }
+    }
+}
+
+impl RcProtocol for Sbus {
+    fn source(&self) -> RcSource {
+        RcSource::Sbus
+    }
+
+    fn stats(&self) -> ParserStats {
+        self.stats
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn next_frame(&mut self) -> Option<RcInputMsg> {
+        loop {
+            let start = self.buf.iter().position(|x| *x == HEADER).unwrap_or(self.buf.len());
+            self.buf.drain(..start);
+            self.stats.discarded_bytes += start as u32;
+            if self.buf.len() < FRAME_LEN {
+                return None;
+            }
+            if !Self::footer_valid(self.buf[FRAME_LEN - 1]) {
+                // not a frame start, resync from the next byte
+                self.stats.discarded_bytes += 1;
+                self.buf.drain(..1);
+                continue;
+            }
+            let msg = Self::decode(&self.buf[..FRAME_LEN]);
+            self.buf.drain(..FRAME_LEN);
+            return Some(msg);
+        }
+    }
+}
+
+pub fn sbus_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        spawn_reader(&args.dev_name, args.baudrate, Parity::Even, StopBits::Two, Sbus::default());
+        thread_logln!("sbus dev:{}", args.dev_name);
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    rpos::module::Module::register("sbus", sbus_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sbus_frame(channels: &[u16; CHANNELS], flags: u8) -> [u8; FRAME_LEN] {
+        let mut frame = [0; FRAME_LEN];
+        frame[0] = HEADER;
+        for (i, x) in channels.iter().enumerate() {
+            for bit in 0..11 {
+                if x & (1 << bit) != 0 {
+                    let pos = i * 11 + bit;
+                    frame[1 + pos / 8] |= 1 << (pos % 8);
+                }
+            }
+        }
+        frame[23] = flags;
+        frame
+    }
+
+    #[test]
+    fn sbus_stream_test() {
+        let channels: [u16; CHANNELS] = std::array::from_fn(|i| 172 + i as u16 * 100);
+        let mut stream = vec![0x12, HEADER, 0x34];
+        stream.extend(sbus_frame(&channels, 0));
+        stream.extend(sbus_frame(&channels, FLAG_FRAME_LOST));
+        // a broken frame, the footer is wrong
+        let mut broken = sbus_frame(&channels, 0);
+        broken[24] = 0xFF;
+        stream.extend(broken);
+        stream.extend(sbus_frame(&[992; CHANNELS], FLAG_FRAME_LOST | FLAG_FAILSAFE));
+
+        // the bytes come in pieces
+        let mut sbus = Sbus::default();
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(7) {
+            sbus.push_bytes(chunk);
+            while let Some(msg) = sbus.next_frame() {
+                frames.push(msg);
+            }
+        }
+        assert_eq!(frames.len(), 3);
+        // the leading bytes and the broken frame
+        assert!(sbus.stats().discarded_bytes >= 3 + 25);
+        assert_eq!(frames[0].channel_vals, channels.map(crsf_to_us));
+        assert_eq!((frames[0].source, frames[0].channel_count), (RcSource::Sbus, 16));
+        assert_eq!((frames[0].frame_lost, frames[0].failsafe), (false, false));
+        assert_eq!((frames[1].frame_lost, frames[1].failsafe), (true, false));
+        assert_eq!((frames[2].frame_lost, frames[2].failsafe), (true, true));
+        assert_eq!(frames[2].channel_vals, [1500; CHANNELS]);
+    }
+}
@@ -0,0 +1,886 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::{Parser, ValueEnum};
+use quaternion_core::{frame_rotation, point_rotation, Quaternion as Q};
+use rpos::{msg::get_new_rx_of_message, thread_logln};
+
+use crate::{
+    msg_define::{SensorAccelMsg, SensorGyroMsg, SensorMagMsg, Vector3},
+    param::{self, CachedParams, ParameterData},
+};
+
+const GRAVITY: f32 = 9.80665;
+
+const GYRO_OFF_PARAMS: [&str; 3] = ["cal_gyro_xoff", "cal_gyro_yoff", "cal_gyro_zoff"];
+const ACC_OFF_PARAMS: [&str; 3] = ["cal_acc_xoff", "cal_acc_yoff", "cal_acc_zoff"];
+const ACC_SCALE_PARAMS: [&str; 3] = ["cal_acc_xscale", "cal_acc_yscale", "cal_acc_zscale"];
+const MAG_OFF_PARAMS: [&str; 3] = ["cal_mag_xoff", "cal_mag_yoff", "cal_mag_zoff"];
+const MAG_SCALE_PARAMS: [&str; 3] = ["cal_mag_xscale", "cal_mag_yscale", "cal_mag_zscale"];
+// off-diagonal elements of the soft iron matrix, it is symmetric
+const MAG_ODIAG_PARAMS: [&str; 3] = ["cal_mag_xyscale", "cal_mag_xzscale", "cal_mag_yzscale"];
+const MAG_ODIAG_INDEX: [(usize, usize); 3] = [(0, 1), (0, 2), (1, 2)];
+const TRIM_ROLL_PARAM: &str = "cal_trim_roll"; // rad
+const TRIM_PITCH_PARAM: &str = "cal_trim_pitch"; // rad
+
+static CALIBRATING: AtomicBool = AtomicBool::new(false);
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CalibType {
+    Gyro,
+    Accel,
+    Level,
+    Mag,
+    Rc,
+}
+
+#[derive(Parser, Clone)]
+#[command(name = "sensor_calib", about = "calibrate gyro bias, accelerometer offsets/scales, level trim, magnetometer and rc endpoints")]
+struct Cli {
+    #[arg(value_enum)]
+    calib_type: CalibType,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CalibError {
+    Busy,
+    Moved,
+    Timeout,
+    BadData,
+    ParamWrite,
+}
+
+fn get_f32(name: &str) -> Option<f32> {
+    param::get_param(name).map(|x| x.as_f32())
+}
+
+fn get_f32s(names: [&str; 3]) -> Option<[f32; 3]> {
+    Some([get_f32(names[0])?, get_f32(names[1])?, get_f32(names[2])?])
+}
+
+// the entry may be locked by a reader for a moment
+pub fn set_f32(name: &str, val: f32) -> Result<(), CalibError> {
+    for _ in 0..10 {
+        if param::set_param(name, ParameterData::Float(val)).is_ok() {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    thread_logln!("failed to write {}", name);
+    Err(CalibError::ParamWrite)
+}
+
+// all or nothing, the written ones are restored if a write fails
+fn set_all(values: &[(&str, f32)]) -> Result<(), CalibError> {
+    let old: Vec<Option<f32>> = values.iter().map(|(name, _)| get_f32(name)).collect();
+    for (i, (name, val)) in values.iter().enumerate() {
+        if let Err(e) = set_f32(name, *val) {
+            for ((name, _), old) in values[..i].iter().zip(&old) {
+                if let Some(x) = old {
+                    let _ = set_f32(name, *x);
+                }
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// the next sample before the deadline, the sensor may be absent or stopped
+fn read_before<T>(mut try_read: impl FnMut() -> Option<T>, deadline: Instant) -> Result<T, CalibError> {
+    loop {
+        if let Some(x) = try_read() {
+            return Ok(x);
+        }
+        if Instant::now() > deadline {
+            return Err(CalibError::Timeout);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn to_array(v: Vector3) -> [f32; 3] {
+    [v.x, v.y, v.z]
+}
+
+fn to_vector(v: [f32; 3]) -> Vector3 {
+    Vector3 { x: v[0], y: v[1], z: v[2] }
+}
+
+fn get_trim_q(roll: f32, pitch: f32) -> Q<f32> {
+    // pitch is the rotation around x axis, roll is around y axis
+    quaternion_core::mul(
+        quaternion_core::from_axis_angle([1.0, 0.0, 0.0], pitch),
+        quaternion_core::from_axis_angle([0.0, 1.0, 0.0], roll),
+    )
+}
+
+// the sensor-correction stage, applied by the sensor drivers before publishing.
+// corrected = trim * ((raw - offset) * scale), the mag scale is a 3x3 matrix
+#[derive(Debug, Clone, Copy)]
+pub struct SensorCorrection {
+    gyro_off: [f32; 3],
+    acc_off: [f32; 3],
+    acc_scale: [f32; 3],
+    mag_off: [f32; 3],   // hard iron
+    mag_scale: [[f32; 3]; 3], // soft iron
+    trim: Q<f32>,
+}
+
+impl Default for SensorCorrection {
+    fn default() -> Self {
+        SensorCorrection {
+            gyro_off: [0.0; 3],
+            acc_off: [0.0; 3],
+            acc_scale: [1.0; 3],
+            mag_off: [0.0; 3],
+            mag_scale: soft_iron([1.0; 3], [0.0; 3]),
+            trim: (1.0, [0.0; 3]),
+        }
+    }
+}
+
+impl SensorCorrection {
+    // every driver keeps its own copy, no lock is taken on the sample path.
+    // the parameters are read again only after a change.
+    pub fn cached() -> CachedParams<Self> {
+        CachedParams::new(Self::from_params, Self::default())
+    }
+
+    pub fn from_params() -> Option<Self> {
+        Some(SensorCorrection {
+            gyro_off: get_f32s(GYRO_OFF_PARAMS)?,
+            acc_off: get_f32s(ACC_OFF_PARAMS)?,
+            acc_scale: get_f32s(ACC_SCALE_PARAMS)?,
+            mag_off: get_f32s(MAG_OFF_PARAMS)?,
+            mag_scale: soft_iron(get_f32s(MAG_SCALE_PARAMS)?, get_f32s(MAG_ODIAG_PARAMS)?),
+            trim: get_trim_q(get_f32(TRIM_ROLL_PARAM)?, get_f32(TRIM_PITCH_PARAM)?),
+        })
+    }
+
+    pub fn correct_gyro(&self, v: Vector3) -> Vector3 {
+        let v = to_array(v);
+        let v = [0, 1, 2].map(|i| v[i] - self.gyro_off[i]);
+        to_vector(point_rotation(self.trim, v))
+    }
+
+    pub fn correct_acc(&self, v: Vector3) -> Vector3 {
+        let v = to_array(v);
+        let v = [0, 1, 2].map(|i| (v[i] - self.acc_off[i]) * self.acc_scale[i]);
+        to_vector(point_rotation(self.trim, v))
+    }
+
+    pub fn correct_mag(&self, v: Vector3) -> Vector3 {
+        let v = to_array(v);
+        let v = [0, 1, 2].map(|i| v[i] - self.mag_off[i]);
+        to_vector(point_rotation(self.trim, mat_mul(&self.mag_scale, v)))
+    }
+
+    // the inverse of the corrections, the calibrations fit the raw sensor values
+    fn raw_gyro(&self, v: Vector3) -> [f32; 3] {
+        let v = frame_rotation(self.trim, to_array(v));
+        [0, 1, 2].map(|i| v[i] + self.gyro_off[i])
+    }
+
+    fn raw_acc(&self, v: Vector3) -> [f32; 3] {
+        let v = frame_rotation(self.trim, to_array(v));
+        [0, 1, 2].map(|i| v[i] / self.acc_scale[i] + self.acc_off[i])
+    }
+
+    // None if the soft iron matrix is singular
+    fn raw_mag(&self, v: Vector3) -> Option<[f32; 3]> {
+        let v = frame_rotation(self.trim, to_array(v));
+        let v = solve(self.mag_scale.map(|row| row.map(|x| x as f64)), v.map(|x| x as f64))?;
+        Some([0, 1, 2].map(|i| v[i] as f32 + self.mag_off[i]))
+    }
+}
+
+// the symmetric matrix from the diagonal and the off-diagonal elements
+fn soft_iron(diag: [f32; 3], odiag: [f32; 3]) -> [[f32; 3]; 3] {
+    let mut m = [[0.0; 3]; 3];
+    for i in 0..3 {
+        m[i][i] = diag[i];
+        let (a, b) = MAG_ODIAG_INDEX[i];
+        m[a][b] = odiag[i];
+        m[b][a] = odiag[i];
+    }
+    m
+}
+
+fn mat_mul(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+// accumulates samples while the vehicle keeps still.
+struct StillAverager {
+    sum: [f32; 3],
+    min: [f32; 3],
+    max: [f32; 3],
+    count: u32,
+    max_diff: f32,
+}
+
+impl StillAverager {
+    fn new(max_diff: f32) -> Self {
+        StillAverager {
+            sum: [0.0; 3],
+            min: [f32::MAX; 3],
+            max: [f32::MIN; 3],
+            count: 0,
+            max_diff,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = StillAverager::new(self.max_diff);
+    }
+
+    // return false if the vehicle moved, the samples before are dropped.
+    fn push(&mut self, v: [f32; 3]) -> bool {
+        for i in 0..3 {
+            self.min[i] = self.min[i].min(v[i]);
+            self.max[i] = self.max[i].max(v[i]);
+            if self.max[i] - self.min[i] > self.max_diff {
+                self.reset();
+                return false;
+            }
+            self.sum[i] += v[i];
+        }
+        self.count += 1;
+        true
+    }
+
+    fn mean(&self) -> [f32; 3] {
+        self.sum.map(|x| x / self.count as f32)
+    }
+}
+
+pub struct GyroCalibrator {
+    gyro: StillAverager,
+    acc: StillAverager,
+    samples: u32,
+    retries: u32,
+}
+
+impl GyroCalibrator {
+    const MAX_RETRIES: u32 = 5;
+
+    pub fn new(samples: u32) -> Self {
+        GyroCalibrator {
+            gyro: StillAverager::new(0.1),
+            acc: StillAverager::new(1.0),
+            samples,
+            retries: 0,
+        }
+    }
+
+    // feed a pair of gyro(rad/s) and acc(m/s^2) samples, the bias is returned when finished.
+    pub fn push(&mut self, gyro: [f32; 3], acc: [f32; 3]) -> Option<Result<[f32; 3], CalibError>> {
+        if !self.gyro.push(gyro) || !self.acc.push(acc) {
+            self.gyro.reset();
+            self.acc.reset();
+            self.retries += 1;
+            if self.retries > Self::MAX_RETRIES {
+                return Some(Err(CalibError::Moved));
+            }
+            return None;
+        }
+        if self.gyro.count >= self.samples {
+            return Some(Ok(self.gyro.mean()));
+        }
+        None
+    }
+}
+
+// six-position accelerometer calibration: every axis pointing up and down once.
+pub struct AccelCalibrator {
+    averager: StillAverager,
+    current_side: Option<usize>,
+    sides: [Option<[f32; 3]>; 6], // +x, -x, +y, -y, +z, -z up
+    samples: u32,
+}
+
+impl AccelCalibrator {
+    pub fn new(samples: u32) -> Self {
+        AccelCalibrator {
+            averager: StillAverager::new(0.5),
+            current_side: None,
+            sides: [None; 6],
+            samples,
+        }
+    }
+
+    // which axis is pointing up or down
+    fn detect_side(acc: [f32; 3]) -> Option<usize> {
+        for i in 0..3 {
+            let others = (0..3).filter(|j| *j != i).all(|j| acc[j].abs() < 0.2 * GRAVITY);
+            if acc[i].abs() > 0.8 * GRAVITY && others {
+                return Some(i * 2 + if acc[i] > 0.0 { 0 } else { 1 });
+            }
+        }
+        None
+    }
+
+    pub fn side_done(&self, side: usize) -> bool {
+        self.sides[side].is_some()
+    }
+
+    // return the side index which is just collected
+    pub fn push(&mut self, acc: [f32; 3]) -> Option<usize> {
+        let side = Self::detect_side(acc);
+        if side != self.current_side {
+            self.current_side = side;
+            self.averager.reset();
+        }
+        let side = side?;
+        if self.sides[side].is_some() || !self.averager.push(acc) {
+            return None;
+        }
+        if self.averager.count >= self.samples {
+            self.sides[side] = Some(self.averager.mean());
+            self.averager.reset();
+            return Some(side);
+        }
+        None
+    }
+
+    pub fn finished(&self) -> bool {
+        self.sides.iter().all(|x| x.is_some())
+    }
+
+    // return (offsets, scales)
+    pub fn result(&self) -> Result<([f32; 3], [f32; 3]), CalibError> {
+        if !self.finished() {
+            return Err(CalibError::BadData);
+        }
+        let mut offsets = [0.0; 3];
+        let mut scales = [1.0; 3];
+        for i in 0..3 {
+            let up = self.sides[i * 2].unwrap()[i];
+            let down = self.sides[i * 2 + 1].unwrap()[i];
+            offsets[i] = (up + down) / 2.0;
+            scales[i] = 2.0 * GRAVITY / (up - down);
+            if !(0.5..2.0).contains(&scales[i]) {
+                return Err(CalibError::BadData);
+            }
+        }
+        Ok((offsets, scales))
+    }
+}
+
+// solve a * x = b with gaussian elimination, None if a is singular.
+fn solve<const M: usize>(mut a: [[f64; M]; M], mut b: [f64; M]) -> Option<[f64; M]> {
+    for col in 0..M {
+        let pivot = (col..M).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col];
+        for row in col + 1..M {
+            let k = a[row][col] / pivot_row[col];
+            for (x, p) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *x -= k * p;
+            }
+            b[row] -= k * b[col];
+        }
+    }
+    let mut x = [0.0; M];
+    for row in (0..M).rev() {
+        let sum: f64 = (row + 1..M).map(|j| a[row][j] * x[j]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+// hard/soft iron calibration, the vehicle is rotated in every direction
+// and the samples are fitted to an ellipsoid of any orientation.
+pub struct MagCalibrator {
+    samples: Vec<[f32; 3]>,
+    count: usize,
+    min_dist: f32, // gauss, close samples are dropped
+}
+
+impl MagCalibrator {
+    pub fn new(count: usize, min_dist: f32) -> Self {
+        MagCalibrator {
+            samples: Vec::with_capacity(count),
+            count,
+            min_dist,
+        }
+    }
+
+    pub fn push(&mut self, mag: [f32; 3]) {
+        let far = self.samples.iter().all(|s| {
+            let d = [0, 1, 2].map(|i| s[i] - mag[i]);
+            quaternion_core::norm(d) > self.min_dist
+        });
+        if far && self.samples.len() < self.count {
+            self.samples.push(mag);
+        }
+    }
+
+    pub fn progress(&self) -> usize {
+        self.samples.len() * 100 / self.count
+    }
+
+    pub fn finished(&self) -> bool {
+        self.samples.len() >= self.count
+    }
+
+    // return (offsets, soft iron matrix), the matrix keeps the mean field strength.
+    pub fn result(&self) -> Result<([f32; 3], [[f32; 3]; 3]), CalibError> {
+        if !self.finished() {
+            return Err(CalibError::BadData);
+        }
+        // a*x^2 + b*y^2 + c*z^2 + 2f*yz + 2g*xz + 2h*xy + 2p*x + 2q*y + 2r*z = 1
+        let mut ata = [[0.0; 9]; 9];
+        let mut atb = [0.0; 9];
+        for s in &self.samples {
+            let [x, y, z] = s.map(|x| x as f64);
+            let row = [x * x, y * y, z * z, 2.0 * y * z, 2.0 * x * z, 2.0 * x * y, 2.0 * x, 2.0 * y, 2.0 * z];
+            for i in 0..9 {
+                for j in 0..9 {
+                    ata[i][j] += row[i] * row[j];
+                }
+                atb[i] += row[i];
+            }
+        }
+        let k = solve(ata, atb).ok_or(CalibError::BadData)?;
+        // (v - center)^T * A * (v - center) = 1 + center^T * A * center
+        let a = [[k[0], k[5], k[4]], [k[5], k[1], k[3]], [k[4], k[3], k[2]]];
+        let center = solve(a, [-k[6], -k[7], -k[8]]).ok_or(CalibError::BadData)?;
+        let g = 1.0 + (0..3).map(|i| (0..3).map(|j| center[i] * a[i][j] * center[j]).sum::<f64>()).sum::<f64>();
+        let (eigenvalues, vectors) = sym_eigen(a.map(|row| row.map(|x| x / g)));
+        if eigenvalues.iter().any(|x| *x <= 0.0) {
+            return Err(CalibError::BadData);
+        }
+        // the radii along the principal axes, each is scaled to the mean
+        let radii = eigenvalues.map(|x| 1.0 / x.sqrt());
+        let mean_radius = radii.iter().sum::<f64>() / 3.0;
+        let scales = radii.map(|r| mean_radius / r);
+        let mut matrix = [[0.0f32; 3]; 3];
+        for (i, row) in matrix.iter_mut().enumerate() {
+            for (j, x) in row.iter_mut().enumerate() {
+                *x = (0..3).map(|n| vectors[i][n] * scales[n] * vectors[j][n]).sum::<f64>() as f32;
+            }
+        }
+        let offsets = center.map(|x| x as f32);
+        let mean_radius = mean_radius as f32;
+
+        // the earth field is 0.25~0.65 gauss, leave some room for the sensor scale error
+        if !(0.1..2.0).contains(&mean_radius) || scales.iter().any(|s| !(0.5..2.0).contains(s)) {
+            return Err(CalibError::BadData);
+        }
+        // the fitted samples should be on a sphere now
+        let max_err = self
+            .samples
+            .iter()
+            .map(|s| {
+                let v = mat_mul(&matrix, [0, 1, 2].map(|i| s[i] - offsets[i]));
+                (quaternion_core::norm(v) - mean_radius).abs()
+            })
+            .fold(0.0, f32::max);
+        if max_err > 0.2 * mean_radius {
+            return Err(CalibError::BadData);
+        }
+        Ok((offsets, matrix))
+    }
+}
+
+// eigenvalues and eigenvectors(columns) of a symmetric matrix, jacobi rotations
+fn sym_eigen(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for _ in 0..50 {
+        // zero the largest off-diagonal element
+        let (p, q) = MAG_ODIAG_INDEX.into_iter().max_by(|x, y| a[x.0][x.1].abs().total_cmp(&a[y.0][y.1].abs())).unwrap();
+        if a[p][q].abs() < 1e-15 {
+            break;
+        }
+        let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+        let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+        let c = 1.0 / (t * t + 1.0).sqrt();
+        let s = t * c;
+        for row in a.iter_mut().chain(v.iter_mut()) {
+            let (xp, xq) = (row[p], row[q]);
+            row[p] = c * xp - s * xq;
+            row[q] = s * xp + c * xq;
+        }
+        let (ap, aq) = (a[p], a[q]);
+        for k in 0..3 {
+            a[p][k] = c * ap[k] - s * aq[k];
+            a[q][k] = s * ap[k] + c * aq[k];
+        }
+    }
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+// roll and pitch of the vehicle on a level surface, unit:rad
+pub fn get_level_trim(acc: [f32; 3]) -> (f32, f32) {
+    let roll = (-acc[0]).atan2(acc[2]);
+    let pitch = acc[1].atan2(acc[2]);
+    (roll, pitch)
+}
+
+fn run_gyro_calib(log: &dyn Fn(&str)) -> Result<(), CalibError> {
+    let mut gyro_rx = get_new_rx_of_message::<SensorGyroMsg>("gyro").unwrap();
+    let mut acc_rx = get_new_rx_of_message::<SensorAccelMsg>("acc").unwrap();
+
+    // the params are written only on success, the published data is corrected with the old ones
+    let correction = SensorCorrection::from_params().ok_or(CalibError::BadData)?;
+
+    log("gyro calibration: keep the vehicle still.");
+    let mut calibrator = GyroCalibrator::new(1000);
+    let mut acc = [0.0; 3];
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        if let Some(msg) = acc_rx.try_read() {
+            acc = to_array(msg.acc);
+        }
+        let gyro = correction.raw_gyro(read_before(|| gyro_rx.try_read(), deadline)?.rate);
+        if let Some(result) = calibrator.push(gyro, acc) {
+            let bias = result?;
+            set_all(&[0, 1, 2].map(|i| (GYRO_OFF_PARAMS[i], bias[i])))?;
+            log(&format!("gyro bias:{:?}", bias));
+            return Ok(());
+        }
+    }
+}
+
+fn run_accel_calib(log: &dyn Fn(&str)) -> Result<(), CalibError> {
+    let mut acc_rx = get_new_rx_of_message::<SensorAccelMsg>("acc").unwrap();
+    let correction = SensorCorrection::from_params().ok_or(CalibError::BadData)?;
+
+    const SIDE_NAMES: [&str; 6] = ["right side down", "left side down", "nose down", "nose up", "level", "upside down"];
+    log("accel calibration: place the vehicle still on each of the 6 sides.");
+    let mut calibrator = AccelCalibrator::new(200);
+    let deadline = Instant::now() + Duration::from_secs(120);
+    while !calibrator.finished() {
+        if let Some(side) = calibrator.push(correction.raw_acc(read_before(|| acc_rx.try_read(), deadline)?.acc)) {
+            let remain: Vec<&str> = (0..6)
+                .filter(|x| !calibrator.side_done(*x))
+                .map(|x| SIDE_NAMES[x])
+                .collect();
+            log(&format!("{} done, remain:{:?}", SIDE_NAMES[side], remain));
+        }
+    }
+    let (offsets, scales) = calibrator.result()?;
+    // the level trim was measured with the old offsets, it is cleared
+    let mut values = vec![(TRIM_ROLL_PARAM, 0.0), (TRIM_PITCH_PARAM, 0.0)];
+    for i in 0..3 {
+        values.push((ACC_OFF_PARAMS[i], offsets[i]));
+        values.push((ACC_SCALE_PARAMS[i], scales[i]));
+    }
+    set_all(&values)?;
+    log(&format!("acc offsets:{:?} scales:{:?}, run the level calibration again", offsets, scales));
+    Ok(())
+}
+
+fn run_level_calib(log: &dyn Fn(&str)) -> Result<(), CalibError> {
+    let mut acc_rx = get_new_rx_of_message::<SensorAccelMsg>("acc").unwrap();
+    // the published data is trimmed, the trim is measured without it
+    let trim = SensorCorrection::from_params().ok_or(CalibError::BadData)?.trim;
+
+    log("level calibration: keep the vehicle still on a level surface.");
+    let mut averager = StillAverager::new(0.5);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while averager.count < 500 {
+        averager.push(frame_rotation(trim, to_array(read_before(|| acc_rx.try_read(), deadline)?.acc)));
+    }
+    let (roll, pitch) = get_level_trim(averager.mean());
+    if roll.abs() > 0.35 || pitch.abs() > 0.35 {
+        return Err(CalibError::BadData);
+    }
+    set_all(&[(TRIM_ROLL_PARAM, roll), (TRIM_PITCH_PARAM, pitch)])?;
+    log(&format!("level trim roll:{} pitch:{}", roll, pitch));
+    Ok(())
+}
+
+fn run_mag_calib(log: &dyn Fn(&str)) -> Result<(), CalibError> {
+    let mut mag_rx = get_new_rx_of_message::<SensorMagMsg>("mag").unwrap();
+    // the params are written only on success, the published data is corrected with the old ones
+    let correction = SensorCorrection::from_params().ok_or(CalibError::BadData)?;
+
+    log("mag calibration: rotate the vehicle around all axes.");
+    let mut calibrator = MagCalibrator::new(300, 0.02);
+    let mut last_progress = 0;
+    let deadline = Instant::now() + Duration::from_secs(120);
+    while !calibrator.finished() {
+        let mag = read_before(|| mag_rx.try_read(), deadline)?.mag;
+        calibrator.push(correction.raw_mag(mag).ok_or(CalibError::BadData)?);
+        if calibrator.progress() >= last_progress + 10 {
+            last_progress = calibrator.progress();
+            log(&format!("mag calibration progress:{}%", last_progress));
+        }
+    }
+    let (offsets, scales) = calibrator.result()?;
+    let mut values = Vec::new();
+    for i in 0..3 {
+        let (a, b) = MAG_ODIAG_INDEX[i];
+        values.push((MAG_OFF_PARAMS[i], offsets[i]));
+        values.push((MAG_SCALE_PARAMS[i], scales[i][i]));
+        values.push((MAG_ODIAG_PARAMS[i], scales[a][b]));
+    }
+    set_all(&values)?;
+    log(&format!("mag offsets:{:?} scales:{:?}", offsets, scales));
+    Ok(())
+}
+
+pub fn run_calibration(calib_type: CalibType, log: &dyn Fn(&str)) -> Result<(), CalibError> {
+    if CALIBRATING.swap(true, Ordering::SeqCst) {
+        return Err(CalibError::Busy);
+    }
+    let ret = match calib_type {
+        CalibType::Gyro => run_gyro_calib(log),
+        CalibType::Accel => run_accel_calib(log),
+        CalibType::Level => run_level_calib(log),
+        CalibType::Mag => run_mag_calib(log),
+        CalibType::Rc => crate::rc_update::run_rc_calib(log),
+    };
+    CALIBRATING.store(false, Ordering::SeqCst);
+    ret
+}
+
+fn sensor_calib_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        let ret = run_calibration(args.calib_type, &|s| thread_logln!("{}", s));
+        thread_logln!("{:?} calibration result:{:?}", args.calib_type, ret);
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    for name in GYRO_OFF_PARAMS.iter().chain(ACC_OFF_PARAMS.iter()).chain(MAG_OFF_PARAMS.iter()).chain(MAG_ODIAG_PARAMS.iter()) {
+        param::add_param(name, ParameterData::Float(0.0));
+    }
+    for name in ACC_SCALE_PARAMS.iter().chain(MAG_SCALE_PARAMS.iter()) {
+        param::add_param(name, ParameterData::Float(1.0));
+    }
+    param::add_param(TRIM_ROLL_PARAM, ParameterData::Float(0.0));
+    param::add_param(TRIM_PITCH_PARAM, ParameterData::Float(0.0));
+    rpos::module::Module::register("sensor_calib", sensor_calib_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_eq(a: [f32; 3], b: [f32; 3], eps: f32) {
+        for i in 0..3 {
+            assert!((a[i] - b[i]).abs() < eps, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_correction() {
+        // the shared cal_* params are not written here, other tests may read them
+        assert!(SensorCorrection::from_params().is_some());
+        let v = Vector3 { x: 0.1, y: -0.2, z: 0.3 };
+        check_eq(to_array(SensorCorrection::default().correct_gyro(v)), to_array(v), 1e-6);
+        let correction = SensorCorrection { gyro_off: [0.0, 0.0, 0.5], ..Default::default() };
+        assert_eq!(correction.correct_gyro(Vector3::default()).z, -0.5);
+        assert_eq!(set_f32("cal_not_exist", 1.0), Err(CalibError::ParamWrite));
+
+        // the raw values give back the published ones
+        let correction = SensorCorrection {
+            gyro_off: [0.01, -0.02, 0.03],
+            acc_off: [0.2, -0.3, 0.5],
+            acc_scale: [1.02, 0.98, 1.05],
+            trim: get_trim_q(0.05, -0.03),
+            ..Default::default()
+        };
+        check_eq(to_array(correction.correct_gyro(to_vector(correction.raw_gyro(v)))), to_array(v), 1e-6);
+        check_eq(to_array(correction.correct_acc(to_vector(correction.raw_acc(v)))), to_array(v), 1e-6);
+        let correction = SensorCorrection {
+            mag_off: [0.12, -0.3, 0.05],
+            mag_scale: soft_iron([1.1, 0.9, 1.0], [0.12, -0.06, 0.05]),
+            ..correction
+        };
+        check_eq(to_array(correction.correct_mag(to_vector(correction.raw_mag(v).unwrap()))), to_array(v), 1e-5);
+        let singular = SensorCorrection { mag_scale: [[0.0; 3]; 3], ..correction };
+        assert!(singular.raw_mag(v).is_none());
+    }
+
+    #[test]
+    fn test_set_all() {
+        param::add_param("cal_test_a", ParameterData::Float(1.0));
+        param::add_param("cal_test_b", ParameterData::Float(2.0));
+        assert_eq!(set_all(&[("cal_test_a", 3.0), ("cal_not_exist", 1.0)]), Err(CalibError::ParamWrite));
+        assert_eq!(get_f32("cal_test_a"), Some(1.0));
+        set_all(&[("cal_test_a", 3.0), ("cal_test_b", 4.0)]).unwrap();
+        assert_eq!((get_f32("cal_test_a"), get_f32("cal_test_b")), (Some(3.0), Some(4.0)));
+    }
+
+    #[test]
+    fn test_sensor_absent() {
+        // nothing publishes it
+        rpos::msg::add_message::<SensorGyroMsg>("gyro_calib_test");
+        let mut rx = get_new_rx_of_message::<SensorGyroMsg>("gyro_calib_test").unwrap();
+        let start = Instant::now();
+        let ret = read_before(|| rx.try_read(), start + Duration::from_millis(50));
+        assert_eq!(ret.err(), Some(CalibError::Timeout));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_gyro_calib() {
+        let bias = [0.01, -0.02, 0.005];
+        let mut calibrator = GyroCalibrator::new(100);
+        let mut ret = None;
+        for i in 0..100 {
+            let noise = if i % 2 == 0 { 0.001 } else { -0.001 };
+            ret = calibrator.push(bias.map(|x| x + noise), [0.0, 0.0, GRAVITY]);
+        }
+        check_eq(ret.unwrap().unwrap(), bias, 1e-4);
+    }
+
+    #[test]
+    fn test_gyro_calib_moved() {
+        let mut calibrator = GyroCalibrator::new(100);
+        let mut ret = None;
+        for i in 0..100 {
+            let rate = if i % 10 == 0 { 1.0 } else { 0.0 };
+            ret = ret.or(calibrator.push([rate, 0.0, 0.0], [0.0, 0.0, GRAVITY]));
+        }
+        assert_eq!(ret.unwrap(), Err(CalibError::Moved));
+    }
+
+    #[test]
+    fn test_accel_calib() {
+        let offsets = [0.2, -0.3, 0.5];
+        let scales = [1.02, 0.98, 1.05];
+        // raw = corrected / scale + offset
+        let raw = |v: [f32; 3]| [0, 1, 2].map(|i| v[i] / scales[i] + offsets[i]);
+
+        let mut calibrator = AccelCalibrator::new(50);
+        for side in 0..6 {
+            let mut v = [0.0; 3];
+            v[side / 2] = if side % 2 == 0 { GRAVITY } else { -GRAVITY };
+            assert!(!calibrator.side_done(side));
+            let mut done = None;
+            for _ in 0..50 {
+                done = done.or(calibrator.push(raw(v)));
+            }
+            assert_eq!(done, Some(side));
+        }
+        let (off, scale) = calibrator.result().unwrap();
+        check_eq(off, offsets, 1e-4);
+        check_eq(scale, scales, 1e-4);
+    }
+
+    #[test]
+    fn test_level_trim() {
+        let q = get_trim_q(0.05, -0.03);
+        let acc = frame_rotation(q, [0.0, 0.0, GRAVITY]);
+        let (roll, pitch) = get_level_trim(acc);
+        assert!((roll - 0.05).abs() < 1e-3);
+        assert!((pitch + 0.03).abs() < 1e-3);
+
+        let correction = SensorCorrection {
+            gyro_off: [0.0; 3],
+            acc_off: [0.0; 3],
+            acc_scale: [1.0; 3],
+            mag_off: [0.0; 3],
+            mag_scale: soft_iron([1.0; 3], [0.0; 3]),
+            trim: get_trim_q(roll, pitch),
+        };
+        let corrected = correction.correct_acc(to_vector(acc));
+        check_eq(to_array(corrected), [0.0, 0.0, GRAVITY], 1e-2);
+    }
+
+    // a spiral over the sphere of the field, distorted by the soft iron then offset
+    fn mag_samples(calibrator: &mut MagCalibrator, soft_iron: &[[f32; 3]; 3], offsets: [f32; 3], field: f32) {
+        for i in 0..2000 {
+            let t = i as f32 / 2000.0;
+            let z = 1.0 - 2.0 * t;
+            let r = (1.0 - z * z).sqrt();
+            let a = t * 60.0;
+            let v = mat_mul(soft_iron, [r * a.cos(), r * a.sin(), z].map(|x| x * field));
+            calibrator.push([0, 1, 2].map(|i| v[i] + offsets[i]));
+        }
+    }
+
+    #[test]
+    fn test_mag_calib() {
+        let offsets = [0.12, -0.3, 0.05];
+        let scales = [1.1, 0.9, 1.0];
+        let field = 0.5;
+        let mut calibrator = MagCalibrator::new(300, 0.02);
+        mag_samples(&mut calibrator, &soft_iron(scales.map(|x| 1.0 / x), [0.0; 3]), offsets, field);
+        assert!(calibrator.finished());
+        let (off, scale) = calibrator.result().unwrap();
+        check_eq(off, offsets, 1e-3);
+        // the scales are normalized by the mean radius
+        let k = scale[2][2] / scales[2];
+        check_eq([0, 1, 2].map(|i| scale[i][i] / k), scales, 1e-3);
+        check_eq([scale[0][1], scale[0][2], scale[1][2]], [0.0; 3], 1e-3);
+
+        let correction = SensorCorrection {
+            gyro_off: [0.0; 3],
+            acc_off: [0.0; 3],
+            acc_scale: [1.0; 3],
+            mag_off: off,
+            mag_scale: scale,
+            trim: (1.0, [0.0; 3]),
+        };
+        let raw = [offsets[0], field / scales[1] + offsets[1], offsets[2]];
+        let corrected = to_array(correction.correct_mag(to_vector(raw)));
+        assert!(corrected[0].abs() < 1e-3 && corrected[2].abs() < 1e-3);
+        assert!((corrected[1] - field).abs() < 0.05 * field);
+    }
+
+    #[test]
+    fn test_mag_calib_rotated() {
+        // the soft iron distortion is not along the sensor axes
+        let offsets = [-0.08, 0.2, 0.15];
+        let distortion = [[1.1, 0.12, -0.06], [0.12, 0.9, 0.05], [-0.06, 0.05, 1.0]];
+        let field = 0.45;
+        let mut calibrator = MagCalibrator::new(300, 0.02);
+        mag_samples(&mut calibrator, &distortion, offsets, field);
+        let (off, scale) = calibrator.result().unwrap();
+        check_eq(off, offsets, 1e-3);
+        // the inverse of the distortion up to the normalization
+        let product = distortion.map(|row| [0, 1, 2].map(|j| (0..3).map(|n| row[n] * scale[n][j]).sum::<f32>()));
+        let k = product[0][0];
+        for i in 0..3 {
+            check_eq(product[i].map(|x| x / k), [0, 1, 2].map(|j| if i == j { 1.0 } else { 0.0 }), 1e-3);
+            check_eq(scale[i], [0, 1, 2].map(|j| scale[j][i]), 1e-6);
+        }
+
+        // the diagonal only fit leaves the field strength changing with the direction
+        let diagonal = soft_iron([0, 1, 2].map(|i| scale[i][i]), [0.0; 3]);
+        let strength = |m: &[[f32; 3]; 3], v: [f32; 3]| quaternion_core::norm(mat_mul(m, mat_mul(&distortion, v)));
+        let dirs = [[1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [0.0, 1.0, 1.0], [0.0, 1.0, -1.0]].map(|v| v.map(|x| x * field / 2f32.sqrt()));
+        let spread = |m: &[[f32; 3]; 3]| {
+            let s = dirs.map(|v| strength(m, v));
+            s.iter().fold(0.0f32, |a, b| a.max(*b)) - s.iter().fold(f32::MAX, |a, b| a.min(*b))
+        };
+        assert!(spread(&scale) < 1e-3 * field);
+        assert!(spread(&diagonal) > 0.05 * field);
+
+        let correction = SensorCorrection {
+            gyro_off: [0.0; 3],
+            acc_off: [0.0; 3],
+            acc_scale: [1.0; 3],
+            mag_off: off,
+            mag_scale: scale,
+            trim: (1.0, [0.0; 3]),
+        };
+        let raw = mat_mul(&distortion, [0.0, 0.0, field]);
+        let corrected = to_array(correction.correct_mag(to_vector([0, 1, 2].map(|i| raw[i] + offsets[i]))));
+        assert!((quaternion_core::norm(corrected) - k * field).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_mag_calib_bad_data() {
+        // the vehicle is not rotated, all the samples are on a small arc
+        let mut calibrator = MagCalibrator::new(50, 0.0005);
+        for i in 0..50 {
+            let a = i as f32 * 0.002;
+            calibrator.push([0.5 * a.cos(), 0.5 * a.sin(), 0.0]);
+        }
+        assert!(calibrator.finished());
+        assert_eq!(calibrator.result(), Err(CalibError::BadData));
+    }
+}
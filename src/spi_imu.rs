@@ -0,0 +1,601 @@
+use std::{f32::consts::PI, io, time::Duration};
+
+use clap::{Parser, ValueEnum};
+use rpos::{libc::c_long, pthread_scheduler::SchedulePthread, thread_logln};
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+
+use crate::{
+    basic::rotation::Rotation,
+    msg_define::{Publisher, SensorAccelMsg, SensorGyroMsg, Vector3},
+    param::CachedParams,
+    sensor_calib::SensorCorrection,
+};
+
+const GRAVITY: f32 = 9.80665;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ImuChip {
+    Mpu6000,
+    Icm42688,
+}
+
+impl ImuChip {
+    // the clock limit of the register access, the sensor data can be read faster
+    fn reg_max_freq(self) -> u32 {
+        match self {
+            ImuChip::Mpu6000 => 1_000_000,
+            ImuChip::Icm42688 => 24_000_000,
+        }
+    }
+}
+
+#[derive(Parser, Clone)]
+#[command(name = "spi_imu", about = "spi imu driver(mpu6000/icm42688), publish gyro and acc")]
+struct Cli {
+    #[arg(short, long, value_enum)]
+    chip: ImuChip,
+
+    #[arg(short, long, default_value_t = 1000, help = "sample rate, Hz")]
+    rate: u32,
+
+    #[arg(long, default_value_t = 2000, help = "gyro range, deg/s")]
+    gyro_range: u32,
+
+    #[arg(long, default_value_t = 16, help = "accelerometer range, g")]
+    acc_range: u32,
+
+    #[arg(long, default_value_t = 1000, help = "publish period, us")]
+    period: u32,
+
+    #[arg(long, default_value_t = 10_000_000, help = "spi clock of the fifo reads, Hz")]
+    spi_max_freq: u32,
+
+    dev_name: String,
+}
+
+mod mpu6000_regs {
+    pub const SMPLRT_DIV: u8 = 0x19;
+    pub const CONFIG: u8 = 0x1A;
+    pub const GYRO_CONFIG: u8 = 0x1B;
+    pub const ACCEL_CONFIG: u8 = 0x1C;
+    pub const FIFO_EN: u8 = 0x23;
+    pub const TEMP_OUT_H: u8 = 0x41;
+    pub const USER_CTRL: u8 = 0x6A;
+    pub const PWR_MGMT_1: u8 = 0x6B;
+    pub const FIFO_COUNTH: u8 = 0x72;
+    pub const FIFO_R_W: u8 = 0x74;
+    pub const WHO_AM_I: u8 = 0x75;
+
+    pub const WHO_AM_I_VAL: u8 = 0x68;
+    pub const FIFO_SAMPLE_SIZE: usize = 12; // accel xyz, gyro xyz
+}
+
+mod icm42688_regs {
+    pub const DEVICE_CONFIG: u8 = 0x11;
+    pub const FIFO_CONFIG: u8 = 0x16;
+    pub const FIFO_COUNTH: u8 = 0x2E;
+    pub const FIFO_DATA: u8 = 0x30;
+    pub const SIGNAL_PATH_RESET: u8 = 0x4B;
+    pub const PWR_MGMT0: u8 = 0x4E;
+    pub const GYRO_CONFIG0: u8 = 0x4F;
+    pub const ACCEL_CONFIG0: u8 = 0x50;
+    pub const FIFO_CONFIG1: u8 = 0x5F;
+    pub const WHO_AM_I: u8 = 0x75;
+    pub const REG_BANK_SEL: u8 = 0x76;
+
+    pub const WHO_AM_I_VAL: u8 = 0x47;
+    pub const FIFO_SAMPLE_SIZE: usize = 16; // header, accel xyz, gyro xyz, temp, timestamp
+    pub const FIFO_HEADER_EMPTY: u8 = 0x80;
+}
+
+// max samples read in one transfer, so that the transfer buffer has a fixed size.
+const MAX_FIFO_SAMPLES: usize = 32;
+
+pub trait SpiDevice {
+    // full duplex transfer, rx.len() == tx.len(), speed_hz is the clock of this transfer
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8], speed_hz: u32) -> io::Result<()>;
+}
+
+impl SpiDevice for Spidev {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8], speed_hz: u32) -> io::Result<()> {
+        let mut trans = SpidevTransfer::read_write(tx, rx);
+        trans.speed_hz = speed_hz;
+        Spidev::transfer(self, &mut trans)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RawSample {
+    acc: [i16; 3],
+    gyro: [i16; 3],
+}
+
+struct SpiImu<S: SpiDevice> {
+    spi: S,
+    chip: ImuChip,
+    device_id: u32,
+    max_freq: u32, // spi clock of the fifo reads, Hz
+    sample_rate: u32,
+    gyro_scale: f32, // rad/s per LSB
+    acc_scale: f32,  // m/s^2 per LSB
+    rotation: Rotation,
+    error_count: u32,
+    correction: CachedParams<SensorCorrection>,
+}
+
+impl<S: SpiDevice> SpiImu<S> {
+    fn new(spi: S, chip: ImuChip, device_id: u32, max_freq: u32) -> Self {
+        SpiImu {
+            spi,
+            chip,
+            device_id,
+            max_freq,
+            sample_rate: 1000,
+            gyro_scale: 0.0,
+            acc_scale: 0.0,
+            rotation: Rotation::None,
+            error_count: 0,
+            correction: SensorCorrection::cached(),
+        }
+    }
+
+    fn reg_freq(&self) -> u32 {
+        self.max_freq.min(self.chip.reg_max_freq())
+    }
+
+    fn read_regs(&mut self, reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        self.read_regs_at(reg, buf, self.reg_freq())
+    }
+
+    fn read_regs_at(&mut self, reg: u8, buf: &mut [u8], speed_hz: u32) -> io::Result<()> {
+        let mut tx = [0u8; 1 + MAX_FIFO_SAMPLES * icm42688_regs::FIFO_SAMPLE_SIZE];
+        let mut rx = [0u8; 1 + MAX_FIFO_SAMPLES * icm42688_regs::FIFO_SAMPLE_SIZE];
+        let len = buf.len() + 1;
+        tx[0] = reg | 0x80;
+        self.spi.transfer(&tx[..len], &mut rx[..len], speed_hz)?;
+        buf.copy_from_slice(&rx[1..len]);
+        Ok(())
+    }
+
+    fn read_reg(&mut self, reg: u8) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_regs(reg, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn write_reg(&mut self, reg: u8, val: u8) -> io::Result<()> {
+        let mut rx = [0u8; 2];
+        let speed_hz = self.reg_freq();
+        self.spi.transfer(&[reg & 0x7F, val], &mut rx, speed_hz)
+    }
+
+    fn init(&mut self, rate: u32, gyro_range: u32, acc_range: u32) -> io::Result<()> {
+        let range_err = |what: &str, val: u32| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("unsupport {} :{}", what, val))
+        };
+
+        // (range, register value) pairs of each chip
+        let (gyro_ranges, acc_ranges): (&[(u32, u8)], &[(u32, u8)]) = match self.chip {
+            ImuChip::Mpu6000 => (
+                &[(250, 0), (500, 1), (1000, 2), (2000, 3)],
+                &[(2, 0), (4, 1), (8, 2), (16, 3)],
+            ),
+            ImuChip::Icm42688 => (
+                &[(250, 3), (500, 2), (1000, 1), (2000, 0)],
+                &[(2, 3), (4, 2), (8, 1), (16, 0)],
+            ),
+        };
+        let gyro_fs = gyro_ranges
+            .iter()
+            .find(|x| x.0 == gyro_range)
+            .ok_or(range_err("gyro range", gyro_range))?
+            .1;
+        let acc_fs = acc_ranges
+            .iter()
+            .find(|x| x.0 == acc_range)
+            .ok_or(range_err("acc range", acc_range))?
+            .1;
+
+        self.gyro_scale = gyro_range as f32 / 32768.0 / 180.0 * PI;
+        self.acc_scale = acc_range as f32 / 32768.0 * GRAVITY;
+
+        match self.chip {
+            ImuChip::Mpu6000 => {
+                use mpu6000_regs::*;
+                // with the dlpf enabled, the internal sample rate is 1kHz
+                if rate == 0 || rate > 1000 || 1000 % rate != 0 {
+                    return Err(range_err("sample rate", rate));
+                }
+                self.write_reg(PWR_MGMT_1, 0x80)?; // reset
+                std::thread::sleep(Duration::from_millis(100));
+                self.check_who_am_i(WHO_AM_I, WHO_AM_I_VAL)?;
+                self.write_reg(PWR_MGMT_1, 0x01)?; // clock source: pll with x gyro
+                self.write_reg(USER_CTRL, 0x10)?; // disable i2c interface
+                self.write_reg(SMPLRT_DIV, (1000 / rate - 1) as u8)?;
+                self.write_reg(CONFIG, 0x02)?; // dlpf: 98Hz
+                self.write_reg(GYRO_CONFIG, gyro_fs << 3)?;
+                self.write_reg(ACCEL_CONFIG, acc_fs << 3)?;
+                self.write_reg(FIFO_EN, 0x78)?; // gyro xyz, accel
+                self.write_reg(USER_CTRL, 0x10 | 0x40 | 0x04)?; // fifo enable and reset
+            }
+            ImuChip::Icm42688 => {
+                use icm42688_regs::*;
+                let odr = match rate {
+                    8000 => 3,
+                    4000 => 4,
+                    2000 => 5,
+                    1000 => 6,
+                    500 => 15,
+                    200 => 7,
+                    100 => 8,
+                    _ => return Err(range_err("sample rate", rate)),
+                };
+                self.write_reg(REG_BANK_SEL, 0)?;
+                self.write_reg(DEVICE_CONFIG, 0x01)?; // soft reset
+                std::thread::sleep(Duration::from_millis(10));
+                self.check_who_am_i(WHO_AM_I, WHO_AM_I_VAL)?;
+                self.write_reg(FIFO_CONFIG, 0x40)?; // stream to fifo
+                self.write_reg(FIFO_CONFIG1, 0x07)?; // accel, gyro, temp
+                self.write_reg(GYRO_CONFIG0, gyro_fs << 5 | odr)?;
+                self.write_reg(ACCEL_CONFIG0, acc_fs << 5 | odr)?;
+                self.write_reg(PWR_MGMT0, 0x0F)?; // gyro and accel in low noise mode
+                std::thread::sleep(Duration::from_millis(1));
+                self.write_reg(SIGNAL_PATH_RESET, 0x02)?; // fifo flush
+            }
+        }
+        self.sample_rate = rate;
+        Ok(())
+    }
+
+    fn check_who_am_i(&mut self, reg: u8, expect: u8) -> io::Result<()> {
+        let id = self.read_reg(reg)?;
+        if id != expect {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{:?} who am i mismatch: {:#x}", self.chip, id),
+            ));
+        }
+        Ok(())
+    }
+
+    fn reset_fifo(&mut self) -> io::Result<()> {
+        match self.chip {
+            ImuChip::Mpu6000 => self.write_reg(mpu6000_regs::USER_CTRL, 0x10 | 0x40 | 0x04),
+            ImuChip::Icm42688 => self.write_reg(icm42688_regs::SIGNAL_PATH_RESET, 0x02),
+        }
+    }
+
+    // read all samples in the fifo, temperature in degC is returned if the chip provides it.
+    fn read_fifo(&mut self, samples: &mut Vec<RawSample>) -> io::Result<f32> {
+        let (count_reg, data_reg, sample_size) = match self.chip {
+            ImuChip::Mpu6000 => (
+                mpu6000_regs::FIFO_COUNTH,
+                mpu6000_regs::FIFO_R_W,
+                mpu6000_regs::FIFO_SAMPLE_SIZE,
+            ),
+            ImuChip::Icm42688 => (
+                icm42688_regs::FIFO_COUNTH,
+                icm42688_regs::FIFO_DATA,
+                icm42688_regs::FIFO_SAMPLE_SIZE,
+            ),
+        };
+
+        let mut count = [0u8; 2];
+        self.read_regs(count_reg, &mut count)?;
+        let bytes = u16::from_be_bytes(count) as usize;
+        let n = (bytes / sample_size).min(MAX_FIFO_SAMPLES);
+
+        let mut buf = [0u8; MAX_FIFO_SAMPLES * icm42688_regs::FIFO_SAMPLE_SIZE];
+        let buf = &mut buf[..n * sample_size];
+        // only the burst of the sensor data is read at the full clock
+        if n > 0 {
+            self.read_regs_at(data_reg, buf, self.max_freq)?;
+        }
+
+        let be = |b: &[u8], i: usize| i16::from_be_bytes([b[i], b[i + 1]]);
+        let mut temperature = f32::NAN;
+        for frame in buf.chunks_exact(sample_size) {
+            match self.chip {
+                ImuChip::Mpu6000 => samples.push(RawSample {
+                    acc: [be(frame, 0), be(frame, 2), be(frame, 4)],
+                    gyro: [be(frame, 6), be(frame, 8), be(frame, 10)],
+                }),
+                ImuChip::Icm42688 => {
+                    if frame[0] & icm42688_regs::FIFO_HEADER_EMPTY != 0 {
+                        continue;
+                    }
+                    samples.push(RawSample {
+                        acc: [be(frame, 1), be(frame, 3), be(frame, 5)],
+                        gyro: [be(frame, 7), be(frame, 9), be(frame, 11)],
+                    });
+                    temperature = frame[13] as i8 as f32 / 2.07 + 25.0;
+                }
+            }
+        }
+
+        if self.chip == ImuChip::Mpu6000 {
+            let mut temp = [0u8; 2];
+            self.read_regs(mpu6000_regs::TEMP_OUT_H, &mut temp)?;
+            temperature = i16::from_be_bytes(temp) as f32 / 340.0 + 36.53;
+        }
+        Ok(temperature)
+    }
+
+    // read the fifo and average the samples into one gyro and one acc message
+    fn collect(&mut self) -> Option<(SensorGyroMsg, SensorAccelMsg)> {
+        let mut samples = Vec::with_capacity(MAX_FIFO_SAMPLES);
+        let temperature = match self.read_fifo(&mut samples) {
+            Ok(t) => t,
+            Err(_) => {
+                self.error_count += 1;
+                let _ = self.reset_fifo();
+                return None;
+            }
+        };
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut gyro_sum = [0.0f32; 3];
+        let mut acc_sum = [0.0f32; 3];
+        let mut clip_count = [0u8; 3];
+        for sample in &samples {
+            for i in 0..3 {
+                gyro_sum[i] += sample.gyro[i] as f32;
+                acc_sum[i] += sample.acc[i] as f32;
+                if sample.acc[i] == i16::MAX || sample.acc[i] == i16::MIN {
+                    clip_count[i] = clip_count[i].saturating_add(1);
+                }
+            }
+        }
+
+        let n = samples.len() as f32;
+        let delta_dt = (samples.len() as u64 * 1000_000 / self.sample_rate as u64) as u32;
+        let dt = delta_dt as f32 / 1000_000.0;
+
+        let correction = *self.correction.get();
+        let rate = correction.correct_gyro(self.rotation.rotate_v(Vector3 {
+            x: gyro_sum[0] / n * self.gyro_scale,
+            y: gyro_sum[1] / n * self.gyro_scale,
+            z: gyro_sum[2] / n * self.gyro_scale,
+        }));
+        let acc = correction.correct_acc(self.rotation.rotate_v(Vector3 {
+            x: acc_sum[0] / n * self.acc_scale,
+            y: acc_sum[1] / n * self.acc_scale,
+            z: acc_sum[2] / n * self.acc_scale,
+        }));
+
+        let gyro_msg = SensorGyroMsg {
+            device_id: self.device_id,
+            temperature,
+            error_count: self.error_count,
+            samples: samples.len() as u8,
+            rate,
+            delta_angle: Vector3 { x: rate.x * dt, y: rate.y * dt, z: rate.z * dt },
+            delta_dt,
+            ..Default::default()
+        };
+        let acc_msg = SensorAccelMsg {
+            device_id: self.device_id,
+            temperature,
+            error_count: self.error_count,
+            samples: samples.len() as u8,
+            clip_count,
+            acc,
+            delta_velocity: Vector3 { x: acc.x * dt, y: acc.y * dt, z: acc.z * dt },
+            delta_dt,
+            ..Default::default()
+        };
+        Some((gyro_msg, acc_msg))
+    }
+}
+
+// device id: [chip type:8][spi bus:8][chip select:8]
+fn get_device_id(chip: ImuChip, dev_name: &str) -> u32 {
+    let mut id = (chip as u32 + 1) << 16;
+    if let Some((bus, cs)) = dev_name
+        .trim_start_matches("/dev/spidev")
+        .split_once('.')
+    {
+        id |= bus.parse::<u32>().unwrap_or(0) << 8 | cs.parse::<u32>().unwrap_or(0);
+    }
+    id
+}
+
+fn open_spidev(dev_name: &str, max_freq: u32) -> io::Result<Spidev> {
+    let mut spidev = Spidev::open(dev_name)?;
+    let option = SpidevOptions::new()
+        .bits_per_word(8)
+        .max_speed_hz(max_freq)
+        .mode(SpiModeFlags::SPI_MODE_3)
+        .build();
+    spidev.configure(&option)?;
+    Ok(spidev)
+}
+
+pub fn spi_imu_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        let spidev = match open_spidev(&args.dev_name, args.spi_max_freq) {
+            Ok(dev) => dev,
+            Err(e) => {
+                thread_logln!("open {} failed: {}", args.dev_name, e);
+                return;
+            }
+        };
+        let mut imu = SpiImu::new(spidev, args.chip, get_device_id(args.chip, &args.dev_name), args.spi_max_freq);
+        imu.rotation = Rotation::from_params("imu_rot");
+        if let Err(e) = imu.init(args.rate, args.gyro_range, args.acc_range) {
+            thread_logln!("{:?} init failed: {}", args.chip, e);
+            return;
+        }
+
+        let gyro_tx = Publisher::<SensorGyroMsg>::new("gyro");
+        let acc_tx = Publisher::<SensorAccelMsg>::new("acc");
+        let period = args.period as c_long;
+        SchedulePthread::new_fifo(
+            1024 * 1024,
+            98,
+            Box::new(move |s| loop {
+                if let Some((gyro_msg, acc_msg)) = imu.collect() {
+                    gyro_tx.send(gyro_msg);
+                    acc_tx.send(acc_msg);
+                }
+                s.schedule_until(period);
+            }),
+        );
+        thread_logln!("{:?} on {} started!", args.chip, args.dev_name);
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    Rotation::add_params("imu_rot", Rotation::None);
+    rpos::module::Module::register("spi_imu", spi_imu_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+
+    use super::*;
+
+    // a fake spi device, which answers register reads from a register map,
+    // replays the queued fifo bytes and records all the register writes.
+    struct MockSpi {
+        regs: HashMap<u8, u8>,
+        fifo_reg: u8,
+        fifo: VecDeque<u8>,
+        writes: Vec<(u8, u8)>,
+        speeds: Vec<(u8, u32)>, // register and clock of every transfer
+    }
+
+    impl MockSpi {
+        fn new(who_am_i: u8, fifo_reg: u8) -> Self {
+            let mut regs = HashMap::new();
+            regs.insert(0x75, who_am_i);
+            MockSpi {
+                regs,
+                fifo_reg,
+                fifo: VecDeque::new(),
+                writes: Vec::new(),
+                speeds: Vec::new(),
+            }
+        }
+
+        fn push_fifo(&mut self, count_reg: u8, bytes: &[u8]) {
+            self.fifo.extend(bytes);
+            let len = (self.fifo.len() as u16).to_be_bytes();
+            self.regs.insert(count_reg, len[0]);
+            self.regs.insert(count_reg + 1, len[1]);
+        }
+    }
+
+    impl SpiDevice for MockSpi {
+        fn transfer(&mut self, tx: &[u8], rx: &mut [u8], speed_hz: u32) -> io::Result<()> {
+            let reg = tx[0] & 0x7F;
+            self.speeds.push((reg, speed_hz));
+            if tx[0] & 0x80 == 0 {
+                self.writes.push((reg, tx[1]));
+                return Ok(());
+            }
+            for i in 1..rx.len() {
+                rx[i] = if reg == self.fifo_reg {
+                    self.fifo.pop_front().unwrap_or(0)
+                } else {
+                    *self.regs.get(&(reg + i as u8 - 1)).unwrap_or(&0)
+                };
+            }
+            Ok(())
+        }
+    }
+
+    fn mpu6000_frame(acc: [i16; 3], gyro: [i16; 3]) -> Vec<u8> {
+        acc.iter().chain(gyro.iter()).flat_map(|x| x.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn test_mpu6000_init() {
+        let spi = MockSpi::new(mpu6000_regs::WHO_AM_I_VAL, mpu6000_regs::FIFO_R_W);
+        let mut imu = SpiImu::new(spi, ImuChip::Mpu6000, 0, 10_000_000);
+        imu.init(500, 2000, 8).unwrap();
+        let writes = &imu.spi.writes;
+        assert_eq!(writes[0], (mpu6000_regs::PWR_MGMT_1, 0x80));
+        assert!(writes.contains(&(mpu6000_regs::SMPLRT_DIV, 1)));
+        assert!(writes.contains(&(mpu6000_regs::GYRO_CONFIG, 3 << 3)));
+        assert!(writes.contains(&(mpu6000_regs::ACCEL_CONFIG, 2 << 3)));
+        assert!(writes.contains(&(mpu6000_regs::FIFO_EN, 0x78)));
+
+        assert!(imu.init(300, 2000, 8).is_err());
+        assert!(imu.init(500, 300, 8).is_err());
+    }
+
+    #[test]
+    fn test_who_am_i_mismatch() {
+        let spi = MockSpi::new(0x00, mpu6000_regs::FIFO_R_W);
+        let mut imu = SpiImu::new(spi, ImuChip::Mpu6000, 0, 10_000_000);
+        assert!(imu.init(1000, 2000, 16).is_err());
+    }
+
+    #[test]
+    fn test_mpu6000_fifo() {
+        let spi = MockSpi::new(mpu6000_regs::WHO_AM_I_VAL, mpu6000_regs::FIFO_R_W);
+        let mut imu = SpiImu::new(spi, ImuChip::Mpu6000, 0, 10_000_000);
+        imu.init(1000, 2000, 16).unwrap();
+
+        assert!(imu.collect().is_none());
+
+        let lsb_per_g = 2048;
+        let mut bytes = mpu6000_frame([0, 0, lsb_per_g], [164, 0, 0]);
+        bytes.extend(mpu6000_frame([0, 0, lsb_per_g], [0, 164, i16::MIN]));
+        imu.spi.push_fifo(mpu6000_regs::FIFO_COUNTH, &bytes);
+
+        let (gyro, acc) = imu.collect().unwrap();
+        assert_eq!(gyro.samples, 2);
+        assert_eq!(gyro.delta_dt, 2000);
+        assert!((acc.acc.z - GRAVITY).abs() < 1e-3);
+        // 164 LSB is averaged with 0 at 2000deg/s range
+        let expect_rate = 82.0 * 2000.0 / 32768.0 / 180.0 * PI;
+        assert!((gyro.rate.x - expect_rate).abs() < 1e-4);
+        assert!((gyro.rate.y - expect_rate).abs() < 1e-4);
+        assert!((gyro.delta_angle.x - gyro.rate.x * 0.002).abs() < 1e-6);
+        assert_eq!(acc.clip_count, [0; 3]);
+
+        // the registers at 1MHz, the fifo data at the full clock
+        for (reg, speed) in &imu.spi.speeds {
+            let expect = if *reg == mpu6000_regs::FIFO_R_W { 10_000_000 } else { 1_000_000 };
+            assert_eq!(*speed, expect, "reg {:#x}", reg);
+        }
+        assert!(imu.spi.speeds.contains(&(mpu6000_regs::FIFO_R_W, 10_000_000)));
+    }
+
+    #[test]
+    fn test_icm42688_fifo_with_rotation() {
+        use icm42688_regs::*;
+        let spi = MockSpi::new(WHO_AM_I_VAL, FIFO_DATA);
+        let mut imu = SpiImu::new(spi, ImuChip::Icm42688, 0, 10_000_000);
+        imu.init(1000, 2000, 16).unwrap();
+        assert!(imu.spi.writes.contains(&(GYRO_CONFIG0, 6)));
+        imu.rotation = Rotation::Yaw270;
+
+        let mut frame = vec![0x68u8];
+        frame.extend([2048i16, 0, i16::MAX].iter().flat_map(|x| x.to_be_bytes()));
+        frame.extend([0i16, 0, 0].iter().flat_map(|x| x.to_be_bytes()));
+        frame.extend([0u8, 0, 0]); // temperature, timestamp
+        let mut empty = vec![FIFO_HEADER_EMPTY];
+        empty.extend([0u8; FIFO_SAMPLE_SIZE - 1]);
+        frame.extend(empty);
+        imu.spi.push_fifo(FIFO_COUNTH, &frame);
+
+        let (gyro, acc) = imu.collect().unwrap();
+        assert_eq!(gyro.samples, 1);
+        assert!((gyro.temperature - 25.0).abs() < 1e-3);
+        assert!(acc.acc.x.abs() < 1e-3);
+        assert!((acc.acc.y - GRAVITY).abs() < 1e-3);
+        assert_eq!(acc.clip_count, [0, 0, 1]);
+    }
+
+    #[test]
+    fn test_device_id() {
+        assert_eq!(get_device_id(ImuChip::Icm42688, "/dev/spidev1.2"), 2 << 16 | 1 << 8 | 2);
+    }
+}
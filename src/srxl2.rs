@@ -0,0 +1,206 @@
+use clap::Parser;
+use rpos::thread_logln;
+use serialport::{Parity, StopBits};
+
+use crate::{
+    msg_define::{MsgHeader, RcInputMsg, RcSource, RC_INPUT_MAX_CHANNELS},
+    rc_input::{spawn_reader, ParserStats, RcProtocol},
+};
+
+/*
+    spektrum srxl2, 115200 baud 8N1 half duplex. the receiver is the bus master,
+    the handshake is not answered so it sends the channel data without telemetry.
+*/
+#[derive(Parser)]
+#[command(name = "srxl2", about = None, long_about = None)]
+struct Cli {
+    #[arg(short, long, default_value_t = 115200)]
+    baudrate: u32,
+
+    dev_name: String,
+}
+
+// [0xA6, type, len(whole packet), payload.., crc16(be)]
+const HEADER: u8 = 0xA6;
+const MIN_LEN: usize = 5;
+const MAX_LEN: usize = 80;
+const CONTROL_DATA: u8 = 0xCD;
+const CMD_CHANNEL: u8 = 0x00;
+const CMD_FAILSAFE: u8 = 0x01;
+
+// crc16 ccitt(xmodem)
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, x| {
+        (0..8).fold(crc ^ ((*x as u16) << 8), |crc, _| if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 })
+    })
+}
+
+// 0x8000 is the centre, ±100% travel is 1000~2000us, rc_update does the calibration
+fn srxl2_to_us(x: u16) -> i16 {
+    (1500 + ((x >> 2) as i32 - 8192) * 500 / 5456) as i16
+}
+
+pub struct Srxl2 {
+    buf: Vec<u8>,
+    // a packet carries the channels in its mask only, the others keep the last values
+    channel_vals: [i16; RC_INPUT_MAX_CHANNELS],
+    channel_count: u8,
+    frame_losses: Option<u16>,
+    stats: ParserStats,
+}
+
+impl Default for Srxl2 {
+    fn default() -> Self {
+        Srxl2 {
+            buf: Vec::new(),
+            channel_vals: [1500; RC_INPUT_MAX_CHANNELS],
+            channel_count: 0,
+            frame_losses: None,
+            stats: ParserStats::default(),
+        }
+    }
+}
+
+impl Srxl2 {
+    // payload: [cmd, reply id, rssi, frame losses(u16 le), channel mask(u32 le), channels(u16 le)..]
+    fn decode_control(&mut self, payload: &[u8]) -> Option<RcInputMsg> {
+        if payload.len() < 9 || !(payload[0] == CMD_CHANNEL || payload[0] == CMD_FAILSAFE) {
+            return None;
+        }
+        let frame_losses = u16::from_le_bytes([payload[3], payload[4]]);
+        let mask = u32::from_le_bytes(payload[5..9].try_into().unwrap());
+        let mut values = payload[9..].chunks_exact(2);
+        for i in 0..RC_INPUT_MAX_CHANNELS {
+            if mask & (1 << i) != 0 {
+                let x = values.next()?;
+                self.channel_vals[i] = srxl2_to_us(u16::from_le_bytes([x[0], x[1]]));
+                self.channel_count = self.channel_count.max(i as u8 + 1);
+            }
+        }
+        // the counter of the receiver goes up when frames from the transmitter are missed
+        let frame_lost = self.frame_losses.is_some_and(|x| x != frame_losses);
+        self.frame_losses = Some(frame_losses);
+        Some(RcInputMsg {
+            header: MsgHeader::default(),
+            source: RcSource::Srxl2,
+            channel_count: self.channel_count,
+            failsafe: payload[0] == CMD_FAILSAFE,
+            frame_lost,
+            channel_vals: self.channel_vals,
+        })
+    }
+}
+
+impl RcProtocol for Srxl2 {
+    fn source(&self) -> RcSource {
+        RcSource::Srxl2
+    }
+
+    fn stats(&self) -> ParserStats {
+        self.stats
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn next_frame(&mut self) -> Option<RcInputMsg> {
+        loop {
+            let start = self.buf.iter().position(|x| *x == HEADER).unwrap_or(self.buf.len());
+            self.buf.drain(..start);
+            self.stats.discarded_bytes += start as u32;
+            if self.buf.len() < 3 {
+                return None;
+            }
+            let len = self.buf[2] as usize;
+            if !(MIN_LEN..=MAX_LEN).contains(&len) {
+                self.stats.discarded_bytes += 1;
+                self.buf.drain(..1);
+                continue;
+            }
+            if self.buf.len() < len {
+                return None;
+            }
+            let packet: Vec<u8> = self.buf.drain(..len).collect();
+            if crc16(&packet[..len - 2]) != u16::from_be_bytes([packet[len - 2], packet[len - 1]]) {
+                self.stats.crc_errors += 1;
+                self.stats.discarded_bytes += 1;
+                // resync inside the packet
+                self.buf.splice(0..0, packet[1..].iter().copied());
+                continue;
+            }
+            if packet[1] == CONTROL_DATA {
+                if let Some(msg) = self.decode_control(&packet[3..len - 2]) {
+                    return Some(msg);
+                }
+            }
+        }
+    }
+}
+
+pub fn srxl2_main(argc: u32, argv: *const &str) {
+    if let Some(args) = crate::basic::client_process_args::<Cli>(argc, argv) {
+        spawn_reader(&args.dev_name, args.baudrate, Parity::None, StopBits::One, Srxl2::default());
+        thread_logln!("srxl2 dev:{}", args.dev_name);
+    }
+}
+
+#[rpos::ctor::ctor]
+fn register() {
+    rpos::module::Module::register("srxl2", srxl2_main);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control_packet(cmd: u8, frame_losses: u16, channels: &[(usize, u16)]) -> Vec<u8> {
+        let mut packet = vec![HEADER, CONTROL_DATA, 0, cmd, 0, 0x50];
+        packet.extend(frame_losses.to_le_bytes());
+        let mask = channels.iter().fold(0u32, |mask, (i, _)| mask | (1 << i));
+        packet.extend(mask.to_le_bytes());
+        for (_, x) in channels {
+            packet.extend(x.to_le_bytes());
+        }
+        packet[2] = packet.len() as u8 + 2;
+        let crc = crc16(&packet);
+        packet.extend(crc.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn srxl2_stream_test() {
+        // check value of CRC-16/XMODEM
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(srxl2_to_us(0x8000), 1500);
+
+        let mut stream = vec![0x00, HEADER];
+        stream.extend(control_packet(CMD_CHANNEL, 3, &[(0, 0x8000), (1, 0x8000 + 5456 * 4), (5, 0x8000 - 5456 * 4)]));
+        // handshake, ignored
+        let mut handshake = vec![HEADER, 0x21, 14, 0x10, 0x21, 10, 0, 0, 1, 2, 3, 4];
+        handshake.extend(crc16(&handshake).to_be_bytes());
+        stream.extend(handshake);
+        let mut broken = control_packet(CMD_CHANNEL, 4, &[(0, 0x9000)]);
+        broken[8] ^= 0xFF;
+        stream.extend(broken);
+        stream.extend(control_packet(CMD_CHANNEL, 4, &[(1, 0x8000)]));
+        stream.extend(control_packet(CMD_FAILSAFE, 4, &[(0, 0x8000)]));
+
+        let mut srxl2 = Srxl2::default();
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(6) {
+            srxl2.push_bytes(chunk);
+            while let Some(msg) = srxl2.next_frame() {
+                frames.push(msg);
+            }
+        }
+        assert_eq!(frames.len(), 3);
+        assert_eq!(srxl2.stats().crc_errors, 1);
+        assert_eq!(frames[0].channel_vals[..6], [1500, 2000, 1500, 1500, 1500, 1000]);
+        assert_eq!((frames[0].source, frames[0].channel_count, frames[0].frame_lost), (RcSource::Srxl2, 6, false));
+        // the missed frame is counted
+        assert_eq!((frames[1].channel_vals[1], frames[1].frame_lost, frames[1].failsafe), (1500, true, false));
+        assert_eq!(frames[1].channel_vals[5], 1000);
+        assert_eq!((frames[2].frame_lost, frames[2].failsafe), (false, true));
+    }
+}
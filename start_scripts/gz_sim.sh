@@ -3,10 +3,29 @@
 
 ./rust_pilot gazebo_actuator
 
+# the same as the built-in geometry: ./rust_pilot mixer quad_x
 ./rust_pilot mixer /home/ncer/RustPilot/mixers/gz_mixer.json
 
+./rust_pilot imu_update
+
+./rust_pilot alt_est
+
 ./rust_pilot att_control
 
+./rust_pilot rate_control
+
+./rust_pilot alt_control
+
+./rust_pilot pos_control
+
+./rust_pilot offboard
+
+./rust_pilot rc_update
+
 ./rust_pilot -- manual_ctrl
 
-./rust_pilot -- mavlink_gs --addr localhost:14550 --joystick
\ No newline at end of file
+./rust_pilot -- mavlink_gs --addr localhost:14550
+
+# keyboard rc in this terminal, or run mavlink_gs with --joystick to fly with the ground station,
+# or a usb gamepad: ./rust_pilot joystick_input /dev/input/eventX
+./rust_pilot fake_linux_input